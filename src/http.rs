@@ -0,0 +1,186 @@
+//! Optional HTTP/JSON adapter, translating requests into [`Message`]s for [`Daemon::handle`].
+
+use super::{CommandDef, CommandEntry, Daemon, ErrorCode, Message, Page, Response};
+use std::sync::atomic::Ordering;
+use std::thread::Scope;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Server};
+
+pub struct HttpConfig {
+    pub addr: String,
+    /// Bearer token every request has to carry
+    pub token: String,
+}
+
+/// Serves HTTP requests until the daemon is stopping, handling each on its own thread.
+pub fn serve<'scope, 'env>(
+    daemon: &'env Daemon,
+    config: &'env HttpConfig,
+    scope: &'scope Scope<'scope, 'env>,
+) {
+    let server = match Server::http(&config.addr) {
+        Ok(server) => server,
+        Err(e) => {
+            log::error!("could not serve http on {}: {e}", config.addr);
+            return;
+        }
+    };
+    while !daemon.stopping.load(Ordering::SeqCst) {
+        match server.recv_timeout(Duration::from_millis(160)) {
+            Ok(Some(request)) => {
+                scope.spawn(move || handle(daemon, &config.token, request));
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("failed to receive http request: {e}"),
+        }
+    }
+}
+
+fn handle(daemon: &Daemon, token: &str, mut request: Request) {
+    let bearer = format!("Bearer {token}");
+    let authorized = request
+        .headers()
+        .iter()
+        .any(|h| h.field.equiv("Authorization") && h.value.as_str() == bearer);
+    if !authorized {
+        let response = Response::failure(
+            ErrorCode::Unauthorized,
+            "missing or wrong bearer token".into(),
+        );
+        return respond(request, 401, &response);
+    }
+    let mut body = String::new();
+    let _ = request.as_reader().read_to_string(&mut body);
+    match route(request.method(), request.url(), body) {
+        Ok(message) => {
+            let response = daemon.handle(message);
+            let code = match response.error_code() {
+                Some(ErrorCode::NotFound | ErrorCode::NotRunning) => 404,
+                Some(
+                    ErrorCode::AlreadyRunning
+                    | ErrorCode::Conflict
+                    | ErrorCode::Disabled
+                    | ErrorCode::Ambiguous,
+                ) => 409,
+                Some(ErrorCode::Invalid) => 400,
+                Some(ErrorCode::Unauthorized) => 401,
+                Some(ErrorCode::LimitReached) => 429,
+                Some(ErrorCode::Stopping) => 503,
+                Some(ErrorCode::Timeout) => 504,
+                Some(ErrorCode::SpawnFailed | ErrorCode::Inaccessible | ErrorCode::HookFailed) => {
+                    500
+                }
+                None => match response {
+                    Response::Health { .. } | Response::Readiness { .. } if response.failed() => {
+                        503
+                    }
+                    _ => 200,
+                },
+            };
+            respond(request, code, &response)
+        }
+        Err(response) => respond(request, 404, &response),
+    }
+}
+
+fn route(method: &Method, url: &str, body: String) -> Result<Message, Response> {
+    let path = url.split('?').next().unwrap_or_default().trim_matches('/');
+    let segments: Vec<&str> = path.split('/').collect();
+    let message = match (method, segments.as_slice()) {
+        (Method::Get, ["commands"]) => Message::List { page: page(url) },
+        (Method::Get, ["status"]) => Message::Status {
+            page: page(url),
+            tree: query(url).any(|(key, value)| key == "tree" && value == "true"),
+            verbose: query(url).any(|(key, value)| key == "verbose" && value == "true"),
+        },
+        (Method::Get, ["search", term]) => Message::Search {
+            term: term.to_string(),
+        },
+        (Method::Get, ["dump"]) => Message::Dump,
+        (Method::Get, ["info"]) => Message::Info,
+        (Method::Get, ["health"]) => Message::Health,
+        (Method::Get, ["history", name]) => Message::History {
+            name: name.to_string(),
+        },
+        (Method::Get, ["ready", name]) => Message::WaitReady {
+            name: name.to_string(),
+            timeout: query(url).find_map(|(key, value)| match key {
+                "timeout" => value.parse().ok(),
+                _ => None,
+            }),
+        },
+        (Method::Post, ["commands", name]) => {
+            // the body is either a json command definition or the plain command
+            let command = serde_json::from_str::<CommandEntry>(&body)
+                .map(CommandDef::from)
+                .unwrap_or_else(|_| CommandDef {
+                    command: body.trim().to_string(),
+                    ..Default::default()
+                });
+            Message::Add {
+                name: name.to_string(),
+                command,
+            }
+        }
+        (Method::Delete, ["commands", name]) => Message::Remove {
+            name: name.to_string(),
+        },
+        (Method::Post, ["execute", name]) => Message::Execute {
+            name: name.to_string(),
+            force: query(url).any(|(key, value)| key == "force" && value == "true"),
+        },
+        (Method::Post, ["restart", name]) => Message::Restart {
+            name: name.to_string(),
+        },
+        (Method::Post, ["toggle", name]) => Message::Toggle {
+            name: name.to_string(),
+        },
+        (Method::Post, ["disown", name]) => Message::Disown {
+            name: name.to_string(),
+        },
+        (Method::Post, ["reset-backoff", name]) => Message::ResetBackoff {
+            name: name.to_string(),
+        },
+        (Method::Delete, ["procs", name]) => Message::Kill {
+            name: name.to_string(),
+        },
+        _ => {
+            return Err(Response::failure(
+                ErrorCode::NotFound,
+                format!("no route for {method} {url}"),
+            ))
+        }
+    };
+    Ok(message)
+}
+
+fn respond(request: Request, code: u16, response: &Response) {
+    let body = serde_json::to_string(response).expect("can convert to json");
+    let header = Header::from_bytes("Content-Type", "application/json").expect("valid header");
+    let response = tiny_http::Response::from_string(body)
+        .with_status_code(code)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        log::warn!("failed to send http response: {e}");
+    }
+}
+
+/// The `key=value` pairs of the query string.
+fn query(url: &str) -> impl Iterator<Item = (&str, &str)> {
+    let query = url.split_once('?').map(|(_, q)| q).unwrap_or_default();
+    query.split('&').filter_map(|pair| pair.split_once('='))
+}
+
+/// Reads `offset`, `limit` and `selector` from the query string, ignoring anything else.
+fn page(url: &str) -> Page {
+    let mut page = Page::default();
+    for (key, value) in query(url) {
+        match key {
+            "offset" => page.offset = value.parse().unwrap_or_default(),
+            "limit" => page.limit = value.parse().ok(),
+            "selector" => page.selector = value.parse().ok(),
+            _ => {}
+        }
+    }
+    page
+}
@@ -0,0 +1,6276 @@
+//! Supervision of uniquely named processes: a [`Daemon`] owning the processes,
+//! driven by [`Message`]s answered with [`Response`]s, either in-process through
+//! [`Daemon::handle`] or over its socket with [`send_message`].
+
+use clap::Args;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use serde_json::from_str;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR2};
+use signal_hook::iterator::Signals;
+use std::collections::hash_map::Entry;
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Display;
+use std::io::Write;
+use std::io::{BufRead, ErrorKind, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::Scope;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{collections::HashMap, fs, process::Command};
+use sysinfo::{ProcessExt, ProcessStatus, System, SystemExt};
+use xdg::BaseDirectories;
+
+#[cfg(feature = "http")]
+mod http;
+pub mod state;
+#[cfg(test)]
+mod tests;
+
+/// A window into the name-sorted commands, shared by `list` and `status`.
+#[derive(Args, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Page {
+    /// Only the commands whose labels match, like `env=prod,tier!=cache`
+    #[arg(long, short = 'l', value_parser = Selector::from_str)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selector: Option<Selector>,
+    /// Skip the first entries
+    #[arg(long, default_value_t = 0)]
+    #[serde(default)]
+    pub offset: usize,
+    /// Return at most this many entries
+    #[arg(long)]
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl Page {
+    /// Picks the names on this page, so only those have to be cloned.
+    fn select(&self, commands: &HashMap<String, CommandDef>) -> Vec<String> {
+        let mut names: Vec<&String> = commands
+            .iter()
+            .filter(|(_, command)| {
+                let selector = self.selector.as_ref();
+                selector.is_none_or(|selector| selector.matches(&command.labels))
+            })
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Kubernetes style label selector, requirements separated by commas that all have
+/// to hold: `key=value`, `key!=value`, `key in (a,b)`, `key notin (a,b)`, `key`
+/// for having the label at all and `!key` for not having it. Like there,
+/// `!=` and `notin` also match commands without the label.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    NotExists(String),
+}
+
+impl Selector {
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.requirements
+            .iter()
+            .all(|requirement| match requirement {
+                Requirement::Equals(key, value) => labels.get(key) == Some(value),
+                Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+                Requirement::In(key, values) => labels.get(key).is_some_and(|v| values.contains(v)),
+                Requirement::NotIn(key, values) => {
+                    labels.get(key).is_none_or(|v| !values.contains(v))
+                }
+                Requirement::Exists(key) => labels.contains_key(key),
+                Requirement::NotExists(key) => !labels.contains_key(key),
+            })
+    }
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        // commas inside the parentheses of a set separate values, not requirements
+        let mut terms = Vec::new();
+        let (mut depth, mut start) = (0, 0);
+        for (i, c) in selector.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    terms.push(&selector[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        terms.push(&selector[start..]);
+        let requirements = terms
+            .into_iter()
+            .map(|term| parse_requirement(term.trim()))
+            .collect::<Result<_, _>>()?;
+        Ok(Selector { requirements })
+    }
+}
+
+fn parse_requirement(term: &str) -> Result<Requirement, String> {
+    let invalid = || format!("invalid selector requirement '{term}'");
+    if let Some(open) = term.find('(') {
+        let values = term[open..]
+            .strip_prefix('(')
+            .and_then(|values| values.strip_suffix(')'))
+            .ok_or_else(invalid)?;
+        let values: Vec<String> = values.split(',').map(|v| v.trim().to_string()).collect();
+        if values.iter().any(|value| !is_label_part(value)) {
+            return Err(invalid());
+        }
+        let mut words = term[..open].split_whitespace();
+        let requirement = match (words.next(), words.next(), words.next()) {
+            (Some(key), Some("in"), None) if is_label_part(key) => {
+                Requirement::In(key.to_string(), values)
+            }
+            (Some(key), Some("notin"), None) if is_label_part(key) => {
+                Requirement::NotIn(key.to_string(), values)
+            }
+            _ => return Err(invalid()),
+        };
+        return Ok(requirement);
+    }
+    let requirement = if let Some((key, value)) = term.split_once("!=") {
+        Requirement::NotEquals(key.trim().to_string(), value.trim().to_string())
+    } else if let Some((key, value)) = term.split_once("==").or_else(|| term.split_once('=')) {
+        Requirement::Equals(key.trim().to_string(), value.trim().to_string())
+    } else if let Some(key) = term.strip_prefix('!') {
+        Requirement::NotExists(key.trim().to_string())
+    } else {
+        Requirement::Exists(term.to_string())
+    };
+    let parts = match &requirement {
+        Requirement::Equals(key, value) | Requirement::NotEquals(key, value) => {
+            is_label_part(key) && is_label_part(value)
+        }
+        Requirement::Exists(key) | Requirement::NotExists(key) => is_label_part(key),
+        Requirement::In(..) | Requirement::NotIn(..) => true,
+    };
+    if parts {
+        Ok(requirement)
+    } else {
+        Err(invalid())
+    }
+}
+
+/// Label keys and values are nonempty and free of the characters selectors use.
+fn is_label_part(part: &str) -> bool {
+    !part.is_empty()
+        && part
+            .chars()
+            .all(|c| !c.is_whitespace() && !",=!()".contains(c))
+}
+
+fn parse_label(label: &str) -> Result<String, String> {
+    match label.split_once('=') {
+        Some((key, value)) if is_label_part(key) && is_label_part(value) => Ok(label.to_string()),
+        _ if label.is_empty() => Ok(String::new()),
+        _ => Err(format!(
+            "{label} is not of the form KEY=VALUE without spaces or any of ,=!()"
+        )),
+    }
+}
+
+impl TryFrom<String> for Selector {
+    type Error = String;
+
+    fn try_from(selector: String) -> Result<Self, Self::Error> {
+        selector.parse()
+    }
+}
+
+impl From<Selector> for String {
+    fn from(selector: Selector) -> Self {
+        selector.to_string()
+    }
+}
+
+impl Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let terms: Vec<String> = self
+            .requirements
+            .iter()
+            .map(|requirement| match requirement {
+                Requirement::Equals(key, value) => format!("{key}={value}"),
+                Requirement::NotEquals(key, value) => format!("{key}!={value}"),
+                Requirement::In(key, values) => format!("{key} in ({})", values.join(",")),
+                Requirement::NotIn(key, values) => format!("{key} notin ({})", values.join(",")),
+                Requirement::Exists(key) => key.clone(),
+                Requirement::NotExists(key) => format!("!{key}"),
+            })
+            .collect();
+        write!(f, "{}", terms.join(","))
+    }
+}
+
+/// Optional fields of a command definition, shared by `add` and `edit`.
+#[derive(Args, Clone, Default, Serialize, Deserialize)]
+pub struct DefinitionArgs {
+    /// Probe passing once the process is ready: exec:<command>, tcp://<host>:<port> or a URL
+    #[arg(long, value_parser = parse_probe, conflicts_with_all = ["ready_cmd", "ready_tcp"])]
+    pub ready: Option<String>,
+    /// Shell command that succeeds once the process is ready
+    #[arg(long, conflicts_with = "ready_tcp")]
+    pub ready_cmd: Option<String>,
+    /// Address that accepts TCP connections once the process is ready
+    #[arg(long)]
+    pub ready_tcp: Option<String>,
+    /// Seconds to wait for the readiness probe to pass
+    #[arg(long)]
+    pub start_timeout: Option<u64>,
+    /// Seconds a kill waits after SIGTERM before sending SIGKILL, 0 for SIGKILL right away
+    #[arg(long)]
+    pub stop_timeout: Option<u64>,
+    /// Probe passing while the process is healthy, written like for `--ready`
+    #[arg(
+        long,
+        value_parser = parse_probe,
+        conflicts_with_all = ["live_cmd", "live_tcp", "live_http"]
+    )]
+    pub live: Option<String>,
+    /// Shell command that keeps succeeding while the process is healthy
+    #[arg(long, conflicts_with_all = ["live_tcp", "live_http"])]
+    pub live_cmd: Option<String>,
+    /// Address that keeps accepting TCP connections while the process is healthy
+    #[arg(long, conflicts_with = "live_http")]
+    pub live_tcp: Option<String>,
+    /// URL that keeps answering GET requests successfully while the process is healthy
+    #[arg(long)]
+    pub live_http: Option<String>,
+    /// Seconds between two liveness probes
+    #[arg(long)]
+    pub live_interval: Option<u64>,
+    /// Failed liveness probes in a row after which the process is restarted
+    #[arg(long)]
+    pub live_failures: Option<u32>,
+    /// Shell command run before spawning, a failure aborts the execute
+    #[arg(long)]
+    pub pre_start: Option<String>,
+    /// Shell command run after the process exited
+    #[arg(long)]
+    pub post_stop: Option<String>,
+    /// When to restart the process after it exited on its own
+    #[arg(long, value_enum)]
+    pub restart: Option<RestartPolicy>,
+    /// Automatic restarts within the restart window after which the command is given up
+    #[arg(long)]
+    pub max_restarts: Option<u32>,
+    /// Seconds counted back for `--max-restarts`
+    #[arg(long)]
+    pub restart_window: Option<u64>,
+    /// Expand `$VAR` and `${VAR}` against the daemon environment before running
+    #[arg(long, value_enum)]
+    pub expand_env: Option<ExpandEnv>,
+    /// URL to POST a JSON notification to whenever the process exits
+    #[arg(long)]
+    pub notify_url: Option<String>,
+    /// Restart the process whenever something below this path changes, can be repeated
+    #[arg(long = "watch")]
+    pub watch_paths: Option<Vec<PathBuf>>,
+    /// Command that has to be running before this one starts, can be repeated
+    #[arg(long)]
+    pub depends_on: Option<Vec<String>>,
+    /// Command this one starts after when both are started together, can be repeated
+    #[arg(long)]
+    pub after: Option<Vec<String>>,
+    /// Command that must not run at the same time as this one, can be repeated
+    #[arg(long)]
+    pub conflicts_with: Option<Vec<String>>,
+    /// Environment variable of the process, can be repeated
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    pub env: Option<Vec<String>>,
+    /// Label to select the command by, can be repeated, an empty string clears them
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_label)]
+    pub label: Option<Vec<String>>,
+    /// File of KEY=VALUE lines read on every start, `--env` takes precedence
+    #[arg(long)]
+    pub env_file: Option<PathBuf>,
+    /// Directory the process runs in, instead of the daemon's
+    #[arg(long)]
+    pub cwd: Option<PathBuf>,
+    /// Run the command in a login shell sourcing the profile, `--login-shell false` undoes it
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub login_shell: Option<bool>,
+    /// Start from an empty environment instead of the daemon's, `--clean-env false` undoes it
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub clean_env: Option<bool>,
+    /// Restart the running process when a config reload changes its definition
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub restart_on_change: Option<bool>,
+    /// Start the command with the daemon and count it as unhealthy whenever it is
+    /// not running, `--autostart false` undoes it
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub autostart: Option<bool>,
+    /// File mode creation mask of the process, in octal like 027
+    #[arg(long, value_name = "OCTAL", value_parser = parse_umask)]
+    pub umask: Option<String>,
+    /// Most file descriptors the process may have open, 0 clears the limit
+    #[arg(long)]
+    pub rlimit_nofile: Option<u64>,
+    /// Most bytes of address space the process may map, 0 clears the limit
+    #[arg(long)]
+    pub rlimit_as: Option<u64>,
+    /// Most seconds of CPU time the process may use, 0 clears the limit
+    #[arg(long)]
+    pub rlimit_cpu: Option<u64>,
+    /// Run the command as init of a new PID namespace, which needs CAP_SYS_ADMIN
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub unshare_pid: Option<bool>,
+    /// Run the command in a private mount namespace, which needs CAP_SYS_ADMIN
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub unshare_mount: Option<bool>,
+    /// Pass the output through the daemon, so `logs --follow` can show it
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub capture_output: Option<bool>,
+    /// Bytes of captured output passed on per process before the rest is dropped,
+    /// instead of the daemon's `--max-log-bytes`, 0 to use that again
+    #[arg(long)]
+    pub max_log_bytes: Option<u64>,
+}
+
+/// Accepts a probe like `tcp://localhost:8080`, or an empty string to clear the probe.
+fn parse_probe(spec: &str) -> Result<String, String> {
+    if !spec.is_empty() {
+        spec.parse::<Probe>()?;
+    }
+    Ok(spec.to_string())
+}
+
+fn parse_umask(umask: &str) -> Result<String, String> {
+    if !umask.is_empty() {
+        octal_mode(umask)?;
+    }
+    Ok(umask.to_string())
+}
+
+/// Parses an octal permission mask like `022`.
+fn octal_mode(mode: &str) -> Result<libc::mode_t, String> {
+    match libc::mode_t::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("{mode} is not an octal mask between 000 and 777")),
+    }
+}
+
+/// Accepts `KEY=VALUE`, or an empty string to clear the variables.
+fn parse_env_var(var: &str) -> Result<String, String> {
+    match var.split_once('=') {
+        Some((key, _)) if !key.is_empty() => Ok(var.to_string()),
+        _ if var.is_empty() => Ok(String::new()),
+        _ => Err(format!("{var} is not of the form KEY=VALUE")),
+    }
+}
+
+impl DefinitionArgs {
+    /// Overwrites the fields of `command` that were given, an empty string clears a field.
+    pub fn apply(self, command: &mut CommandDef) {
+        let non_empty = |s: String| Some(s).filter(|s| !s.is_empty());
+        if let Some(ready) = self.ready {
+            command.readiness = non_empty(ready).and_then(|spec| spec.parse().ok());
+        }
+        if let Some(ready_cmd) = self.ready_cmd {
+            command.readiness = non_empty(ready_cmd).map(Probe::Exec);
+        }
+        if let Some(ready_tcp) = self.ready_tcp {
+            command.readiness = non_empty(ready_tcp).map(Probe::Tcp);
+        }
+        if let Some(start_timeout) = self.start_timeout {
+            command.start_timeout = Some(start_timeout);
+        }
+        if let Some(stop_timeout) = self.stop_timeout {
+            command.stop_timeout = Some(stop_timeout);
+        }
+        if let Some(live) = self.live {
+            command.liveness = non_empty(live).and_then(|spec| spec.parse().ok());
+        }
+        if let Some(live_cmd) = self.live_cmd {
+            command.liveness = non_empty(live_cmd).map(Probe::Exec);
+        }
+        if let Some(live_tcp) = self.live_tcp {
+            command.liveness = non_empty(live_tcp).map(Probe::Tcp);
+        }
+        if let Some(live_http) = self.live_http {
+            command.liveness = non_empty(live_http).map(Probe::Http);
+        }
+        if let Some(live_interval) = self.live_interval {
+            command.liveness_interval = Some(live_interval);
+        }
+        if let Some(live_failures) = self.live_failures {
+            command.liveness_failures = Some(live_failures);
+        }
+        if let Some(max_restarts) = self.max_restarts {
+            command.max_restarts = Some(max_restarts);
+        }
+        if let Some(restart_window) = self.restart_window {
+            command.restart_window = Some(restart_window);
+        }
+        if let Some(pre_start) = self.pre_start {
+            command.pre_start = non_empty(pre_start);
+        }
+        if let Some(post_stop) = self.post_stop {
+            command.post_stop = non_empty(post_stop);
+        }
+        if let Some(restart) = self.restart {
+            command.restart = restart;
+        }
+        if let Some(expand_env) = self.expand_env {
+            command.expand_env = expand_env;
+        }
+        if let Some(notify_url) = self.notify_url {
+            command.notify_url = non_empty(notify_url);
+        }
+        if let Some(watch_paths) = self.watch_paths {
+            let watch_paths = watch_paths
+                .into_iter()
+                .filter(|p| !p.as_os_str().is_empty());
+            command.watch_paths = watch_paths.collect();
+        }
+        if let Some(depends_on) = self.depends_on {
+            command.depends_on = depends_on.into_iter().filter(|d| !d.is_empty()).collect();
+        }
+        if let Some(after) = self.after {
+            command.after = after.into_iter().filter(|a| !a.is_empty()).collect();
+        }
+        if let Some(conflicts_with) = self.conflicts_with {
+            let conflicts_with = conflicts_with.into_iter().filter(|c| !c.is_empty());
+            command.conflicts_with = conflicts_with.collect();
+        }
+        if let Some(env) = self.env {
+            command.env = env
+                .iter()
+                .filter_map(|var| var.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+        }
+        if let Some(labels) = self.label {
+            command.labels = labels
+                .iter()
+                .filter_map(|label| label.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+        }
+        if let Some(env_file) = self.env_file {
+            command.env_file = Some(env_file).filter(|p| !p.as_os_str().is_empty());
+        }
+        if let Some(cwd) = self.cwd {
+            command.cwd = Some(cwd).filter(|p| !p.as_os_str().is_empty());
+        }
+        if let Some(clean_env) = self.clean_env {
+            command.clean_env = clean_env;
+        }
+        if let Some(login_shell) = self.login_shell {
+            command.login_shell = login_shell;
+        }
+        if let Some(restart_on_change) = self.restart_on_change {
+            command.restart_on_change = restart_on_change;
+        }
+        if let Some(autostart) = self.autostart {
+            command.autostart = autostart;
+        }
+        if let Some(umask) = self.umask {
+            command.umask = non_empty(umask);
+        }
+        let positive = |limit: u64| Some(limit).filter(|&limit| limit > 0);
+        if let Some(limit) = self.rlimit_nofile {
+            command.rlimit_nofile = positive(limit);
+        }
+        if let Some(limit) = self.rlimit_as {
+            command.rlimit_as = positive(limit);
+        }
+        if let Some(limit) = self.rlimit_cpu {
+            command.rlimit_cpu = positive(limit);
+        }
+        if let Some(unshare_pid) = self.unshare_pid {
+            command.unshare_pid = unshare_pid;
+        }
+        if let Some(unshare_mount) = self.unshare_mount {
+            command.unshare_mount = unshare_mount;
+        }
+        if let Some(capture_output) = self.capture_output {
+            command.capture_output = capture_output;
+        }
+        if let Some(limit) = self.max_log_bytes {
+            command.max_log_bytes = positive(limit);
+        }
+    }
+
+    /// Makes watched paths absolute, relative to the working directory of the client.
+    pub fn resolve_paths(&mut self) {
+        for path in self
+            .watch_paths
+            .iter_mut()
+            .flatten()
+            .chain(&mut self.env_file)
+            .chain(&mut self.cwd)
+        {
+            if !path.as_os_str().is_empty() {
+                *path = std::path::absolute(&*path).unwrap_or_else(|_| path.clone());
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Message {
+    /// Adds or overwrites a command
+    Add { name: String, command: CommandDef },
+    /// Adds or overwrites several commands at once
+    AddMany {
+        commands: HashMap<String, CommandDef>,
+    },
+    /// Changes only the given fields of a command
+    Edit {
+        name: String,
+        command: Option<String>,
+        fields: DefinitionArgs,
+    },
+    /// Removes a command
+    Remove { name: String },
+    /// Adds or overwrites a template, a command with `{name}` placeholders
+    AddTemplate { name: String, command: CommandDef },
+    /// Removes a template
+    RemoveTemplate { name: String },
+    /// Lists all templates
+    ListTemplates,
+    /// Adds or overwrites the command `name` made from a template, its
+    /// placeholders filled with `vars`
+    AddFromTemplate {
+        template: String,
+        name: String,
+        vars: HashMap<String, String>,
+    },
+    /// Duplicates a command definition under a new name
+    Copy {
+        from: String,
+        to: String,
+        force: bool,
+    },
+    /// Lists all commands
+    List {
+        #[serde(default)]
+        page: Page,
+    },
+    /// Shows which commands are running
+    Status {
+        #[serde(default)]
+        page: Page,
+        #[serde(default)]
+        tree: bool,
+        #[serde(default)]
+        verbose: bool,
+    },
+    /// Finds commands by a case-insensitive substring of name or command
+    Search { term: String },
+    /// Returns the full internal daemon state
+    Dump,
+    /// Returns the settings the daemon runs with
+    Info,
+    /// Returns whether all commands are doing fine, for external health checks
+    Health,
+    /// Returns the latest finished runs of a command
+    History { name: String },
+    /// Waits for the running process of a command to pass its readiness probe, at
+    /// most `timeout` seconds or the start timeout of the command
+    WaitReady { name: String, timeout: Option<u64> },
+    /// Check if daemon is running
+    Alive,
+    /// Executes a command, a disabled one only when forced
+    Execute {
+        name: String,
+        #[serde(default)]
+        force: bool,
+    },
+    /// Executes a command, streaming its output as [`Response::Output`] frames
+    /// before the final [`Response::Exited`]. The process outlives a client that
+    /// disconnects
+    ExecuteAttached {
+        name: String,
+        #[serde(default)]
+        force: bool,
+    },
+    /// Streams the output of the running and later processes of the commands, which
+    /// have to capture it, as [`Response::Line`] frames until the client disconnects
+    Follow { names: Vec<String> },
+    /// Starts a command and its dependencies in the background
+    Start { name: String },
+    /// Starts all commands in the background
+    StartAll,
+    /// Starts the commands matching a label selector in the background
+    StartMatching { selector: Selector },
+    /// Kills all processes, dependents before their dependencies, each once the
+    /// processes depending on it exited. The reverse of [`Message::StartAll`]
+    StopAll,
+    /// Kills a process
+    Kill { name: String },
+    /// Kills and re-execute a process
+    Restart { name: String },
+    /// Kills or executes a process, depending on if a process for that name already exists
+    Toggle { name: String },
+    /// Stops tracking a process without killing it
+    Disown { name: String },
+    /// Sends a signal to a process, which is not restarted when it exits afterwards
+    Signal { name: String, signal: i32 },
+    /// Clears the restart backoff of a process
+    ResetBackoff { name: String },
+    /// Clears the restart backoff of all commands restarted automatically
+    ResetBackoffAll,
+    /// Clears the restart backoff of the commands matching a label selector
+    ResetBackoffMatching { selector: Selector },
+    /// Disables a command without removing it, or enables it again
+    SetEnabled { name: String, enabled: bool },
+    /// Renders a systemd service unit for a command
+    SystemdUnit { name: String },
+    /// Registers a command from the command line, directory and environment of a process
+    Capture {
+        pid: u32,
+        name: String,
+        #[serde(default)]
+        adopt: bool,
+    },
+    /// Renders the environment of a command as shell exports
+    Env {
+        name: String,
+        #[serde(default)]
+        format: ShellSyntax,
+    },
+    /// Clears command definitions and/or process tracking
+    Reset {
+        commands: bool,
+        procs: bool,
+        #[serde(default)]
+        counters: bool,
+    },
+    /// Stops the daemon once the response is sent
+    Shutdown { keep_running: bool },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Response {
+    /// The operation succeeded
+    Ok(String),
+    /// The operation failed, `code` telling the reason apart for scripts
+    Failure { code: ErrorCode, message: String },
+    /// The daemon is running
+    Alive,
+    /// All registered commands
+    Commands {
+        commands: HashMap<String, CommandDef>,
+        /// Those of `commands` missing from the config file
+        #[serde(default)]
+        runtime_only: Vec<String>,
+    },
+    /// Running state of all registered commands
+    Status {
+        entries: Vec<StatusEntry>,
+        /// Number of tracked processes that turned out to be gone or reused
+        stale: usize,
+    },
+    /// Search hits, best match first
+    Matches(Vec<(String, CommandDef)>),
+    /// The raw daemon state
+    Dump(serde_json::Value),
+    /// Settings of the running daemon
+    Info(Box<DaemonInfo>),
+    /// Overall health, degraded as long as any command has a problem
+    Health {
+        status: HealthStatus,
+        problems: Vec<HealthProblem>,
+    },
+    /// How waiting for a command to become ready ended
+    Readiness { name: String, outcome: ReadyOutcome },
+    /// Latest finished runs of a command, oldest first
+    History {
+        name: String,
+        runs: Vec<state::RunRecord>,
+    },
+    /// A line the attached process wrote. Lines that are not UTF-8 come lossily
+    /// converted, and as they were in `raw`.
+    Output {
+        stderr: bool,
+        line: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        raw: Option<Vec<u8>>,
+    },
+    /// A line a followed process wrote, `raw` like for [`Response::Output`]
+    Line {
+        name: String,
+        stderr: bool,
+        line: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        raw: Option<Vec<u8>>,
+    },
+    /// The attached process exited, without a code when killed by a signal
+    Exited { name: String, code: Option<i32> },
+    /// Responses of the steps of a composed operation, in order
+    Sequence(Vec<Response>),
+    /// Per command results of an operation on many commands
+    Bulk(HashMap<String, Response>),
+}
+
+impl Response {
+    /// Whether this or any nested response is an error.
+    pub fn failed(&self) -> bool {
+        match self {
+            Response::Failure { .. } => true,
+            Response::Health { status, .. } => *status == HealthStatus::Degraded,
+            Response::Readiness { outcome, .. } => *outcome != ReadyOutcome::Ready,
+            Response::Sequence(responses) => responses.iter().any(Response::failed),
+            Response::Bulk(results) => results.values().any(Response::failed),
+            _ => false,
+        }
+    }
+
+    pub fn failure(code: ErrorCode, message: String) -> Self {
+        Response::Failure { code, message }
+    }
+
+    /// The code of the failure, of the failed step of a sequence, or shared by all
+    /// failures of a bulk operation.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Response::Failure { code, .. } => Some(*code),
+            Response::Sequence(responses) => responses.iter().find_map(Response::error_code),
+            Response::Bulk(results) => {
+                let mut codes = results
+                    .values()
+                    .filter(|r| r.failed())
+                    .map(Response::error_code);
+                let first = codes.next()??;
+                codes.all(|code| code == Some(first)).then_some(first)
+            }
+            _ => None,
+        }
+    }
+
+    /// Exit code of a client that got this response, see [`ErrorCode::exit_code`].
+    pub fn exit_code(&self) -> i32 {
+        match self.error_code() {
+            Some(code) => code.exit_code(),
+            None => i32::from(self.failed()),
+        }
+    }
+}
+
+/// Why an operation failed, for scripts to branch on instead of the message.
+/// The set is stable: codes are only ever added, never renamed or given other
+/// meanings. In json they are the kebab-case names, like `"not-found"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// The command, template or process does not exist
+    NotFound,
+    /// The command already runs, starts or waits in the queue
+    AlreadyRunning,
+    /// The command has no process to act on
+    NotRunning,
+    /// Another command or definition stands in the way, like a running conflict
+    /// or an existing name
+    Conflict,
+    /// The process could not be started: preparing it, spawning it, its pre_start
+    /// hook, its readiness probe or a dependency of it failed
+    SpawnFailed,
+    /// The request lacked the right credentials
+    Unauthorized,
+    /// A limit was reached: the process limit, the number of connections or the
+    /// restarts a command may make
+    LimitReached,
+    /// The request, the definition or the values given for it are invalid
+    Invalid,
+    /// The command, or a dependency of it, is disabled
+    Disabled,
+    /// The name matches several commands when ignoring case
+    Ambiguous,
+    /// The process exists, but the daemon cannot inspect or signal it
+    Inaccessible,
+    /// The process did not exit in time
+    Timeout,
+    /// The daemon is stopping and starts nothing anymore
+    Stopping,
+    /// The post_stop hook of the command failed
+    HookFailed,
+}
+
+impl ErrorCode {
+    /// Exit code of a client that got a failure of this code. Other failures
+    /// exit with 1.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCode::NotFound => 10,
+            ErrorCode::AlreadyRunning => 11,
+            ErrorCode::NotRunning => 12,
+            ErrorCode::Conflict => 13,
+            ErrorCode::SpawnFailed => 14,
+            ErrorCode::Unauthorized => 15,
+            ErrorCode::LimitReached => 16,
+            ErrorCode::Invalid => 17,
+            ErrorCode::Disabled => 18,
+            ErrorCode::Ambiguous => 19,
+            ErrorCode::Inaccessible => 20,
+            ErrorCode::Timeout => 21,
+            ErrorCode::Stopping => 22,
+            ErrorCode::HookFailed => 23,
+        }
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Ok(msg) => write!(f, "{msg}"),
+            Response::Failure { message, .. } => write!(f, "{message}"),
+            Response::Alive => write!(f, "running"),
+            Response::Commands {
+                commands,
+                runtime_only,
+            } => {
+                let json = serde_json::to_string(commands).expect("can convert to json");
+                write!(f, "{json}")?;
+                if !runtime_only.is_empty() {
+                    write!(
+                        f,
+                        "\nnot in the config, lost when the daemon restarts without -k: {}",
+                        runtime_only.join(", ")
+                    )?;
+                }
+                Ok(())
+            }
+            Response::Dump(state) => write!(
+                f,
+                "{}",
+                serde_json::to_string_pretty(state).expect("can convert to json")
+            ),
+            Response::Info(info) => write!(f, "{info}"),
+            Response::Health { status, problems } => {
+                let width = problems.iter().map(|p| p.name.len()).max().unwrap_or(0);
+                let lines = problems
+                    .iter()
+                    .map(|problem| format!("\n{:width$}  {}", problem.name, problem.reason));
+                write!(f, "{status}{}", lines.collect::<String>())
+            }
+            Response::Readiness { name, outcome } => match outcome {
+                ReadyOutcome::Ready => write!(f, "{name} is ready"),
+                ReadyOutcome::NotRunning => write!(f, "{name} is not running"),
+                ReadyOutcome::Exited { code: Some(code) } => {
+                    write!(f, "{name} exited with code {code} before becoming ready")
+                }
+                ReadyOutcome::Exited { code: None } => {
+                    write!(f, "{name} exited before becoming ready")
+                }
+                ReadyOutcome::Timeout { secs } => {
+                    write!(f, "{name} did not become ready within {secs}s")
+                }
+            },
+            Response::History { name, runs } if runs.is_empty() => {
+                write!(f, "{name} has not finished a run yet")
+            }
+            Response::History { runs, .. } => {
+                let now = unix_now();
+                let lines: Vec<String> = runs.iter().map(|run| describe_run(run, now)).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Response::Output { line, .. } => write!(f, "{}", line.trim_end_matches('\n')),
+            Response::Line { name, line, .. } => {
+                write!(f, "{name} | {}", line.trim_end_matches('\n'))
+            }
+            Response::Exited {
+                name,
+                code: Some(code),
+            } => write!(f, "{name} exited with code {code}"),
+            Response::Exited { name, code: None } => write!(f, "{name} was killed by a signal"),
+            Response::Status { entries, stale } => {
+                let width = entries.iter().map(|e| e.name.len()).max().unwrap_or(0);
+                let mut lines: Vec<String> = entries
+                    .iter()
+                    .map(|entry| format!("{:width$}  {entry}", entry.name))
+                    .collect();
+                if *stale > 0 {
+                    lines.push(format!("pruned {stale} stale process entries"));
+                }
+                write!(f, "{}", lines.join("\n"))
+            }
+            Response::Matches(matches) => {
+                let width = matches
+                    .iter()
+                    .map(|(name, _)| name.len())
+                    .max()
+                    .unwrap_or(0);
+                let lines: Vec<String> = matches
+                    .iter()
+                    .map(|(name, command)| format!("{name:width$}  {}", command.command))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Response::Sequence(responses) => {
+                let lines: Vec<String> = responses.iter().map(|r| r.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Response::Bulk(results) => {
+                let mut names: Vec<&String> = results.keys().collect();
+                names.sort();
+                let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+                let lines: Vec<String> = names
+                    .into_iter()
+                    .map(|name| {
+                        let result = &results[name];
+                        let outcome = if result.failed() { "failed" } else { "ok" };
+                        let message = result.to_string().replace('\n', "; ");
+                        format!("{name:width$}  {outcome:6}  {message}")
+                    })
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StatusEntry {
+    pub name: String,
+    pub command: String,
+    pub pid: Option<u32>,
+    /// Automatic restarts since the last stable run
+    pub restarts: u32,
+    /// Delay before the next automatic restart
+    pub backoff: u64,
+    /// Seconds until the pending automatic restart
+    pub restarting_in: Option<u64>,
+    /// Whether automatic restarts stopped after exceeding the restart limit
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub given_up: bool,
+    /// Whether the command is missing from the config file
+    #[serde(default)]
+    pub runtime_only: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    /// Liveness probes failed in a row by the running process
+    #[serde(default)]
+    pub liveness_failures: u32,
+    /// Place in the queue of starts waiting for the process limit, 1 is next
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queued: Option<usize>,
+    /// Command line of the running process, only given when asked to be verbose
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub argv: Vec<String>,
+    /// Number of descendant processes, only counted when asked for the tree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub children: Option<usize>,
+    /// Bytes of captured output after which the rest of the running process's was dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<u64>,
+}
+
+impl Display for StatusEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.pid, self.restarting_in, self.queued) {
+            (Some(pid), _, _) => write!(f, "running (pid {pid})")?,
+            (None, _, Some(place)) => write!(f, "queued (place {place})")?,
+            (None, Some(secs), None) => write!(f, "restarting in {secs}s")?,
+            (None, None, None) if self.given_up => write!(f, "failed, gave up restarting")?,
+            (None, None, None) => write!(f, "stopped")?,
+        }
+        if self.restarts > 0 {
+            write!(
+                f,
+                ", restarted {} times, next backoff {}s",
+                self.restarts, self.backoff
+            )?;
+        }
+        if self.liveness_failures > 0 {
+            write!(
+                f,
+                ", failed the last {} liveness probes",
+                self.liveness_failures
+            )?;
+        }
+        if let Some(children) = self.children.filter(|_| self.pid.is_some()) {
+            write!(f, ", {children} child processes")?;
+        }
+        if let Some(bytes) = self.truncated {
+            write!(f, ", output truncated at {bytes} bytes")?;
+        }
+        if self.disabled {
+            write!(f, ", disabled")?;
+        }
+        if self.runtime_only {
+            write!(f, ", not in the config")?;
+        }
+        if !self.argv.is_empty() {
+            let quoted: Vec<String> = self.argv.iter().map(|arg| sh_quote(arg)).collect();
+            write!(f, ", spawned as {}", quoted.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthStatus {
+    Healthy,
+    Degraded,
+}
+
+impl Display for HealthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthStatus::Healthy => write!(f, "healthy"),
+            HealthStatus::Degraded => write!(f, "degraded"),
+        }
+    }
+}
+
+/// How waiting for readiness ended, each but `Ready` a failure of its own exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadyOutcome {
+    /// The readiness probe passed, or the process runs and has none
+    Ready,
+    /// There was no process to wait for
+    NotRunning,
+    /// The process exited first, without a code when killed by a signal
+    Exited {
+        code: Option<i32>,
+    },
+    Timeout {
+        secs: u64,
+    },
+}
+
+impl ReadyOutcome {
+    /// Exit code of a client that waited.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ReadyOutcome::Ready => 0,
+            ReadyOutcome::NotRunning => 1,
+            ReadyOutcome::Timeout { .. } => 2,
+            ReadyOutcome::Exited { .. } => 3,
+        }
+    }
+}
+
+/// A command that makes the daemon degraded.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HealthProblem {
+    pub name: String,
+    pub reason: String,
+}
+
+/// What a running daemon was started with, since its flags only take effect at startup.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DaemonInfo {
+    pub version: String,
+    pub pid: u32,
+    /// Seconds since the daemon process started, counting re-executions
+    pub uptime: u64,
+    pub socket: PathBuf,
+    /// The config and state files, empty for an ephemeral daemon
+    pub config: PathBuf,
+    pub state: PathBuf,
+    /// Shell the commands run in
+    pub shell: String,
+    pub persist_mode: PersistMode,
+    /// Whether state and config writes are synced to disk
+    pub durable: bool,
+    /// Whether the state file is compact instead of pretty json
+    #[serde(default)]
+    pub compact_state: bool,
+    /// Whether the daemon keeps everything in memory, reading and writing no files
+    #[serde(default)]
+    pub ephemeral: bool,
+    pub watch_config: bool,
+    pub ignore_case: bool,
+    pub max_procs: Option<usize>,
+    pub queue: bool,
+    pub max_restarts: Option<u32>,
+    /// Seconds counted back for `max_restarts`
+    pub restart_window: u64,
+    /// Seconds readiness probes get by default
+    pub start_timeout: u64,
+    /// Seconds kills wait after SIGTERM by default
+    #[serde(default)]
+    pub stop_timeout: u64,
+    /// Seconds a restart waits for the old process to exit
+    pub restart_timeout: u64,
+    /// Milliseconds identical read-only requests share a response
+    #[serde(default)]
+    pub coalesce_window: u64,
+    /// Connections served at once
+    #[serde(default)]
+    pub max_connections: usize,
+    /// Connections rejected as busy since the daemon started
+    #[serde(default)]
+    pub busy_rejections: u64,
+    /// Reloads of the config that replaced the commands since the daemon started
+    #[serde(default)]
+    pub config_reloads: u64,
+    /// Bytes of captured output passed on per process by default, 0 for no limit
+    #[serde(default)]
+    pub max_log_bytes: u64,
+    /// Address of the HTTP API, if served
+    pub http: Option<String>,
+    #[serde(default)]
+    pub counters: state::Counters,
+}
+
+impl Display for DaemonInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_none = |value: Option<String>| value.unwrap_or_else(|| "none".into());
+        let path = |path: &PathBuf| Some(path.display().to_string()).filter(|p| !p.is_empty());
+        let persist_mode = clap::ValueEnum::to_possible_value(&self.persist_mode)
+            .map(|value| value.get_name().to_string());
+        let fields = [
+            ("version", self.version.clone()),
+            ("pid", self.pid.to_string()),
+            ("uptime", format!("{}s", self.uptime)),
+            ("socket", self.socket.display().to_string()),
+            ("config", or_none(path(&self.config))),
+            ("state", or_none(path(&self.state))),
+            ("shell", self.shell.clone()),
+            ("persist mode", or_none(persist_mode)),
+            ("durable", self.durable.to_string()),
+            ("compact state", self.compact_state.to_string()),
+            ("ephemeral", self.ephemeral.to_string()),
+            ("watch config", self.watch_config.to_string()),
+            ("ignore case", self.ignore_case.to_string()),
+            (
+                "max procs",
+                or_none(self.max_procs.map(|max| max.to_string())),
+            ),
+            ("queue", self.queue.to_string()),
+            (
+                "max restarts",
+                or_none(self.max_restarts.map(|max| max.to_string())),
+            ),
+            ("restart window", format!("{}s", self.restart_window)),
+            ("start timeout", format!("{}s", self.start_timeout)),
+            ("stop timeout", format!("{}s", self.stop_timeout)),
+            ("restart timeout", format!("{}s", self.restart_timeout)),
+            ("coalesce window", format!("{}ms", self.coalesce_window)),
+            ("max connections", self.max_connections.to_string()),
+            ("busy rejections", self.busy_rejections.to_string()),
+            ("config reloads", self.config_reloads.to_string()),
+            (
+                "max log bytes",
+                or_none(
+                    Some(self.max_log_bytes)
+                        .filter(|&max| max > 0)
+                        .map(|max| max.to_string()),
+                ),
+            ),
+            ("http", or_none(self.http.clone())),
+            ("executes", self.counters.executes.to_string()),
+            ("kills", self.counters.kills.to_string()),
+            ("restarts", self.counters.restarts.to_string()),
+            ("crashes", self.counters.crashes.to_string()),
+            ("timeouts", self.counters.timeouts.to_string()),
+        ];
+        let lines: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| format!("{key:15}  {value}"))
+            .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl Message {
+    /// Whether the message only looks at the state, so identical ones arriving
+    /// together can share one response.
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Message::List { .. }
+                | Message::ListTemplates
+                | Message::Status { .. }
+                | Message::Search { .. }
+                | Message::Dump
+                | Message::Info
+                | Message::Health
+                | Message::History { .. }
+        )
+    }
+
+    /// Whether carrying the message out may change what read-only requests return.
+    fn is_mutating(&self) -> bool {
+        !self.is_read_only()
+            && !matches!(
+                self,
+                Message::Alive
+                    | Message::WaitReady { .. }
+                    | Message::Follow { .. }
+                    | Message::SystemdUnit { .. }
+                    | Message::Env { .. }
+            )
+    }
+
+    /// Whether the response waits for processes to start or exit, so it may
+    /// legitimately take arbitrarily long.
+    pub fn waits_on_processes(&self) -> bool {
+        matches!(
+            self,
+            Message::Execute { .. }
+                | Message::ExecuteAttached { .. }
+                | Message::Follow { .. }
+                | Message::Start { .. }
+                | Message::StartAll
+                | Message::StartMatching { .. }
+                | Message::StopAll
+                | Message::ResetBackoff { .. }
+                | Message::ResetBackoffAll
+                | Message::ResetBackoffMatching { .. }
+                | Message::Restart { .. }
+                | Message::Toggle { .. }
+                | Message::WaitReady { .. }
+        )
+    }
+
+    /// The existing command a message looks up by name, if any.
+    fn target_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Message::Edit { name, .. }
+            | Message::Remove { name }
+            | Message::Execute { name, .. }
+            | Message::ExecuteAttached { name, .. }
+            | Message::Start { name }
+            | Message::Kill { name }
+            | Message::Restart { name }
+            | Message::Toggle { name }
+            | Message::Disown { name }
+            | Message::Signal { name, .. }
+            | Message::ResetBackoff { name }
+            | Message::SetEnabled { name, .. }
+            | Message::History { name }
+            | Message::WaitReady { name, .. }
+            | Message::SystemdUnit { name }
+            | Message::Env { name, .. } => Some(name),
+            Message::Copy { from, .. } => Some(from),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Daemon {
+    data: Arc<Mutex<DaemonState>>,
+    name_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    stopping: Arc<AtomicBool>,
+    reaper: Reaper,
+    /// Whether the daemon exits without waiting for its processes
+    keep_running: AtomicBool,
+    watcher: Mutex<Option<PathWatcher>>,
+    /// Hands started processes to the supervising threads of `run`
+    detach: Mutex<Option<Sender<(String, Started)>>>,
+    ignore_case: bool,
+    watch_config: bool,
+    /// Limit of running (and starting) processes
+    max_procs: Option<usize>,
+    /// Whether starts at the limit queue up for `slot_freed` instead of failing
+    queue: bool,
+    /// Signalled with the `data` lock whenever a tracked process exited
+    slot_freed: Condvar,
+    /// Automatic restarts within `restart_window` seconds after which a command
+    /// is given up, for commands without their own limit
+    max_restarts: Option<u32>,
+    restart_window: Option<u64>,
+    /// How long identical read-only requests share the response of the first
+    coalesce_window: Duration,
+    /// Responses of recent read-only requests by their encoding
+    coalesced: Mutex<HashMap<Vec<u8>, Arc<Coalesced>>>,
+    /// Connections served at once, further ones are answered as busy
+    max_connections: usize,
+    connections: AtomicUsize,
+    /// Connections answered as busy since the daemon started
+    busy_rejections: AtomicU64,
+    /// Reloads of the config that replaced the commands since the daemon started
+    config_reloads: AtomicU64,
+    /// Clients following the output of commands
+    followers: Followers,
+    /// Bytes of captured output passed on per process, 0 for no limit
+    max_log_bytes: u64,
+    /// Shell the commands, hooks and exec probes run in
+    shell: String,
+    /// Taken before `run`, so the handoff from a replaced daemon is not raced
+    startup_lock: Mutex<Option<fs::File>>,
+    #[cfg(feature = "http")]
+    http: Option<http::HttpConfig>,
+}
+
+/// A read-only response shared by the identical requests received after the
+/// first, which compute it, until the window is over.
+struct Coalesced {
+    received: Instant,
+    response: std::sync::OnceLock<Response>,
+}
+
+const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_millis(25);
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 128;
+
+pub const DEFAULT_MAX_LOG_BYTES: u64 = 64 * 1024 * 1024;
+
+pub const DEFAULT_SHELL: &str = "sh";
+
+type Followers = Arc<Mutex<Vec<Follower>>>;
+
+/// Events of a file watcher
+type FileEvents = Receiver<notify::Result<notify::Event>>;
+
+/// Whether a line of output went to stderr, and its bytes.
+type OutputLine = (bool, Vec<u8>);
+
+/// A client following the output of `names`.
+struct Follower {
+    names: HashSet<String>,
+    lines: Sender<(String, bool, Vec<u8>)>,
+}
+
+/// Counts a connection as served until it is dropped.
+struct ConnectionSlot<'a>(&'a AtomicUsize);
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Watches the `watch_paths` of the running commands.
+struct PathWatcher {
+    watcher: RecommendedWatcher,
+    watched: HashSet<PathBuf>,
+}
+
+impl PathWatcher {
+    /// Watches exactly the paths of the commands that are currently running.
+    fn sync(&mut self, data: &DaemonState) {
+        let wanted: HashSet<PathBuf> = data
+            .procs
+            .keys()
+            .filter_map(|name| data.commands.get(name))
+            .flat_map(|command| command.watch_paths.iter().cloned())
+            .collect();
+        for path in self.watched.difference(&wanted) {
+            let _ = self.watcher.unwatch(path);
+        }
+        for path in wanted.difference(&self.watched) {
+            if let Err(e) = self.watcher.watch(path, RecursiveMode::Recursive) {
+                log::warn!("could not watch {}: {e}", path.display());
+            }
+        }
+        self.watched = wanted;
+    }
+}
+
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+impl Daemon {
+    pub fn new(keep: bool) -> Self {
+        Self {
+            data: Arc::from(Mutex::from(DaemonState::new(keep))),
+            coalesce_window: DEFAULT_COALESCE_WINDOW,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_log_bytes: DEFAULT_MAX_LOG_BYTES,
+            shell: DEFAULT_SHELL.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Sets how long a read-only response is shared, zero turns coalescing off.
+    pub fn with_coalesce_window(mut self, window: Duration) -> Self {
+        self.coalesce_window = window;
+        self
+    }
+
+    pub fn with_persist_mode(self, persist_mode: PersistMode) -> Self {
+        self.data.lock().expect("working mutex").persist_mode = persist_mode;
+        self
+    }
+
+    pub fn with_durable(self, durable: bool) -> Self {
+        self.data.lock().expect("working mutex").durable = durable;
+        self
+    }
+
+    /// Writes the state file as compact json, for large states where pretty
+    /// printing slows down the writes. The config is always pretty printed.
+    pub fn with_compact_state(self, compact_state: bool) -> Self {
+        self.data.lock().expect("working mutex").compact_state = compact_state;
+        self
+    }
+
+    /// A daemon that neither reads nor writes the config and state files, keeping
+    /// everything in memory for its lifetime. All of it is lost on shutdown.
+    pub fn new_ephemeral() -> Self {
+        let daemon = Self {
+            data: Arc::from(Mutex::from(DaemonState::default())),
+            coalesce_window: DEFAULT_COALESCE_WINDOW,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_log_bytes: DEFAULT_MAX_LOG_BYTES,
+            shell: DEFAULT_SHELL.into(),
+            ..Default::default()
+        };
+        daemon.with_ephemeral()
+    }
+
+    /// A daemon that reads the config and state files but never writes them, for
+    /// runs without a daemon. Fails on an invalid config.
+    pub fn new_read_only() -> Result<Self, String> {
+        Ok(Self {
+            data: Arc::from(Mutex::from(DaemonState::open(false, true)?)),
+            coalesce_window: DEFAULT_COALESCE_WINDOW,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_log_bytes: DEFAULT_MAX_LOG_BYTES,
+            shell: DEFAULT_SHELL.into(),
+            ..Default::default()
+        })
+    }
+
+    /// Leaves the config and state files alone from now on.
+    pub fn with_ephemeral(self) -> Self {
+        self.data.lock().expect("working mutex").ephemeral = true;
+        self
+    }
+
+    pub fn with_ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    pub fn with_watch_config(mut self, watch_config: bool) -> Self {
+        self.watch_config = watch_config;
+        self
+    }
+
+    /// Hands over the [`startup_lock`] taken for replacing a running daemon, which
+    /// `run` then holds on to until it listens.
+    pub fn with_startup_lock(self, lock: fs::File) -> Self {
+        *self.startup_lock.lock().expect("working mutex") = Some(lock);
+        self
+    }
+
+    /// Limits the captured output passed on per process, unless a command has a
+    /// limit of its own. Zero lifts the limit.
+    pub fn with_max_log_bytes(mut self, max_log_bytes: u64) -> Self {
+        self.max_log_bytes = max_log_bytes;
+        self
+    }
+
+    /// Runs the commands, hooks and exec probes in `shell` instead of `sh`,
+    /// looked up in PATH unless it is a path.
+    pub fn with_shell(mut self, shell: String) -> Self {
+        self.shell = shell;
+        self
+    }
+
+    /// Sets how many connections are served at once, each one waiting on a process
+    /// counts for as long as it waits.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn with_max_procs(mut self, max_procs: Option<usize>, queue: bool) -> Self {
+        self.max_procs = max_procs;
+        self.queue = queue;
+        self
+    }
+
+    pub fn with_restart_limit(mut self, max_restarts: Option<u32>, window: Option<u64>) -> Self {
+        self.max_restarts = max_restarts;
+        self.restart_window = window;
+        self
+    }
+
+    #[cfg(feature = "http")]
+    pub fn with_http(mut self, addr: String, token: String) -> Self {
+        self.http = Some(http::HttpConfig { addr, token });
+        self
+    }
+
+    fn sync_watches(&self) {
+        let data = self.data.lock().expect("working mutex");
+        if let Some(watcher) = self.watcher.lock().expect("working mutex").as_mut() {
+            watcher.sync(&data);
+        }
+    }
+
+    /// Restarts running processes once their watched paths stopped changing for a moment.
+    fn restart_on_changes<'scope, 'env>(
+        &'env self,
+        events: FileEvents,
+        scope: &'scope Scope<'scope, 'env>,
+    ) {
+        let mut changed: HashMap<String, Instant> = HashMap::new();
+        while !self.stopping.load(Ordering::SeqCst) {
+            if let Ok(Ok(event)) = events.recv_timeout(PROBE_INTERVAL) {
+                if event.kind.is_access() {
+                    continue;
+                }
+                let data = self.data.lock().expect("working mutex");
+                for name in data.procs.keys() {
+                    let Some(command) = data.commands.get(name) else {
+                        continue;
+                    };
+                    let affected = event
+                        .paths
+                        .iter()
+                        .any(|path| command.watch_paths.iter().any(|w| path.starts_with(w)));
+                    if affected {
+                        changed.insert(name.clone(), Instant::now());
+                    }
+                }
+            }
+            let settled: Vec<String> = changed
+                .iter()
+                .filter(|(_, at)| at.elapsed() >= WATCH_DEBOUNCE)
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in settled {
+                changed.remove(&name);
+                scope.spawn(move || self.restart(name));
+            }
+        }
+    }
+
+    fn supervise_detached<'scope, 'env>(
+        &'env self,
+        detached: Receiver<(String, Started)>,
+        scope: &'scope Scope<'scope, 'env>,
+    ) {
+        while !self.stopping.load(Ordering::SeqCst) {
+            if let Ok((name, started)) = detached.recv_timeout(PROBE_INTERVAL) {
+                scope.spawn(move || self.supervise(name, started));
+            }
+        }
+    }
+
+    fn list(&self, page: Page) -> Response {
+        // only the page is copied under the lock, encoding it happens without
+        let (commands, runtime_only) = {
+            let data = self.data.lock().expect("working mutex");
+            let names = page.select(&data.commands);
+            let runtime_only = names
+                .iter()
+                .filter(|name| !data.persisted.contains(*name))
+                .cloned()
+                .collect();
+            let commands = names
+                .into_iter()
+                .map(|name| {
+                    let command = data.commands[&name].clone();
+                    (name, command)
+                })
+                .collect();
+            (commands, runtime_only)
+        };
+        Response::Commands {
+            commands,
+            runtime_only,
+        }
+    }
+
+    fn systemd_unit(&self, name: String) -> Response {
+        let data = self.data.lock().expect("working mutex");
+        let Some(command) = data.commands.get(&name) else {
+            return Response::failure(ErrorCode::NotFound, format!("{name} is not registered yet"));
+        };
+        match command.systemd_unit(&name, &self.shell) {
+            Ok(unit) => Response::Ok(unit),
+            Err(reason) => Response::failure(ErrorCode::Invalid, reason),
+        }
+    }
+
+    /// Registers `name` to run the command line of `pid` in its directory, with
+    /// those of its variables that the daemon does not have set the same way.
+    fn capture(&self, pid: u32, name: String, adopt: bool) -> Response {
+        let mut system = System::new();
+        system.refresh_process((pid as i32).into());
+        let Some(process) = system.process((pid as i32).into()) else {
+            return Response::failure(
+                ErrorCode::NotFound,
+                format!("there is no process with pid {pid}"),
+            );
+        };
+        if process.cmd().is_empty() {
+            return Response::failure(
+                ErrorCode::Inaccessible,
+                format!("pid {pid} has no command line to capture"),
+            );
+        }
+        let quoted: Vec<String> = process.cmd().iter().map(|arg| sh_quote(arg)).collect();
+        let mut command = CommandDef {
+            command: quoted.join(" "),
+            ..Default::default()
+        };
+        let mut notes = Vec::new();
+        match fs::read_link(format!("/proc/{pid}/cwd")) {
+            Ok(cwd) => command.cwd = Some(cwd),
+            Err(e) => notes.push(format!("its directory is not readable: {e}")),
+        }
+        match fs::read(format!("/proc/{pid}/environ")) {
+            Ok(environ) => {
+                let environ = String::from_utf8_lossy(&environ);
+                command.env = environ
+                    .split('\0')
+                    .filter_map(|var| var.split_once('='))
+                    .filter(|(key, _)| !SHELL_MAINTAINED_VARS.contains(key))
+                    .filter(|(key, value)| std::env::var(key).ok().as_deref() != Some(*value))
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect();
+            }
+            Err(e) => notes.push(format!("its environment is not readable: {e}")),
+        }
+        let info = ProcInfo::of(process);
+        let mut data = self.data.lock().expect("working mutex");
+        if data.commands.contains_key(&name) {
+            return Response::failure(ErrorCode::Conflict, format!("{name} is already registered"));
+        }
+        let mut captured = format!("Captured {name}: {}", command.command);
+        data.commands.insert(name.clone(), command);
+        if adopt {
+            data.procs.insert(name.clone(), info);
+            captured.push_str(&format!(", tracking pid {pid}"));
+        }
+        data.save_state();
+        data.write_commands_to_config_dir();
+        for note in notes {
+            captured.push_str(&format!("\n{note}"));
+        }
+        Response::Ok(captured)
+    }
+
+    fn env(&self, name: String, format: ShellSyntax) -> Response {
+        let command = {
+            let data = self.data.lock().expect("working mutex");
+            let Some(command) = data.commands.get(&name) else {
+                return Response::failure(
+                    ErrorCode::NotFound,
+                    format!("{name} is not registered yet"),
+                );
+            };
+            command.clone()
+        };
+        match command.environment() {
+            Ok(environment) => Response::Ok(format.exports(&environment)),
+            Err(reason) => Response::failure(
+                ErrorCode::Invalid,
+                format!("no environment for {name}: {reason}"),
+            ),
+        }
+    }
+
+    fn search(&self, term: &str) -> Response {
+        let term = term.to_lowercase();
+        let data = self.data.lock().expect("working mutex");
+        let mut hits: Vec<(u8, &String, &CommandDef)> = data
+            .commands
+            .iter()
+            .filter_map(|(name, command)| {
+                let lower = name.to_lowercase();
+                let rank = if lower == term {
+                    0
+                } else if lower.starts_with(&term) {
+                    1
+                } else if lower.contains(&term) {
+                    2
+                } else if command.command.to_lowercase().contains(&term) {
+                    3
+                } else {
+                    return None;
+                };
+                Some((rank, name, command))
+            })
+            .collect();
+        hits.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(b.1)));
+        Response::Matches(
+            hits.into_iter()
+                .map(|(_, name, command)| (name.clone(), command.clone()))
+                .collect(),
+        )
+    }
+
+    fn dump(&self) -> Response {
+        let data = self.data.lock().expect("working mutex");
+        Response::Dump(serde_json::to_value(data.to_file()).expect("can convert to json"))
+    }
+
+    fn info(&self) -> Response {
+        let pid = std::process::id();
+        let mut system = System::new();
+        system.refresh_process((pid as i32).into());
+        let uptime = system
+            .process((pid as i32).into())
+            .map_or(0, |process| process.run_time());
+        let data = self.data.lock().expect("working mutex");
+        Response::Info(Box::new(DaemonInfo {
+            version: env!("CARGO_PKG_VERSION").into(),
+            pid,
+            uptime,
+            socket: socket_path(),
+            // looking the config up creates its directory, which may be read-only
+            config: match data.ephemeral {
+                true => PathBuf::new(),
+                false => DaemonState::get_config_path(),
+            },
+            state: match data.ephemeral {
+                true => PathBuf::new(),
+                false => DaemonState::get_state_path(),
+            },
+            shell: self.shell.clone(),
+            persist_mode: data.persist_mode,
+            durable: data.durable,
+            compact_state: data.compact_state,
+            ephemeral: data.ephemeral,
+            watch_config: self.watch_config,
+            ignore_case: self.ignore_case,
+            max_procs: self.max_procs,
+            queue: self.queue,
+            max_restarts: self.max_restarts,
+            restart_window: self.restart_window.unwrap_or(DEFAULT_RESTART_WINDOW),
+            start_timeout: DEFAULT_START_TIMEOUT,
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+            restart_timeout: RESTART_TIMEOUT.as_secs(),
+            coalesce_window: self.coalesce_window.as_millis() as u64,
+            max_connections: self.max_connections,
+            max_log_bytes: self.max_log_bytes,
+            busy_rejections: self.busy_rejections.load(Ordering::SeqCst),
+            config_reloads: self.config_reloads.load(Ordering::SeqCst),
+            #[cfg(feature = "http")]
+            http: self.http.as_ref().map(|http| http.addr.clone()),
+            #[cfg(not(feature = "http"))]
+            http: None,
+            counters: data.counters,
+        }))
+    }
+
+    /// Sums up the commands into one status. A command is a problem once automatic
+    /// restarts gave up on it, while it waits to be restarted after a crash, while
+    /// it fails liveness probes, and when it should autostart but is not running.
+    fn health(&self) -> Response {
+        self.prune_stale();
+        let data = self.data.lock().expect("working mutex");
+        let mut names: Vec<&String> = data.commands.keys().collect();
+        names.sort();
+        let problems: Vec<HealthProblem> = names
+            .into_iter()
+            .filter_map(|name| {
+                let restart = data.restarts.get(name);
+                let running = data.procs.contains_key(name);
+                let failures = data.liveness_failures.get(name).copied().unwrap_or(0);
+                let reason = if restart.is_some_and(|r| r.given_up) && !running {
+                    "failed, gave up restarting".to_string()
+                } else if let Some(at) = restart.and_then(|r| r.pending) {
+                    let secs = at.saturating_duration_since(Instant::now()).as_secs();
+                    format!("exited unexpectedly, restarting in {secs}s")
+                } else if running && failures > 0 {
+                    format!("failed the last {failures} liveness probes")
+                } else if data.commands[name].autostart
+                    && !data.commands[name].disabled
+                    && !running
+                    && !data.starting.contains(name)
+                    && !data.queued.contains(name)
+                {
+                    "stopped, but should be running".to_string()
+                } else {
+                    return None;
+                };
+                Some(HealthProblem {
+                    name: name.clone(),
+                    reason,
+                })
+            })
+            .collect();
+        let status = if problems.is_empty() {
+            HealthStatus::Healthy
+        } else {
+            HealthStatus::Degraded
+        };
+        Response::Health { status, problems }
+    }
+
+    fn history(&self, name: String) -> Response {
+        let data = self.data.lock().expect("working mutex");
+        if !data.commands.contains_key(&name) && !data.history.contains_key(&name) {
+            return Response::failure(ErrorCode::NotFound, format!("{name} is not registered yet"));
+        }
+        let runs = data.history.get(&name).cloned().unwrap_or_default();
+        Response::History {
+            name,
+            runs: runs.into(),
+        }
+    }
+
+    /// Like [`DaemonState::prune_stale`], but scans the processes without holding the
+    /// lock, which takes long with many processes. Returns the scan along.
+    fn prune_stale(&self) -> (usize, System) {
+        let (procs, commands) = {
+            let data = self.data.lock().expect("working mutex");
+            let commands: HashMap<String, CommandDef> = data
+                .procs
+                .keys()
+                .filter_map(|name| Some((name.clone(), data.commands.get(name)?.clone())))
+                .collect();
+            (data.procs.clone(), commands)
+        };
+        let mut system = System::new();
+        system.refresh_processes();
+        let stale = stale_procs(&system, &procs, &commands);
+        let dropped = self.data.lock().expect("working mutex").drop_stale(&stale);
+        (dropped, system)
+    }
+
+    fn status(&self, page: Page, tree: bool, verbose: bool) -> Response {
+        let (stale, system) = self.prune_stale();
+        let data = self.data.lock().expect("working mutex");
+        let mut entries: Vec<StatusEntry> = page
+            .select(&data.commands)
+            .into_iter()
+            .map(|name| {
+                let restart = data.restarts.get(&name).cloned().unwrap_or_default();
+                StatusEntry {
+                    command: data.commands[&name].command.clone(),
+                    pid: data.procs.get(&name).map(|p| p.pid),
+                    restarts: restart.count,
+                    backoff: backoff_delay(restart.count + 1).as_secs(),
+                    restarting_in: restart
+                        .pending
+                        .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+                    given_up: restart.given_up,
+                    runtime_only: !data.persisted.contains(&name),
+                    disabled: data.commands[&name].disabled,
+                    liveness_failures: data.liveness_failures.get(&name).copied().unwrap_or(0),
+                    queued: data
+                        .queued
+                        .iter()
+                        .position(|queued| *queued == name)
+                        .map(|position| position + 1),
+                    argv: data
+                        .procs
+                        .get(&name)
+                        .filter(|_| verbose)
+                        .map(|info| info.argv.clone())
+                        .unwrap_or_default(),
+                    children: None,
+                    truncated: data.truncated.get(&name).copied(),
+                    name,
+                }
+            })
+            .collect();
+        drop(data);
+        if tree {
+            let children = child_pids(&system);
+            for entry in &mut entries {
+                entry.children = entry.pid.map(|pid| count_descendants(&children, pid));
+            }
+        }
+        Response::Status { entries, stale }
+    }
+}
+
+impl Daemon {
+    /// Serves clients on the socket until a shutdown. A shutdown keeping the processes
+    /// running exits the whole program instead of returning, as they outlive the daemon.
+    pub fn run(&self) {
+        let activated = activated_listener();
+        let owns_socket = activated.is_none();
+        // daemons starting together would both find no daemon and bind the socket
+        let handed_over = self.startup_lock.lock().expect("working mutex").take();
+        let lock = match handed_over {
+            Some(lock) => Some(lock),
+            None if owns_socket => match startup_lock() {
+                Ok(lock) => Some(lock),
+                Err(e) => {
+                    log::warn!("starting without the startup lock: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        if owns_socket
+            && matches!(
+                send_message(Message::Alive, Codec::Json, Some(CLIENT_TIMEOUT)),
+                Ok(Response::Alive)
+            )
+        {
+            return;
+        }
+        let stopping = self.stopping.clone();
+        let (signals_tx, signals) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGUSR2]).unwrap();
+            for signal in signals.forever() {
+                if signal == SIGINT || signal == SIGTERM {
+                    stopping.store(true, Ordering::SeqCst);
+                    break;
+                }
+                let _ = signals_tx.send(signal);
+            }
+        });
+
+        let socket = activated.unwrap_or_else(|| {
+            let _ = std::fs::remove_file(socket_path());
+            std::os::unix::net::UnixListener::bind(socket_path())
+                .expect("successfull creation of socket")
+        });
+        drop(lock);
+        socket
+            .set_nonblocking(true)
+            .expect("can set socket to nonblocking");
+        std::thread::scope(|s| {
+            let (events_tx, events) = std::sync::mpsc::channel();
+            match notify::recommended_watcher(events_tx) {
+                Ok(watcher) => {
+                    *self.watcher.lock().expect("working mutex") = Some(PathWatcher {
+                        watcher,
+                        watched: HashSet::new(),
+                    });
+                    self.sync_watches();
+                    s.spawn(move || self.restart_on_changes(events, s));
+                }
+                Err(e) => log::warn!("could not watch files, watch paths are ignored: {e}"),
+            }
+            s.spawn(move || self.handle_signals(signals));
+            if self.watch_config {
+                match watch_config_file() {
+                    Ok((watcher, config_events)) => {
+                        s.spawn(move || self.reload_on_changes(watcher, config_events));
+                    }
+                    Err(e) => log::warn!("could not watch the config, edits need a SIGHUP: {e}"),
+                }
+            }
+            let (detach_tx, detached) = std::sync::mpsc::channel();
+            *self.detach.lock().expect("working mutex") = Some(detach_tx);
+            s.spawn(move || self.supervise_detached(detached, s));
+            s.spawn(move || self.autostart());
+            #[cfg(feature = "http")]
+            if let Some(config) = &self.http {
+                s.spawn(move || http::serve(self, config, s));
+            }
+            s.spawn(move || self.probe_liveness());
+            let persist_mode = self.data.lock().expect("working mutex").persist_mode;
+            if persist_mode == PersistMode::Async {
+                let (dirty_tx, dirty) = std::sync::mpsc::channel();
+                self.data.lock().expect("working mutex").dirty = Some(dirty_tx);
+                s.spawn(move || self.persist_in_background(dirty));
+            }
+            if let Some(interval) = sd_notify::watchdog_enabled() {
+                s.spawn(move || self.feed_watchdog(interval));
+            }
+            // without a NOTIFY_SOCKET, that is outside of systemd, these are no-ops
+            let _ = sd_notify::notify(&[sd_notify::NotifyState::Ready]);
+            while !self.stopping.load(Ordering::SeqCst) {
+                let connection = socket.accept();
+                match connection {
+                    Ok((stream, _)) => {
+                        if self.connections.fetch_add(1, Ordering::SeqCst) >= self.max_connections {
+                            self.connections.fetch_sub(1, Ordering::SeqCst);
+                            self.reject_busy(stream);
+                            continue;
+                        }
+                        let slot = ConnectionSlot(&self.connections);
+                        s.spawn(move || {
+                            let _slot = slot;
+                            self.serve_connection(stream)
+                        });
+                    }
+                    Err(_) => std::thread::sleep(std::time::Duration::from_millis(160)),
+                }
+            }
+            let _ = sd_notify::notify(&[sd_notify::NotifyState::Stopping]);
+            if self.keep_running.load(Ordering::SeqCst) {
+                // the supervising threads wait for the processes, so do not join them
+                self.data.lock().expect("working mutex").write_state();
+                if owns_socket {
+                    let _ = std::fs::remove_file(socket_path());
+                }
+                std::process::exit(0);
+            }
+        });
+        self.data.lock().expect("working mutex").write_state();
+        // an activated socket belongs to systemd, which keeps listening on it
+        if owns_socket {
+            std::fs::remove_file(socket_path()).expect("can remove socket");
+        }
+    }
+
+    /// Writes the state file whenever it was marked dirty, coalescing the marks
+    /// that arrived while writing. The final write happens when `run` returns.
+    fn persist_in_background(&self, dirty: Receiver<()>) {
+        while !self.stopping.load(Ordering::SeqCst) {
+            if dirty.recv_timeout(PROBE_INTERVAL).is_err() {
+                continue;
+            }
+            while dirty.try_recv().is_ok() {}
+            let (content, durable) = {
+                let data = self.data.lock().expect("working mutex");
+                (data.state_content(), data.durable)
+            };
+            if let Err(e) = write_atomic(&DaemonState::get_state_path(), &content, durable) {
+                log::error!("could not write the state file: {e}");
+            }
+        }
+    }
+
+    /// Runs the liveness probes of the running processes at their intervals and kills
+    /// those failing too often in a row, for `supervise` to restart them.
+    fn probe_liveness(&self) {
+        // pid and time of the last probe, or of the start not to probe right away
+        let mut probed: HashMap<String, (u32, Instant)> = HashMap::new();
+        while !self.stopping.load(Ordering::SeqCst) {
+            let due: Vec<(String, u32, CommandDef)> = {
+                let data = self.data.lock().expect("working mutex");
+                probed.retain(|name, (pid, _)| data.procs.get(name).is_some_and(|p| p.pid == *pid));
+                data.procs
+                    .iter()
+                    .filter_map(|(name, info)| {
+                        let command = data.commands.get(name)?;
+                        command.liveness.as_ref()?;
+                        let (_, at) = probed
+                            .entry(name.clone())
+                            .or_insert((info.pid, Instant::now()));
+                        (at.elapsed() >= command.liveness_interval())
+                            .then(|| (name.clone(), info.pid, command.clone()))
+                    })
+                    .collect()
+            };
+            for (name, pid, command) in due {
+                let passed = command
+                    .liveness
+                    .as_ref()
+                    .is_some_and(|probe| probe.check(&self.shell));
+                probed.insert(name.clone(), (pid, Instant::now()));
+                let mut data = self.data.lock().expect("working mutex");
+                if data.procs.get(&name).is_none_or(|p| p.pid != pid) {
+                    continue;
+                }
+                if passed {
+                    data.liveness_failures.remove(&name);
+                    continue;
+                }
+                let failures = data.liveness_failures.entry(name.clone()).or_default();
+                *failures += 1;
+                let failures = *failures;
+                log::warn!(command = name, pid, failures; "liveness probe of {name} failed");
+                if failures < command.liveness_failures() {
+                    continue;
+                }
+                let mut system = System::new();
+                system.refresh_process((pid as i32).into());
+                if let Some(process) = system.process((pid as i32).into()) {
+                    // still tracked, so supervise sees an unexpected exit and restarts it
+                    process.kill();
+                    data.hung.insert(name.clone());
+                    log::warn!(command = name, pid; "killed {name}, it failed {failures} liveness probes in a row");
+                }
+            }
+            std::thread::sleep(PROBE_INTERVAL);
+        }
+    }
+
+    /// Tells systemd the daemon is alive twice per watchdog interval, until it stops.
+    fn feed_watchdog(&self, interval: Duration) {
+        let mut fed = Instant::now();
+        while !self.stopping.load(Ordering::SeqCst) {
+            if fed.elapsed() >= interval / 2 {
+                let _ = sd_notify::notify(&[sd_notify::NotifyState::Watchdog]);
+                fed = Instant::now();
+            }
+            std::thread::sleep(PROBE_INTERVAL);
+        }
+    }
+
+    /// Reads one message from a client and writes back the response.
+    fn serve_connection(&self, mut stream: std::os::unix::net::UnixStream) {
+        let mut msg_raw = Vec::new();
+        let _ = stream.set_read_timeout(Some(std::time::Duration::from_millis(160)));
+        let _ = stream.read_to_end(&mut msg_raw);
+        let codec = Codec::detect(&msg_raw);
+        let mut shutdown = false;
+        let response = match codec.decode(&msg_raw) {
+            Ok(msg) => {
+                shutdown = matches!(msg, Message::Shutdown { .. });
+                self.handle_streaming(msg, &mut |frame| {
+                    stream.write_all(&codec.encode(frame)).is_ok()
+                })
+            }
+            Err(e) => {
+                const SNIPPET_LEN: usize = 80;
+                let received = String::from_utf8_lossy(&msg_raw);
+                let mut snippet: String = received.chars().take(SNIPPET_LEN).collect();
+                if received.chars().count() > SNIPPET_LEN {
+                    snippet.push_str("...");
+                }
+                Response::failure(
+                    ErrorCode::Invalid,
+                    format!(
+                        "Could not parse the command ({e}), received {snippet:?}. \
+                     The client and daemon may speak incompatible protocols, \
+                     restart the daemon if it is older than the client"
+                    ),
+                )
+            }
+        };
+        let _ = stream.write_all(&codec.encode(&response));
+        if shutdown {
+            self.stopping.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Answers a connection beyond `max_connections` without a thread of its own, in
+    /// the codec of the message.
+    fn reject_busy(&self, mut stream: std::os::unix::net::UnixStream) {
+        let rejections = self.busy_rejections.fetch_add(1, Ordering::SeqCst) + 1;
+        log::warn!(rejections; "busy, rejected a connection beyond {} at once", self.max_connections);
+        // read what was sent, closing with it unread would reset the connection
+        let mut received = Vec::new();
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(Duration::from_millis(10)));
+        let _ = stream.read_to_end(&mut received);
+        let response = Response::failure(
+            ErrorCode::LimitReached,
+            format!(
+                "the daemon is busy serving {} connections, try again later",
+                self.max_connections
+            ),
+        );
+        let _ = stream.write_all(&Codec::detect(&received).encode(&response));
+    }
+
+    /// Reloads the config file on SIGHUP and re-executes the daemon on SIGUSR2,
+    /// until the daemon stops.
+    fn handle_signals(&self, signals: Receiver<i32>) {
+        while !self.stopping.load(Ordering::SeqCst) {
+            match signals.recv_timeout(PROBE_INTERVAL) {
+                Ok(SIGHUP) => self.reload_config(),
+                Ok(SIGUSR2) => self.reexec(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Replaces the daemon with the binary now installed at its path, which picks
+    /// up the saved state with -k. The processes stay children of this process,
+    /// and the new image rebinds the socket. Stays up if the binary is unusable.
+    fn reexec(&self) {
+        use std::os::unix::process::CommandExt;
+        let exe = match std::env::current_exe() {
+            // the binary was replaced since this process started
+            Ok(exe) => PathBuf::from(exe.to_string_lossy().trim_end_matches(" (deleted)")),
+            Err(e) => return log::error!("not re-executing, could not find the binary: {e}"),
+        };
+        match Command::new(&exe).arg("--version").output() {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => {
+                return log::error!(
+                    "not re-executing, {} --version failed with {}",
+                    exe.display(),
+                    output.status
+                )
+            }
+            Err(e) => return log::error!("not re-executing, could not run {}: {e}", exe.display()),
+        }
+        let mut args: Vec<std::ffi::OsString> = std::env::args_os().skip(1).collect();
+        if !args.iter().any(|arg| arg == "-k") {
+            args.insert(0, "-k".into());
+        }
+        // an activated socket has to survive the exec, the new image adopts it again
+        let activated: Vec<i32> = sd_notify::listen_fds().into_iter().flatten().collect();
+        for &fd in &activated {
+            // SAFETY: fcntl only changes the flags of an fd this process owns
+            unsafe { libc::fcntl(fd, libc::F_SETFD, 0) };
+        }
+        // holding the lock keeps the saved state current until the image is replaced
+        let data = self.data.lock().expect("working mutex");
+        data.write_state();
+        log::info!("re-executing {}", exe.display());
+        let e = Command::new(&exe).args(&args).exec();
+        drop(data);
+        for &fd in &activated {
+            // SAFETY: as above
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        log::error!("could not re-execute {}, staying up: {e}", exe.display());
+    }
+
+    /// Reloads the config file once the edits [`watch_config_file`] reports settle.
+    /// The daemon's own writes refresh the stamp of the config, so they are
+    /// recognized and skipped.
+    fn reload_on_changes(&self, _watcher: RecommendedWatcher, events: FileEvents) {
+        let config_path = DaemonState::get_config_path();
+        let mut changed: Option<Instant> = None;
+        while !self.stopping.load(Ordering::SeqCst) {
+            if let Ok(Ok(event)) = events.recv_timeout(PROBE_INTERVAL) {
+                if !event.kind.is_access() && event.paths.contains(&config_path) {
+                    changed = Some(Instant::now());
+                }
+            }
+            if changed.is_some_and(|at| at.elapsed() >= WATCH_DEBOUNCE) {
+                changed = None;
+                self.reload_config();
+            }
+        }
+    }
+
+    fn reload_config(&self) {
+        let reloaded = self.data.lock().expect("working mutex").reload_config();
+        match reloaded {
+            Ok(Some(changed)) => {
+                self.config_reloads.fetch_add(1, Ordering::SeqCst);
+                log::info!("reloaded {}", DaemonState::get_config_path().display());
+                self.sync_watches();
+                for name in changed {
+                    let (killed, started) = self.replace(&name);
+                    match started {
+                        Ok(started) => {
+                            log::info!(command = name.as_str(), pid = started.pid;
+                                "restarted {name}, its definition changed");
+                            self.detach(name, started);
+                        }
+                        Err(response) => {
+                            for failure in [killed, response].iter().filter(|r| r.failed()) {
+                                log::error!(command = name.as_str(); "could not restart {name}: {failure}");
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(None) => log::info!(
+                "{} is unchanged since it was last loaded, skipping the reload",
+                DaemonState::get_config_path().display()
+            ),
+            Err(e) => log::error!("could not reload the config, keeping the current commands: {e}"),
+        }
+    }
+
+    /// Carries out a message, independent of the transport it arrived on.
+    pub fn handle(&self, msg: Message) -> Response {
+        self.handle_streaming(msg, &mut |_| false)
+    }
+
+    /// Like [`Daemon::handle`], passing the frames of streamed responses to
+    /// `output` for as long as it returns true.
+    pub fn handle_streaming(
+        &self,
+        msg: Message,
+        output: &mut dyn FnMut(&Response) -> bool,
+    ) -> Response {
+        if self.coalesce_window.is_zero() {
+            return self.carry_out(msg, output);
+        }
+        if !msg.is_read_only() {
+            if msg.is_mutating() {
+                // later reads have to see the change
+                self.coalesced.lock().expect("working mutex").clear();
+            }
+            return self.carry_out(msg, output);
+        }
+        let shared = {
+            let mut coalesced = self.coalesced.lock().expect("working mutex");
+            coalesced.retain(|_, shared| shared.received.elapsed() < self.coalesce_window);
+            let key = Codec::Json.encode(&msg);
+            coalesced
+                .entry(key)
+                .or_insert_with(|| {
+                    Arc::new(Coalesced {
+                        received: Instant::now(),
+                        response: std::sync::OnceLock::new(),
+                    })
+                })
+                .clone()
+        };
+        // requests arriving while the first computes the response wait for it
+        shared
+            .response
+            .get_or_init(|| self.carry_out(msg, output))
+            .clone()
+    }
+
+    fn carry_out(&self, mut msg: Message, output: &mut dyn FnMut(&Response) -> bool) -> Response {
+        let mut matched = None;
+        if self.ignore_case {
+            if let Some(name) = msg.target_mut() {
+                let data = self.data.lock().expect("working mutex");
+                match data.resolve_name(name) {
+                    Ok(canonical) if canonical != *name => {
+                        matched = Some(format!("Matched {name} as {canonical}"));
+                        *name = canonical;
+                    }
+                    Ok(_) => {}
+                    Err(response) => return response,
+                }
+            }
+        }
+        let response = match msg {
+            Message::Add { name, command } => self.add(name, command),
+            Message::AddMany { commands } => self.add_many(commands),
+            Message::Edit {
+                name,
+                command,
+                fields,
+            } => self.edit(name, command, fields),
+            Message::Remove { name } => self.remove(name),
+            Message::AddTemplate { name, command } => self.add_template(name, command),
+            Message::RemoveTemplate { name } => self.remove_template(name),
+            Message::ListTemplates => self.list_templates(),
+            Message::AddFromTemplate {
+                template,
+                name,
+                vars,
+            } => self.add_from_template(template, name, vars),
+            Message::Copy { from, to, force } => self.copy(from, to, force),
+            Message::Kill { name } => self.kill(name),
+            Message::Restart { name } => self.restart(name),
+            Message::Toggle { name } => self.toggle(name),
+            Message::Disown { name } => self.disown(name),
+            Message::Signal { name, signal } => self.signal(name, signal),
+            Message::ResetBackoff { name } => self.reset_backoff(name),
+            Message::ResetBackoffAll => self.reset_backoff_all(),
+            Message::ResetBackoffMatching { selector } => self.reset_backoff_matching(&selector),
+            Message::SetEnabled { name, enabled } => self.set_enabled(name, enabled),
+            Message::SystemdUnit { name } => self.systemd_unit(name),
+            Message::Capture { pid, name, adopt } => self.capture(pid, name, adopt),
+            Message::Env { name, format } => self.env(name, format),
+            Message::Reset {
+                commands,
+                procs,
+                counters,
+            } => self.reset(commands, procs, counters),
+            Message::Shutdown { keep_running } => self.shutdown(keep_running),
+            Message::Execute { name, force } => self.execute(name, force),
+            Message::ExecuteAttached { name, force } => self.execute_attached(name, force, output),
+            Message::Follow { names } => self.follow(names, output),
+            Message::Start { name } => self.start_detached(&[name]),
+            Message::StartAll => self.start_all(),
+            Message::StartMatching { selector } => self.start_matching(&selector),
+            Message::StopAll => self.stop_all(),
+            Message::List { page } => self.list(page),
+            Message::Status {
+                page,
+                tree,
+                verbose,
+            } => self.status(page, tree, verbose),
+            Message::Search { term } => self.search(&term),
+            Message::Dump => self.dump(),
+            Message::Info => self.info(),
+            Message::Health => self.health(),
+            Message::History { name } => self.history(name),
+            Message::WaitReady { name, timeout } => self.wait_ready(name, timeout),
+            Message::Alive => Response::Alive,
+        };
+        match matched {
+            Some(matched) => Response::Sequence(vec![Response::Ok(matched), response]),
+            None => response,
+        }
+    }
+}
+
+/// Format version of the config file, older files are rewritten when loaded.
+const CONFIG_VERSION: u32 = 1;
+
+/// What the daemon knows at runtime, persisted as a [`state::StateFile`].
+#[derive(Default)]
+pub struct DaemonState {
+    commands: HashMap<String, CommandDef>,
+    /// Commands with placeholders that other commands are made from, kept in the config
+    templates: HashMap<String, CommandDef>,
+    procs: HashMap<String, ProcInfo>,
+    restarts: HashMap<String, RestartState>,
+    /// Names reserved by an execute that has not spawned its process yet
+    starting: HashSet<String>,
+    /// Pids of the processes killed on request that have not exited yet, by command name
+    killing: HashMap<String, u32>,
+    /// Failed liveness probes in a row of the running processes
+    liveness_failures: HashMap<String, u32>,
+    /// The limit of the processes whose captured output exceeded it
+    truncated: HashMap<String, u64>,
+    /// The latest finished runs by command name, at most [`HISTORY_LEN`] each
+    history: HashMap<String, VecDeque<state::RunRecord>>,
+    counters: state::Counters,
+    /// Processes killed for failing their liveness probe, restarted whatever their policy
+    hung: HashSet<String>,
+    /// Processes sent a signal on request, not restarted whatever their policy
+    signalled: HashSet<String>,
+    /// Names waiting for a free slot under the process limit, first in line first
+    queued: VecDeque<String>,
+    /// Whether writes are fsynced
+    durable: bool,
+    /// Whether the state file is written without indentation
+    compact_state: bool,
+    /// Whether the config and state files are left alone, for runs without a daemon
+    /// and daemons that keep everything in memory
+    ephemeral: bool,
+    /// When changes are written to the state file
+    persist_mode: PersistMode,
+    /// Marks the state dirty for the background writer of [`PersistMode::Async`]
+    dirty: Option<Sender<()>>,
+    /// The config file as of the last load or write
+    config_stamp: Option<FileStamp>,
+    /// Names of the commands in the config file as of the last load or write,
+    /// the others were restored from the state file only
+    persisted: HashSet<String>,
+}
+
+/// When the daemon writes changes of its state to the state file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PersistMode {
+    /// Before answering the request that changed it
+    #[default]
+    Sync,
+    /// Soon after, from a background thread
+    Async,
+    /// Only when the daemon shuts down, `-k` cannot recover from a crash
+    OnExit,
+}
+
+/// Modification time and size of a file, to tell whether it changed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<FileStamp> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(FileStamp {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+/// Watches the directory of the config file, as atomic writes replace the file itself.
+fn watch_config_file() -> notify::Result<(RecommendedWatcher, FileEvents)> {
+    let (config_tx, config_events) = std::sync::mpsc::channel();
+    let config_path = DaemonState::get_config_path();
+    let mut watcher = notify::recommended_watcher(config_tx)?;
+    watcher.watch(
+        config_path.parent().unwrap_or(Path::new("/")),
+        RecursiveMode::NonRecursive,
+    )?;
+    Ok((watcher, config_events))
+}
+
+/// Writes `content` to a temporary file next to `path` and renames it into place,
+/// so a crash never leaves a partially written file behind. When `durable`, the file
+/// and its directory are fsynced, so the write survives a power loss once this returns.
+fn write_atomic(path: &Path, content: &str, durable: bool) -> std::io::Result<()> {
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(content.as_bytes())?;
+    if durable {
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    if durable {
+        if let Some(dir) = path.parent() {
+            fs::File::open(dir)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, PartialEq)]
+struct ProcInfo {
+    pid: u32,
+    /// Start time in seconds since the epoch, as reported by sysinfo
+    start_time: Option<u64>,
+    /// Program and arguments the process was spawned with, variables already expanded
+    argv: Vec<String>,
+    /// OOM kills in the cgroup of the process when it started, unknown for processes
+    /// tracked by an earlier daemon
+    oom_kills: Option<OomKills>,
+}
+
+/// The `oom_kill` count of a memory cgroup, which the kernel raises whenever the
+/// OOM killer kills a process in it.
+#[derive(Clone, PartialEq)]
+struct OomKills {
+    /// `memory.events` of cgroup v2 or `memory.oom_control` of v1
+    events: PathBuf,
+    count: u64,
+}
+
+impl OomKills {
+    /// Reads the count of the memory cgroup `pid` runs in, None without one.
+    fn of(pid: u32) -> Option<Self> {
+        let cgroups = fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+        cgroups.lines().find_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let (_, controllers, cgroup) = (fields.next()?, fields.next()?, fields.next()?);
+            let cgroup = cgroup.trim_start_matches('/');
+            let events = if controllers.is_empty() {
+                Path::new("/sys/fs/cgroup")
+                    .join(cgroup)
+                    .join("memory.events")
+            } else if controllers.split(',').any(|c| c == "memory") {
+                let dir = Path::new("/sys/fs/cgroup/memory").join(cgroup);
+                dir.join("memory.oom_control")
+            } else {
+                return None;
+            };
+            let count = oom_kill_count(&events)?;
+            Some(Self { events, count })
+        })
+    }
+
+    /// Whether the OOM killer struck in the cgroup since the count was read.
+    fn rose(&self) -> bool {
+        oom_kill_count(&self.events).is_some_and(|count| count > self.count)
+    }
+}
+
+fn oom_kill_count(events: &Path) -> Option<u64> {
+    let events = fs::read_to_string(events).ok()?;
+    let count = events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))?;
+    count.trim().parse().ok()
+}
+
+impl From<state::ProcRecord> for ProcInfo {
+    fn from(record: state::ProcRecord) -> Self {
+        let state::ProcRecord {
+            pid,
+            start_time,
+            argv,
+        } = record;
+        ProcInfo {
+            pid,
+            start_time,
+            argv,
+            oom_kills: None,
+        }
+    }
+}
+
+impl From<&ProcInfo> for state::ProcRecord {
+    fn from(info: &ProcInfo) -> Self {
+        state::ProcRecord {
+            pid: info.pid,
+            start_time: info.start_time,
+            argv: info.argv.clone(),
+        }
+    }
+}
+
+impl ProcInfo {
+    fn new(pid: u32, spawned: &Command) -> Self {
+        let mut system = System::new();
+        system.refresh_process((pid as i32).into());
+        let start_time = system.process((pid as i32).into()).map(|p| p.start_time());
+        let argv = std::iter::once(spawned.get_program())
+            .chain(spawned.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        Self {
+            pid,
+            start_time,
+            argv,
+            oom_kills: OomKills::of(pid),
+        }
+    }
+
+    /// Info of a running process uniq-proc did not spawn itself.
+    fn of(process: &sysinfo::Process) -> Self {
+        let pid = i32::from(process.pid()) as u32;
+        Self {
+            pid,
+            start_time: Some(process.start_time()),
+            argv: process.cmd().to_vec(),
+            oom_kills: OomKills::of(pid),
+        }
+    }
+
+    /// Checks that `process` is still the one that was spawned for `command`,
+    /// and not an unrelated process that got the recycled pid.
+    fn matches(&self, process: &sysinfo::Process, command: Option<&CommandDef>) -> bool {
+        if process.status() == ProcessStatus::Zombie {
+            return false;
+        }
+        if let Some(start_time) = self.start_time {
+            if process.start_time().abs_diff(start_time) > 1 {
+                return false;
+            }
+            // a shell that exec'd its command shows that command line instead, only
+            // while the spawned program still runs its command line has to match
+            let cmd = process.cmd();
+            return self
+                .argv
+                .first()
+                .is_none_or(|program| cmd.first() != Some(program))
+                || cmd == self.argv;
+        }
+        // state files of older versions carry no start time, fall back to the command line
+        let Some(command) = command else {
+            return true;
+        };
+        let cmd = process.cmd();
+        cmd.join(" ") == command.command
+            || (cmd.len() == 3 && cmd[1] == "-c" && cmd[2] == command.command)
+    }
+}
+
+#[derive(Clone, Default)]
+struct RestartState {
+    /// Automatic restarts since the last stable run
+    count: u32,
+    /// When the process is going to be restarted, if it is waiting for its backoff
+    pending: Option<Instant>,
+    /// Times of the latest automatic restarts, at most one more than the limit
+    recent: VecDeque<Instant>,
+    /// Whether the restart limit was exceeded, until the command is started again
+    given_up: bool,
+}
+
+impl RestartState {
+    /// Counts a restart due now against `max` restarts within `window`, giving
+    /// up on the command when it is one too many.
+    fn exceeds(&mut self, (max, window): (u32, Duration)) -> bool {
+        self.recent.retain(|at| at.elapsed() < window);
+        self.recent.push_back(Instant::now());
+        let exceeded = self.recent.len() > max as usize;
+        if exceeded {
+            self.recent.pop_front();
+            self.given_up = true;
+        }
+        exceeded
+    }
+}
+
+const BACKOFF_START: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// A process running at least this long resets its backoff.
+const BACKOFF_RESET_AFTER: Duration = Duration::from_secs(60);
+
+/// Delay before the `count`th automatic restart in a row.
+fn backoff_delay(count: u32) -> Duration {
+    let factor = 2u32.saturating_pow(count.saturating_sub(1));
+    BACKOFF_START.saturating_mul(factor).min(BACKOFF_MAX)
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    fn restarts(self, success: bool) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !success,
+            RestartPolicy::Always => true,
+        }
+    }
+
+    fn is_never(&self) -> bool {
+        *self == RestartPolicy::Never
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommandDef {
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readiness: Option<Probe>,
+    /// Seconds the readiness probe may take to pass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_timeout: Option<u64>,
+    /// Seconds between SIGTERM and SIGKILL when the process is killed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_timeout: Option<u64>,
+    /// Probe run periodically for as long as the process runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liveness: Option<Probe>,
+    /// Seconds between two liveness probes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liveness_interval: Option<u64>,
+    /// Failed liveness probes in a row after which the process is killed and restarted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liveness_failures: Option<u32>,
+    /// Hook run to completion before the process is spawned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_start: Option<String>,
+    /// Hook run to completion after the process exited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_stop: Option<String>,
+    #[serde(default, skip_serializing_if = "RestartPolicy::is_never")]
+    pub restart: RestartPolicy,
+    /// Automatic restarts within the restart window after which the command is
+    /// given up, overriding the limit of the daemon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_restarts: Option<u32>,
+    /// Seconds counted back for `max_restarts`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_window: Option<u64>,
+    #[serde(default, skip_serializing_if = "ExpandEnv::is_off")]
+    pub expand_env: ExpandEnv,
+    /// Webhook notified whenever the process exits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify_url: Option<String>,
+    /// Paths whose changes restart the running process
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub watch_paths: Vec<PathBuf>,
+    /// Commands started (and waited for to be ready) before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    /// Commands started before this one when they are started at the same time,
+    /// without being pulled in or required
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+    /// Commands that may not run at the same time as this one, in both directions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts_with: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Key-value tags that selectors pick commands by
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    /// File of further variables, re-read whenever the process starts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<PathBuf>,
+    /// Whether the process gets only `env` and the env file, not the daemon's variables
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub clean_env: bool,
+    /// Working directory of the process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Whether the command runs in `sh -lc`, sourcing the login profile
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub login_shell: bool,
+    /// Whether a config reload changing this definition restarts the running process
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub restart_on_change: bool,
+    /// Whether the daemon starts the command when it comes up, and it should keep
+    /// running from then on, a stopped one makes the daemon degraded
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub autostart: bool,
+    /// Whether the command is parked, started neither automatically nor by a plain execute
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    /// File mode creation mask of the process, in octal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub umask: Option<String>,
+    /// Kernel enforced limit of open file descriptors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rlimit_nofile: Option<u64>,
+    /// Kernel enforced limit of address space in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rlimit_as: Option<u64>,
+    /// Kernel enforced limit of CPU time in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rlimit_cpu: Option<u64>,
+    /// Whether the command runs as pid 1 of its own PID namespace. The tracked
+    /// process stays outside and waits for it, when that one is killed or signalled
+    /// the kernel kills the whole namespace.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unshare_pid: bool,
+    /// Whether the command gets a private copy of the mounts, with a /proc of
+    /// its own when it also has a PID namespace
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unshare_mount: bool,
+    /// Whether the output goes to pipes the daemon copies to its own output and to
+    /// followers. Processes that outlive the daemon then can no longer write it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub capture_output: bool,
+    /// Bytes of captured output passed on per process, overriding the daemon's limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_log_bytes: Option<u64>,
+}
+
+/// Whether and how variables in a command are expanded by uniq-proc itself,
+/// instead of being left to the shell.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExpandEnv {
+    #[default]
+    Off,
+    /// Undefined variables keep the command from starting
+    Strict,
+    /// Undefined variables expand to an empty string
+    Empty,
+}
+
+impl ExpandEnv {
+    fn is_off(&self) -> bool {
+        *self == ExpandEnv::Off
+    }
+}
+
+/// Replaces `$VAR` and `${VAR}` with the value `lookup` gives for `VAR`, `$$` is
+/// a literal `$`. A `$` not starting a variable is kept as is.
+fn expand_env(
+    command: &str,
+    mode: ExpandEnv,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, String> {
+    if mode == ExpandEnv::Off {
+        return Ok(command.to_string());
+    }
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut expanded = String::with_capacity(command.len());
+    let mut rest = command;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        let var = if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            let Some(end) = braced.find('}') else {
+                return Err(format!("unterminated ${{ in {command}"));
+            };
+            let var = &braced[..end];
+            if var.is_empty()
+                || var.starts_with(|c: char| c.is_ascii_digit())
+                || !var.chars().all(is_name)
+            {
+                return Err(format!("${{{var}}} is not a valid variable"));
+            }
+            rest = &braced[end + 1..];
+            var
+        } else if rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            let end = rest.find(|c| !is_name(c)).unwrap_or(rest.len());
+            let var = &rest[..end];
+            rest = &rest[end..];
+            var
+        } else {
+            expanded.push('$');
+            continue;
+        };
+        match lookup(var) {
+            Some(value) => expanded.push_str(&value),
+            None if mode == ExpandEnv::Empty => {}
+            None => return Err(format!("{var} is not defined")),
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+const DEFAULT_START_TIMEOUT: u64 = 30;
+const DEFAULT_STOP_TIMEOUT: u64 = 5;
+const DEFAULT_LIVENESS_INTERVAL: u64 = 10;
+const DEFAULT_LIVENESS_FAILURES: u32 = 3;
+const DEFAULT_RESTART_WINDOW: u64 = 60;
+
+impl CommandDef {
+    fn validate(&self) -> Result<(), String> {
+        if self.command.trim().is_empty() {
+            return Err("the command is empty".into());
+        }
+        if self.start_timeout == Some(0) {
+            return Err("the start timeout must be positive".into());
+        }
+        if self.liveness_interval == Some(0) {
+            return Err("the liveness interval must be positive".into());
+        }
+        if self.liveness_failures == Some(0) {
+            return Err("the liveness failure threshold must be positive".into());
+        }
+        if self.restart_window == Some(0) {
+            return Err("the restart window must be positive".into());
+        }
+        if let Some(umask) = &self.umask {
+            octal_mode(umask)?;
+        }
+        let limits = [self.rlimit_nofile, self.rlimit_as, self.rlimit_cpu];
+        if limits.contains(&Some(0)) {
+            return Err("resource limits must be positive".into());
+        }
+        for probe in [&self.readiness, &self.liveness].into_iter().flatten() {
+            if let Probe::Tcp(addr) = probe {
+                if addr.to_socket_addrs().is_err() {
+                    return Err(format!("{addr} is not a valid address"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The variables set for the process, those of `env` override the env file.
+    fn environment(&self) -> Result<HashMap<String, String>, String> {
+        let mut environment = match &self.env_file {
+            Some(path) => read_env_file(path)?,
+            None => HashMap::new(),
+        };
+        environment.extend(self.env.clone());
+        Ok(environment)
+    }
+
+    /// The command line to run, with variables expanded if enabled. Variables of
+    /// `environment` shadow those of the daemon, which are not seen with `clean_env`.
+    fn command_line(&self, environment: &HashMap<String, String>) -> Result<String, String> {
+        expand_env(&self.command, self.expand_env, |var| {
+            let inherited = || std::env::var(var).ok().filter(|_| !self.clean_env);
+            environment.get(var).cloned().or_else(inherited)
+        })
+    }
+
+    fn start_timeout(&self) -> Duration {
+        Duration::from_secs(self.start_timeout.unwrap_or(DEFAULT_START_TIMEOUT))
+    }
+
+    fn stop_timeout(&self) -> Duration {
+        Duration::from_secs(self.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT))
+    }
+
+    fn liveness_interval(&self) -> Duration {
+        Duration::from_secs(self.liveness_interval.unwrap_or(DEFAULT_LIVENESS_INTERVAL))
+    }
+
+    fn liveness_failures(&self) -> u32 {
+        self.liveness_failures.unwrap_or(DEFAULT_LIVENESS_FAILURES)
+    }
+
+    /// Restarts allowed within which window, the daemon's limits fill in what is not set.
+    fn restart_limit(&self, max: Option<u32>, window: Option<u64>) -> Option<(u32, Duration)> {
+        let window = self
+            .restart_window
+            .or(window)
+            .unwrap_or(DEFAULT_RESTART_WINDOW);
+        self.max_restarts
+            .or(max)
+            .map(|max| (max, Duration::from_secs(window)))
+    }
+
+    fn has_rlimits(&self) -> bool {
+        self.rlimit_nofile.is_some() || self.rlimit_as.is_some() || self.rlimit_cpu.is_some()
+    }
+
+    /// Checks the resource limits can be set, which only root may do above the
+    /// hard limits of the daemon.
+    fn check_rlimits(&self) -> Result<(), String> {
+        let limits = [
+            ("nofile", libc::RLIMIT_NOFILE, self.rlimit_nofile),
+            ("as", libc::RLIMIT_AS, self.rlimit_as),
+            ("cpu", libc::RLIMIT_CPU, self.rlimit_cpu),
+        ];
+        for (name, resource, limit) in limits {
+            let Some(limit) = limit else { continue };
+            let mut current = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            // SAFETY: getrlimit only writes the struct it is given
+            if unsafe { libc::getrlimit(resource, &mut current) } != 0 {
+                continue;
+            }
+            // SAFETY: geteuid cannot fail
+            let root = unsafe { libc::geteuid() } == 0;
+            if limit > current.rlim_max && !root {
+                return Err(format!(
+                    "the {name} limit {limit} exceeds the hard limit {} of the daemon",
+                    current.rlim_max
+                ));
+            }
+        }
+        // not even root gets more file descriptors than the kernel allows a process
+        let nr_open = fs::read_to_string("/proc/sys/fs/nr_open")
+            .ok()
+            .and_then(|nr_open| nr_open.trim().parse::<u64>().ok());
+        if let Some((limit, nr_open)) = self.rlimit_nofile.zip(nr_open) {
+            if limit > nr_open {
+                return Err(format!(
+                    "the nofile limit {limit} exceeds the maximum {nr_open} of fs.nr_open"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the daemon may create the namespaces, which takes CAP_SYS_ADMIN.
+    fn check_namespaces(&self) -> Result<(), String> {
+        const CAP_SYS_ADMIN: u32 = 21;
+        if !self.unshare_pid && !self.unshare_mount {
+            return Ok(());
+        }
+        let effective = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                let caps = status
+                    .lines()
+                    .find_map(|line| line.strip_prefix("CapEff:"))?;
+                u64::from_str_radix(caps.trim(), 16).ok()
+            })
+            .ok_or("namespaces are only supported on Linux")?;
+        if effective & (1 << CAP_SYS_ADMIN) == 0 {
+            return Err(
+                "new namespaces need a daemon running as root or with CAP_SYS_ADMIN".into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Sets up what the child changes about itself between fork and exec. A
+    /// failure there fails the spawn.
+    fn prepare_child(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        let umask = self
+            .umask
+            .as_deref()
+            .and_then(|umask| octal_mode(umask).ok());
+        let limits = [
+            (libc::RLIMIT_NOFILE, self.rlimit_nofile),
+            (libc::RLIMIT_AS, self.rlimit_as),
+            (libc::RLIMIT_CPU, self.rlimit_cpu),
+        ];
+        let (unshare_pid, unshare_mount) = (self.unshare_pid, self.unshare_mount);
+        if umask.is_none() && !self.has_rlimits() && !unshare_pid && !unshare_mount {
+            return;
+        }
+        // SAFETY: between fork and exec only async-signal-safe calls are allowed,
+        // the closure makes nothing but plain syscalls on copied values
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(umask) = umask {
+                    libc::umask(umask);
+                }
+                for (resource, limit) in limits {
+                    let Some(limit) = limit else { continue };
+                    let limit = libc::rlimit {
+                        rlim_cur: limit,
+                        rlim_max: limit,
+                    };
+                    if libc::setrlimit(resource, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if unshare_pid || unshare_mount {
+                    enter_namespaces(unshare_pid, unshare_mount)?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Renders a systemd service unit running this command in `program` the way
+    /// the daemon would. Its PrivatePIDs needs systemd 257 or later. Fails for
+    /// what a unit cannot reproduce: expanding variables before the shell runs,
+    /// and variables the env file would override, as systemd lets an
+    /// EnvironmentFile= win over Environment=.
+    fn systemd_unit(&self, name: &str, program: &str) -> Result<String, String> {
+        if !self.expand_env.is_off() {
+            return Err(format!(
+                "{name} expands variables itself, which a systemd unit cannot, \
+                 set --expand-env off first"
+            ));
+        }
+        if let Some(path) = &self.env_file {
+            let from_file = read_env_file(path).unwrap_or_default();
+            let mut overridden: Vec<&String> = self
+                .env
+                .keys()
+                .filter(|key| from_file.contains_key(*key))
+                .collect();
+            if !overridden.is_empty() {
+                overridden.sort();
+                let keys: Vec<&str> = overridden.iter().map(|key| key.as_str()).collect();
+                return Err(format!(
+                    "{} would override --env {} in a systemd unit, unlike in uniq-proc",
+                    path.display(),
+                    keys.join(", ")
+                ));
+            }
+        }
+        // systemd before 239 wants an absolute path
+        let program = match program {
+            DEFAULT_SHELL => "/bin/sh",
+            program => program,
+        };
+        let exec = |command: &str| format!("{program} -c {}", systemd_quote(command));
+        let mut service = Vec::new();
+        if !self.clean_env {
+            service.push(
+                "# uniq-proc passes on its own environment, systemd only the variables below"
+                    .to_string(),
+            );
+        }
+        if let Some(path) = &self.env_file {
+            service.push(format!("EnvironmentFile={}", path.display()));
+        }
+        let mut env: Vec<(&String, &String)> = self.env.iter().collect();
+        env.sort();
+        for (key, value) in env {
+            service.push(format!("Environment={}", systemd_env(key, value)));
+        }
+        if let Some(hook) = &self.pre_start {
+            service.push(format!("ExecStartPre={}", exec(hook)));
+        }
+        let start = if self.login_shell {
+            format!("{program} -lc {}", systemd_quote(&self.command))
+        } else {
+            exec(&self.command)
+        };
+        service.push(format!("ExecStart={start}"));
+        if let Some(cwd) = &self.cwd {
+            service.push(format!("WorkingDirectory={}", cwd.display()));
+        }
+        if let Some(umask) = &self.umask {
+            service.push(format!("UMask={umask}"));
+        }
+        let limits = [
+            ("LimitNOFILE", self.rlimit_nofile),
+            ("LimitAS", self.rlimit_as),
+            ("LimitCPU", self.rlimit_cpu),
+        ];
+        for (key, limit) in limits {
+            if let Some(limit) = limit {
+                service.push(format!("{key}={limit}"));
+            }
+        }
+        match self.stop_timeout {
+            // systemd reads a zero timeout as none at all
+            Some(0) => service.push("KillSignal=SIGKILL".into()),
+            Some(timeout) => service.push(format!("TimeoutStopSec={timeout}")),
+            None => {}
+        }
+        if self.unshare_pid {
+            service.push("PrivatePIDs=yes".into());
+        }
+        if self.unshare_mount {
+            service.push("PrivateMounts=yes".into());
+        }
+        if let Some(hook) = &self.post_stop {
+            service.push(format!("ExecStopPost={}", exec(hook)));
+        }
+        let restart = match self.restart {
+            RestartPolicy::Never => "no",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Always => "always",
+        };
+        service.push(format!("Restart={restart}"));
+        Ok(format!(
+            "[Unit]\nDescription=uniq-proc command {}\n\n[Service]\n{}\n\n[Install]\nWantedBy=default.target",
+            name.replace('%', "%%"),
+            service.join("\n")
+        ))
+    }
+}
+
+/// Moves the child about to exec into new namespaces, to be called from `pre_exec`
+/// and so making only async-signal-safe calls.
+///
+/// Only children of the caller enter a new PID namespace, so it forks the
+/// namespace's init, which goes on to exec the command. The caller stays behind
+/// as the process the daemon tracks and exits like the init. The init gets
+/// SIGKILL once the caller is gone, which takes the rest of the namespace down
+/// with it, so killing or signalling the tracked process ends the command.
+///
+/// # Safety
+///
+/// Must run in a forked child before exec.
+unsafe fn enter_namespaces(pid: bool, mount: bool) -> std::io::Result<()> {
+    let flags =
+        if pid { libc::CLONE_NEWPID } else { 0 } | if mount { libc::CLONE_NEWNS } else { 0 };
+    if libc::unshare(flags) != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // without this, mounts of the command would propagate back to the host
+    let root = c"/".as_ptr();
+    let private = libc::MS_REC | libc::MS_PRIVATE;
+    if mount
+        && libc::mount(
+            std::ptr::null(),
+            root,
+            std::ptr::null(),
+            private,
+            std::ptr::null(),
+        ) != 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    if !pid {
+        return Ok(());
+    }
+    match libc::fork() {
+        -1 => Err(std::io::Error::last_os_error()),
+        0 => {
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+            // the /proc of the host would show none of the namespace's processes
+            let proc = c"/proc".as_ptr();
+            let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+            if mount
+                && libc::mount(
+                    c"proc".as_ptr(),
+                    proc,
+                    c"proc".as_ptr(),
+                    flags,
+                    std::ptr::null(),
+                ) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        init => {
+            // the pipe reporting exec failures is among these, the spawn would
+            // otherwise wait for this process to exit
+            libc::syscall(libc::SYS_close_range, 3, u32::MAX, 0);
+            let mut status = 0;
+            while libc::waitpid(init, &mut status, 0) == -1
+                && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR)
+            {}
+            let code = if libc::WIFSIGNALED(status) {
+                128 + libc::WTERMSIG(status)
+            } else {
+                libc::WEXITSTATUS(status)
+            };
+            libc::_exit(code)
+        }
+    }
+}
+
+/// Variables the shell sets by itself, not worth capturing.
+const SHELL_MAINTAINED_VARS: [&str; 4] = ["_", "PWD", "OLDPWD", "SHLVL"];
+
+/// Quotes `arg` for sh where needed, in single quotes that keep any content literal.
+fn sh_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Quotes a single argument for a systemd `Exec*=` line, escaping specifiers and variables.
+fn systemd_quote(arg: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in arg.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '%' => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Quotes `KEY=VALUE` for an Environment= line, where unlike in ExecStart= a `$`
+/// is no variable.
+fn systemd_env(key: &str, value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in key.chars().chain(['=']).chain(value.chars()) {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '%' => quoted.push_str("%%"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Shells whose syntax `env` can print.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShellSyntax {
+    /// `export KEY='VALUE'`, for sh, bash, zsh and the like
+    #[default]
+    Sh,
+    /// `set -gx KEY 'VALUE'`
+    Fish,
+}
+
+impl ShellSyntax {
+    /// One line per variable, sorted by name.
+    fn exports(self, environment: &HashMap<String, String>) -> String {
+        let mut keys: Vec<&String> = environment.keys().collect();
+        keys.sort();
+        let lines: Vec<String> = keys
+            .into_iter()
+            .map(|key| {
+                let value = &environment[key];
+                match self {
+                    ShellSyntax::Sh => format!("export {key}={}", sh_quote(value)),
+                    ShellSyntax::Fish => format!(
+                        "set -gx {key} '{}'",
+                        value.replace('\\', "\\\\").replace('\'', "\\'")
+                    ),
+                }
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
+
+/// Older configs map names directly to the command string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CommandEntry {
+    Bare(String),
+    Def(Box<CommandDef>),
+}
+
+impl From<CommandEntry> for CommandDef {
+    fn from(value: CommandEntry) -> Self {
+        match value {
+            CommandEntry::Bare(command) => CommandDef {
+                command,
+                ..Default::default()
+            },
+            CommandEntry::Def(def) => *def,
+        }
+    }
+}
+
+/// Health check of a process, written `exec:<command>`, `tcp://<host>:<port>`
+/// or as the `http://` or `https://` URL to query.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", try_from = "ProbeEntry")]
+pub enum Probe {
+    /// Shell command that exits successfully
+    Exec(String),
+    /// Address that accepts TCP connections
+    Tcp(String),
+    /// URL that answers a GET request with a 2xx status
+    Http(String),
+}
+
+impl FromStr for Probe {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        if let Some(command) = spec.strip_prefix("exec:") {
+            Ok(Probe::Exec(command.to_string()))
+        } else if let Some(addr) = spec.strip_prefix("tcp://") {
+            Ok(Probe::Tcp(addr.to_string()))
+        } else if spec.starts_with("http://") || spec.starts_with("https://") {
+            Ok(Probe::Http(spec.to_string()))
+        } else {
+            Err(format!(
+                "{spec} is no probe, expected exec:<command>, tcp://<host>:<port> or an http(s) URL"
+            ))
+        }
+    }
+}
+
+/// Probes are objects like `{"tcp": "localhost:80"}` or the short string form.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProbeEntry {
+    Spec(String),
+    Exec { exec: String },
+    Tcp { tcp: String },
+    Http { http: String },
+}
+
+impl TryFrom<ProbeEntry> for Probe {
+    type Error = String;
+
+    fn try_from(value: ProbeEntry) -> Result<Self, Self::Error> {
+        match value {
+            ProbeEntry::Spec(spec) => spec.parse(),
+            ProbeEntry::Exec { exec } => Ok(Probe::Exec(exec)),
+            ProbeEntry::Tcp { tcp } => Ok(Probe::Tcp(tcp)),
+            ProbeEntry::Http { http } => Ok(Probe::Http(http)),
+        }
+    }
+}
+
+impl Probe {
+    /// Whether the probe passes, exec probes run in `program`.
+    fn check(&self, program: &str) -> bool {
+        match self {
+            Probe::Exec(command) => shell(program, command)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success()),
+            Probe::Tcp(addr) => addr.to_socket_addrs().is_ok_and(|mut addrs| {
+                addrs.any(|addr| TcpStream::connect_timeout(&addr, PROBE_INTERVAL).is_ok())
+            }),
+            Probe::Http(url) => ureq::get(url)
+                .timeout(HTTP_PROBE_TIMEOUT)
+                .call()
+                .is_ok_and(|response| (200..300).contains(&response.status())),
+        }
+    }
+}
+
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+const HTTP_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+impl DaemonState {
+    /// The config file: the one given to [`set_config_path`], else `config.json` in
+    /// `UNIQ_PROC_CONFIG_DIR` if set and in the XDG config directory otherwise.
+    pub fn get_config_path() -> PathBuf {
+        if let Some(path) = CONFIG_PATH.get() {
+            return path.clone();
+        }
+        if let Some(dir) = std::env::var_os("UNIQ_PROC_CONFIG_DIR").filter(|dir| !dir.is_empty()) {
+            let _ = fs::create_dir_all(&dir);
+            return Path::new(&dir).join("config.json");
+        }
+        let base_dirs = BaseDirectories::with_prefix("uniq-proc").unwrap();
+        base_dirs.place_config_file("config.json").unwrap()
+    }
+    /// The state file, in `UNIQ_PROC_STATE_DIR` if set and /tmp otherwise.
+    pub fn get_state_path() -> PathBuf {
+        match state_dir() {
+            Some(dir) => dir.join("uniq-proc.state"),
+            None => PathBuf::from("/tmp/uniq-proc.state"),
+        }
+    }
+    /// Writes the commands to the config file. When that fails they stay in the
+    /// state file, marked unsaved so a restart does not lose them.
+    pub fn write_commands_to_config_dir(&mut self) {
+        if self.ephemeral {
+            return;
+        }
+        let config_path = Self::get_config_path();
+        let mut config =
+            serde_json::json!({ "version": CONFIG_VERSION, "commands": &self.commands });
+        if !self.templates.is_empty() {
+            config["templates"] = serde_json::json!(&self.templates);
+        }
+        let config_content = serde_json::to_string_pretty(&config).expect("can create json");
+        if let Err(e) = write_atomic(&config_path, &config_content, self.durable) {
+            log::error!(
+                "could not write {}, changed commands are only kept in the state file: {e}",
+                config_path.display()
+            );
+            self.save_state();
+            return;
+        }
+        // our own write must not look like an external edit to the next reload
+        self.config_stamp = FileStamp::of(&config_path);
+        let persisted: HashSet<String> = self.commands.keys().cloned().collect();
+        if persisted != self.persisted {
+            self.persisted = persisted;
+            self.save_state();
+        }
+    }
+
+    /// Replaces the commands with the config file, unless it is unchanged since
+    /// it was last loaded or written. Gives the running commands to restart,
+    /// whose definition changed and which have `restart_on_change` set.
+    fn reload_config(&mut self) -> Result<Option<Vec<String>>, String> {
+        if self.ephemeral {
+            return Err("the daemon is ephemeral, it has no config to load".into());
+        }
+        let config_path = Self::get_config_path();
+        let stamp = FileStamp::of(&config_path);
+        if stamp.is_some() && stamp == self.config_stamp {
+            return Ok(None);
+        }
+        let (commands, _) = read_config(&config_path)?;
+        self.templates = read_templates(&config_path)?;
+        let mut changed: Vec<String> = commands
+            .iter()
+            .filter(|(name, command)| {
+                command.restart_on_change
+                    && self.procs.contains_key(*name)
+                    && self.commands.get(*name) != Some(command)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        changed.sort();
+        self.commands = commands;
+        self.config_stamp = stamp;
+        self.persisted = self.commands.keys().cloned().collect();
+        self.save_state();
+        Ok(Some(changed))
+    }
+
+    /// The error for starting `name` while it is disabled.
+    fn refuse_disabled(&self, name: &str) -> Option<Response> {
+        let disabled = self
+            .commands
+            .get(name)
+            .is_some_and(|command| command.disabled);
+        disabled.then(|| {
+            Response::failure(
+                ErrorCode::Disabled,
+                format!("{name} is disabled, enable it or execute it with --force"),
+            )
+        })
+    }
+
+    /// Adds the run of `pid` that ended with `status` to the history of `name`,
+    /// `tracked` is its process info unless uniq-proc stopped tracking it first.
+    fn record_run(
+        &mut self,
+        name: &str,
+        pid: u32,
+        tracked: Option<&ProcInfo>,
+        status: Option<ExitStatus>,
+    ) {
+        use std::os::unix::process::ExitStatusExt;
+        let signal = status.and_then(|s| s.signal());
+        let end = if self.hung.contains(name) {
+            state::RunEnd::Timeout
+        } else if self.signalled.contains(name) {
+            state::RunEnd::Signalled
+        } else if tracked.is_none() {
+            state::RunEnd::Killed
+        } else {
+            // a SIGKILL nobody asked for is only blamed on the OOM killer when the
+            // kernel counted a kill, it may as well come from a user or a supervisor
+            let oom_killed = || {
+                tracked
+                    .and_then(|info| info.oom_kills.as_ref())
+                    .is_some_and(OomKills::rose)
+            };
+            match signal {
+                None => state::RunEnd::Exited,
+                Some(libc::SIGKILL) if oom_killed() => state::RunEnd::Oom,
+                Some(_) => state::RunEnd::Signal,
+            }
+        };
+        // adopted processes exit without a status, which tells nothing about a failure
+        let failed = status.and_then(|s| s.code()).is_some_and(|code| code != 0);
+        match end {
+            state::RunEnd::Timeout => self.counters.timeouts += 1,
+            state::RunEnd::Exited if failed => self.counters.crashes += 1,
+            state::RunEnd::Oom | state::RunEnd::Signal => self.counters.crashes += 1,
+            _ => {}
+        }
+        let runs = self.history.entry(name.to_string()).or_default();
+        runs.push_back(state::RunRecord {
+            pid,
+            started: tracked.and_then(|info| info.start_time),
+            ended: unix_now(),
+            exit_code: status.and_then(|s| s.code()),
+            signal,
+            end,
+        });
+        if runs.len() > HISTORY_LEN {
+            runs.pop_front();
+        }
+    }
+
+    /// Persists a change of the state as the persist mode demands.
+    pub fn save_state(&self) {
+        if self.ephemeral {
+            return;
+        }
+        match self.persist_mode {
+            PersistMode::Sync => self.write_state(),
+            PersistMode::Async => match &self.dirty {
+                Some(dirty) if dirty.send(()).is_ok() => {}
+                // no background writer, like before the daemon runs
+                _ => self.write_state(),
+            },
+            PersistMode::OnExit => {}
+        }
+    }
+
+    /// Writes the state file right away, whatever the persist mode.
+    fn write_state(&self) {
+        if self.ephemeral {
+            return;
+        }
+        let state_path = Self::get_state_path();
+        if let Err(e) = write_atomic(&state_path, &self.state_content(), self.durable) {
+            log::error!("could not write the state file: {e}");
+        }
+    }
+
+    fn state_content(&self) -> String {
+        let file = self.to_file();
+        let content = match self.compact_state {
+            true => serde_json::to_string(&file),
+            false => serde_json::to_string_pretty(&file),
+        };
+        content.expect("can create json")
+    }
+
+    /// Maps `name` to the registered name equal to it ignoring case. Exact
+    /// matches win, unknown names are returned as is for the handler to report.
+    fn resolve_name(&self, name: &str) -> Result<String, Response> {
+        if self.commands.contains_key(name) {
+            return Ok(name.to_string());
+        }
+        let mut candidates: Vec<&String> = self
+            .commands
+            .keys()
+            .filter(|other| other.eq_ignore_ascii_case(name))
+            .collect();
+        candidates.sort();
+        match candidates.as_slice() {
+            [] => Ok(name.to_string()),
+            [canonical] => Ok(canonical.to_string()),
+            _ => Err(Response::failure(
+                ErrorCode::Ambiguous,
+                format!(
+                    "{name} is ambiguous, it matches {}",
+                    candidates
+                        .iter()
+                        .map(|c| c.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )),
+        }
+    }
+
+    /// Finds a running or starting command that conflicts with `name`, declared on either side.
+    fn running_conflict(&self, name: &str) -> Option<String> {
+        let declared = self
+            .commands
+            .get(name)
+            .map_or(&[][..], |c| &c.conflicts_with);
+        let conflicts = self.commands.iter().filter_map(|(other, command)| {
+            let conflicting =
+                declared.contains(other) || command.conflicts_with.iter().any(|c| c == name);
+            (conflicting && other != name).then_some(other)
+        });
+        for other in conflicts {
+            if let Some(info) = self.procs.get(other) {
+                return Some(format!("{other} (running as pid {})", info.pid));
+            }
+            if self.starting.contains(other) {
+                return Some(format!("{other} (starting)"));
+            }
+            if let Some(pid) = self.killing.get(other) {
+                return Some(format!("{other} (stopping as pid {pid})"));
+            }
+        }
+        None
+    }
+
+    /// Drops tracked processes that are gone or whose pid now belongs to another process.
+    /// Returns how many entries were dropped.
+    fn prune_stale(&mut self) -> usize {
+        let mut system = System::new();
+        system.refresh_processes();
+        let stale = stale_procs(&system, &self.procs, &self.commands);
+        self.drop_stale(&stale)
+    }
+
+    /// Drops the entries of `stale` whose command still tracks the same pid, so a
+    /// process started since the scan stays. Returns how many entries were dropped.
+    fn drop_stale(&mut self, stale: &[(String, u32)]) -> usize {
+        let mut dropped = 0;
+        for (name, pid) in stale {
+            if self.procs.get(name).is_some_and(|info| info.pid == *pid) {
+                self.procs.remove(name);
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            self.save_state();
+        }
+        dropped
+    }
+
+    /// The persisted part of the state.
+    fn to_file(&self) -> state::StateFile {
+        let commands = self.commands.iter().map(|(name, command)| {
+            let command = serde_json::to_value(command).expect("can convert to json");
+            (name.clone(), command)
+        });
+        let restarts = self.restarts.iter().map(|(name, restart)| {
+            let record = state::RestartRecord {
+                count: restart.count,
+                given_up: restart.given_up,
+            };
+            (name.clone(), record)
+        });
+        let mut unsaved: Vec<String> = self
+            .commands
+            .keys()
+            .filter(|name| !self.persisted.contains(*name))
+            .cloned()
+            .collect();
+        unsaved.sort();
+        state::StateFile {
+            version: state::STATE_VERSION,
+            commands: commands.collect(),
+            unsaved,
+            procs: self
+                .procs
+                .iter()
+                .map(|(name, info)| (name.clone(), info.into()))
+                .collect(),
+            restarts: restarts.collect(),
+            history: self
+                .history
+                .iter()
+                .map(|(name, runs)| (name.clone(), runs.iter().cloned().collect()))
+                .collect(),
+            counters: self.counters,
+        }
+    }
+
+    /// Reads a state file, leaving everything that is not persisted at its default.
+    /// The commands not marked unsaved count as persisted to the config.
+    fn from_file(file: state::StateFile) -> Result<Self, String> {
+        let mut commands = HashMap::new();
+        for (name, command) in file.commands {
+            let entry: CommandEntry =
+                serde_json::from_value(command).map_err(|e| format!("command {name}: {e}"))?;
+            commands.insert(name, entry.into());
+        }
+        let persisted = commands
+            .keys()
+            .filter(|name| !file.unsaved.contains(*name))
+            .cloned()
+            .collect();
+        let restarts = file.restarts.into_iter().map(|(name, record)| {
+            let restart = RestartState {
+                count: record.count,
+                given_up: record.given_up,
+                ..Default::default()
+            };
+            (name, restart)
+        });
+        Ok(Self {
+            commands,
+            procs: file
+                .procs
+                .into_iter()
+                .map(|(name, record)| (name, record.into()))
+                .collect(),
+            restarts: restarts.collect(),
+            persisted,
+            history: file
+                .history
+                .into_iter()
+                .map(|(name, runs)| (name, runs.into()))
+                .collect(),
+            counters: file.counters,
+            ..Self::default()
+        })
+    }
+
+    /// Reads and parses the state file at `path`.
+    fn load(path: &Path) -> Result<(Self, u32), String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let file = state::StateFile::parse(&content)?;
+        let version = file.version;
+        Ok((Self::from_file(file)?, version))
+    }
+
+    /// Loads the commands and, with `keep`, what the last daemon tracked.
+    ///
+    /// The config file is the source of truth for the commands. Whenever it exists
+    /// its commands replace those of the state file, which only stand in for a
+    /// missing config, with one exception: with -k, the commands the state file
+    /// marks unsaved are kept unless the config has the same name. These are the
+    /// ones whose config write failed, so an added command is never lost, while
+    /// one removed by editing the config stays removed.
+    pub fn new(keep: bool) -> Self {
+        Self::open(keep, false).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Loads like [`DaemonState::new`], failing on an invalid config or state file.
+    /// An `ephemeral` state never writes either, not even to upgrade an old version.
+    pub fn open(keep: bool, ephemeral: bool) -> Result<Self, String> {
+        let config_path = Self::get_config_path();
+        let last_state_path = Self::get_state_path();
+        let mut result = Self {
+            ephemeral,
+            ..Default::default()
+        };
+        let mut old_state = None;
+        let mut unsaved = HashMap::new();
+        if keep && last_state_path.exists() {
+            let (parsed, version) = Self::load(&last_state_path)
+                .map_err(|e| format!("invalid state file {}: {e}", last_state_path.display()))?;
+            if !config_path.exists() {
+                result.commands = parsed.commands;
+            } else {
+                unsaved = parsed.commands;
+                unsaved.retain(|name, _| !parsed.persisted.contains(name));
+            }
+            result.procs = parsed.procs;
+            result.history = parsed.history;
+            result.counters = parsed.counters;
+            old_state = Some(version).filter(|&v| v < state::STATE_VERSION);
+        } else if let Ok(content) = fs::read_to_string(&last_state_path) {
+            // the counters describe the setup rather than one daemon, so they carry
+            // over even without -k. A broken file only loses them.
+            if let Ok(file) = state::StateFile::parse(&content) {
+                result.counters = file.counters;
+            }
+        }
+        if config_path.exists() {
+            let invalid = |e| format!("invalid config {}: {e}", config_path.display());
+            let (commands, version) = read_config(&config_path).map_err(invalid)?;
+            result.commands = commands;
+            result.templates = read_templates(&config_path).map_err(invalid)?;
+            if version < CONFIG_VERSION && !ephemeral {
+                result.write_commands_to_config_dir();
+                log::info!("upgraded {} from version {version}", config_path.display());
+            }
+            result.config_stamp = FileStamp::of(&config_path);
+            result.persisted = result.commands.keys().cloned().collect();
+            for (name, command) in unsaved {
+                if let Entry::Vacant(entry) = result.commands.entry(name) {
+                    log::warn!(command = entry.key().as_str(); "kept {}, it is missing from the config", entry.key());
+                    entry.insert(command);
+                }
+            }
+        }
+        if let Some(version) = old_state.filter(|_| !ephemeral) {
+            // parsing already understands the old layouts, rewriting suffices
+            result.save_state();
+            log::info!("upgraded the state file from version {version}");
+        }
+        Ok(result)
+    }
+}
+
+/// Orders `names` and their transitive dependencies so that dependencies come first,
+/// as do the commands named by `after` that are among `names` too. Fails on unknown
+/// dependencies as not found and on cycles as conflicts, naming the cycle like
+/// `a -> b -> a`.
+fn dependency_order(
+    commands: &HashMap<String, CommandDef>,
+    names: &[String],
+) -> Result<Vec<String>, (ErrorCode, String)> {
+    fn visit(
+        commands: &HashMap<String, CommandDef>,
+        name: &str,
+        batch: &[String],
+        stack: &mut Vec<String>,
+        order: &mut Vec<String>,
+    ) -> Result<(), (ErrorCode, String)> {
+        if order.iter().any(|n| n == name) {
+            return Ok(());
+        }
+        if let Some(start) = stack.iter().position(|n| n == name) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(name.to_string());
+            let reason = format!("dependency cycle {}", cycle.join(" -> "));
+            return Err((ErrorCode::Conflict, reason));
+        }
+        let Some(command) = commands.get(name) else {
+            let reason = match stack.last() {
+                Some(dependent) => format!("{dependent} depends on unknown command {name}"),
+                None => format!("{name} is not registered yet"),
+            };
+            return Err((ErrorCode::NotFound, reason));
+        };
+        stack.push(name.to_string());
+        for dependency in &command.depends_on {
+            visit(commands, dependency, batch, stack, order)?;
+        }
+        for earlier in command
+            .after
+            .iter()
+            .filter(|earlier| batch.contains(earlier))
+        {
+            visit(commands, earlier, batch, stack, order)?;
+        }
+        stack.pop();
+        order.push(name.to_string());
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    let mut names = names.to_vec();
+    names.sort();
+    for name in &names {
+        visit(commands, name, &names, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+/// A freshly spawned process of a registered command.
+struct Started {
+    pid: u32,
+    /// Subscription to the exit of the process at the reaper
+    exit: Receiver<Option<ExitStatus>>,
+    command: CommandDef,
+}
+
+/// Owns the spawned processes and is the only place waiting on them, so no exit
+/// is waited for twice and no zombie is left behind. Whoever is interested in an
+/// exit subscribes and is sent the exit status once the process was reaped.
+#[derive(Default)]
+struct Reaper {
+    state: Mutex<ReaperState>,
+}
+
+#[derive(Default)]
+struct ReaperState {
+    children: HashMap<u32, Child>,
+    subscribers: HashMap<u32, Vec<Sender<Option<ExitStatus>>>>,
+}
+
+impl Reaper {
+    /// Takes over `child`, returning its pid and a subscription to its exit.
+    fn adopt(&self, child: Child) -> (u32, Receiver<Option<ExitStatus>>) {
+        let pid = child.id();
+        let mut state = self.state.lock().expect("working mutex");
+        state.children.insert(pid, child);
+        (pid, Self::add_subscriber(&mut state, pid))
+    }
+
+    /// Subscribes to the exit of `pid`. Processes that are not our children, like
+    /// those taken over from an earlier daemon, are polled and have no exit status.
+    fn subscribe(&self, pid: u32) -> Receiver<Option<ExitStatus>> {
+        Self::add_subscriber(&mut self.state.lock().expect("working mutex"), pid)
+    }
+
+    fn add_subscriber(state: &mut ReaperState, pid: u32) -> Receiver<Option<ExitStatus>> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        state.subscribers.entry(pid).or_default().push(tx);
+        rx
+    }
+
+    /// Reaps the exited children and checks on the other subscribed pids,
+    /// notifying the subscribers of every process that is gone.
+    fn reap(&self) {
+        let mut state = self.state.lock().expect("working mutex");
+        let mut exited = Vec::new();
+        state.children.retain(|&pid, child| match child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) => {
+                exited.push((pid, Some(status)));
+                false
+            }
+            Err(_) => {
+                exited.push((pid, None));
+                false
+            }
+        });
+        let mut system = System::new();
+        for &pid in state.subscribers.keys() {
+            if state.children.contains_key(&pid) || exited.iter().any(|(p, _)| *p == pid) {
+                continue;
+            }
+            let alive = system.refresh_process((pid as i32).into())
+                && system
+                    .process((pid as i32).into())
+                    .is_some_and(|p| p.status() != ProcessStatus::Zombie);
+            if !alive {
+                exited.push((pid, None));
+            }
+        }
+        for (pid, status) in exited {
+            for subscriber in state.subscribers.remove(&pid).unwrap_or_default() {
+                let _ = subscriber.send(status);
+            }
+        }
+    }
+
+    /// Blocks until the process of `exit` is gone and returns its exit status.
+    /// Waiters drive the reaping, so no thread has to run while nobody waits.
+    fn wait(&self, exit: &Receiver<Option<ExitStatus>>) -> Option<ExitStatus> {
+        loop {
+            match exit.recv_timeout(REAP_INTERVAL) {
+                Ok(status) => return status,
+                Err(RecvTimeoutError::Timeout) => self.reap(),
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Like `wait`, but gives up after `timeout`. Returns whether the process is gone.
+    fn wait_timeout(&self, exit: &Receiver<Option<ExitStatus>>, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match exit.recv_timeout(REAP_INTERVAL) {
+                Ok(_) | Err(RecvTimeoutError::Disconnected) => return true,
+                Err(RecvTimeoutError::Timeout) if Instant::now() >= deadline => return false,
+                Err(RecvTimeoutError::Timeout) => self.reap(),
+            }
+        }
+    }
+
+    /// Sends SIGKILL to our child `pid`, returns false if it is not one.
+    fn kill(&self, pid: u32) -> bool {
+        let mut state = self.state.lock().expect("working mutex");
+        state
+            .children
+            .get_mut(&pid)
+            .is_some_and(|child| child.kill().is_ok())
+    }
+}
+
+const REAP_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How a supervised process ended.
+struct Exited {
+    response: Response,
+    success: bool,
+    /// False if uniq-proc stopped tracking the process before it exited (kill, disown, ...)
+    unexpected: bool,
+}
+
+impl Daemon {
+    /// Lock serializing the operations that start or stop the process of `name`.
+    fn name_lock(&self, name: &str) -> Arc<Mutex<()>> {
+        let mut locks = self.name_locks.lock().expect("working mutex");
+        locks.entry(name.to_string()).or_default().clone()
+    }
+
+    pub fn execute(&self, name: String, force: bool) -> Response {
+        let order = {
+            let data = self.data.lock().expect("working mutex");
+            if let Some(disabled) = data.refuse_disabled(&name).filter(|_| !force) {
+                return disabled;
+            }
+            dependency_order(&data.commands, std::slice::from_ref(&name))
+        };
+        let order = match order {
+            Ok(order) => order,
+            Err((code, reason)) => {
+                return Response::failure(code, format!("{name} not started: {reason}"))
+            }
+        };
+        let dependencies = &order[..order.len() - 1];
+        let mut responses = self.start_in_order(dependencies);
+        if responses.last().is_some_and(Response::failed) {
+            return Response::Sequence(responses);
+        }
+        let executed = match self.start(&name) {
+            Ok(started) => self.supervise(name, started),
+            Err(response) => response,
+        };
+        if responses.is_empty() {
+            return executed;
+        }
+        responses.push(executed);
+        Response::Sequence(responses)
+    }
+
+    /// Executes `name` after its dependencies, passing every line of its output to
+    /// `output` until that returns false because the client is gone. The daemon
+    /// supervises the process like a started one, its output also goes where it
+    /// would without a client.
+    fn execute_attached(
+        &self,
+        name: String,
+        force: bool,
+        output: &mut dyn FnMut(&Response) -> bool,
+    ) -> Response {
+        let order = {
+            let data = self.data.lock().expect("working mutex");
+            if let Some(disabled) = data.refuse_disabled(&name).filter(|_| !force) {
+                return disabled;
+            }
+            dependency_order(&data.commands, std::slice::from_ref(&name))
+        };
+        let order = match order {
+            Ok(order) => order,
+            Err((code, reason)) => {
+                return Response::failure(code, format!("{name} not started: {reason}"))
+            }
+        };
+        let responses = self.start_in_order(&order[..order.len() - 1]);
+        if responses.last().is_some_and(Response::failed) {
+            return Response::Sequence(responses);
+        }
+        let (started, lines) = match self.start_with_output(&name, true) {
+            Ok(started) => started,
+            Err(response) => return response,
+        };
+        let exit = self.reaper.subscribe(started.pid);
+        self.detach(name.clone(), started);
+        let status = loop {
+            match lines.recv_timeout(PROBE_INTERVAL) {
+                Ok((stderr, bytes)) => {
+                    let (line, raw) = text_of(bytes);
+                    if !output(&Response::Output { stderr, line, raw }) {
+                        return Response::Ok(format!("{name} keeps running detached"));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    // descendants may keep the pipes open after the process exited
+                    if let Ok(status) = exit.try_recv() {
+                        for (stderr, bytes) in lines.try_iter() {
+                            let (line, raw) = text_of(bytes);
+                            output(&Response::Output { stderr, line, raw });
+                        }
+                        break status;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break self.reaper.wait(&exit),
+            }
+        };
+        Response::Exited {
+            name,
+            code: status.and_then(|status| status.code()),
+        }
+    }
+
+    /// Starts `names` with their dependencies and returns once all of them are ready,
+    /// leaving the processes to be supervised in the background.
+    pub fn start_detached(&self, names: &[String]) -> Response {
+        let order = {
+            let data = self.data.lock().expect("working mutex");
+            dependency_order(&data.commands, names)
+        };
+        match order {
+            Ok(order) => Response::Sequence(self.start_in_order(&order)),
+            Err((code, reason)) => Response::failure(code, format!("Nothing started: {reason}")),
+        }
+    }
+
+    /// Starts every command in the background, dependencies first. A command
+    /// is skipped when one of its dependencies could not be started.
+    pub fn start_all(&self) -> Response {
+        let names: Vec<String> = {
+            let data = self.data.lock().expect("working mutex");
+            data.commands.keys().cloned().collect()
+        };
+        self.start_many(&names)
+    }
+
+    /// Starts the commands whose labels match `selector` like [`Daemon::start_all`].
+    pub fn start_matching(&self, selector: &Selector) -> Response {
+        let names: Vec<String> = {
+            let data = self.data.lock().expect("working mutex");
+            let matching = data.commands.iter();
+            matching
+                .filter(|(_, command)| selector.matches(&command.labels))
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+        if names.is_empty() {
+            return Response::failure(
+                ErrorCode::NotFound,
+                format!("No command matches {selector}"),
+            );
+        }
+        self.start_many(&names)
+    }
+
+    /// Kills the processes of all commands in the reverse of the order they start in,
+    /// waiting for each to exit before the commands it depends on are killed. A
+    /// command stays running when one depending on it could not be stopped.
+    pub fn stop_all(&self) -> Response {
+        let (order, commands) = {
+            let data = self.data.lock().expect("working mutex");
+            let names: Vec<String> = data.commands.keys().cloned().collect();
+            (
+                dependency_order(&data.commands, &names),
+                data.commands.clone(),
+            )
+        };
+        let order = match order {
+            Ok(order) => order,
+            Err((code, reason)) => {
+                return Response::failure(code, format!("Nothing stopped: {reason}"))
+            }
+        };
+        let mut results: HashMap<String, Response> = HashMap::new();
+        for name in order.into_iter().rev() {
+            let failed_dependent = results
+                .iter()
+                .filter(|(_, result)| result.failed())
+                .map(|(dependent, _)| dependent)
+                .find(|dependent| commands[*dependent].depends_on.contains(&name));
+            let result = match failed_dependent {
+                Some(dependent) => Response::failure(
+                    ErrorCode::Conflict,
+                    format!("not stopped, dependent {dependent} is still running"),
+                ),
+                None => self.stop(&name),
+            };
+            results.insert(name, result);
+        }
+        Response::Bulk(results)
+    }
+
+    /// Kills the process of `name` and waits for it to exit.
+    fn stop(&self, name: &str) -> Response {
+        let pid = {
+            let data = self.data.lock().expect("working mutex");
+            data.procs.get(name).map(|p| p.pid)
+        };
+        let exit = pid.map(|pid| self.reaper.subscribe(pid));
+        let killed = self.kill(name.to_string());
+        if killed.error_code() == Some(ErrorCode::NotRunning) {
+            return Response::Ok("already stopped".into());
+        }
+        if let (Some(pid), Some(exit), Response::Ok(_)) = (pid, exit, &killed) {
+            if !self.reaper.wait_timeout(&exit, RESTART_TIMEOUT) {
+                return Response::failure(
+                    ErrorCode::Timeout,
+                    format!(
+                        "{name} (pid {pid}) did not exit within {}s",
+                        RESTART_TIMEOUT.as_secs()
+                    ),
+                );
+            }
+        }
+        killed
+    }
+
+    /// Starts the autostart commands that are not running yet, like the processes
+    /// a daemon with -k took over, logging how each of them went.
+    fn autostart(&self) {
+        let names: Vec<String> = {
+            let data = self.data.lock().expect("working mutex");
+            let autostart = data
+                .commands
+                .iter()
+                .filter(|(_, command)| command.autostart && !command.disabled);
+            autostart.map(|(name, _)| name.clone()).collect()
+        };
+        if names.is_empty() {
+            return;
+        }
+        let results = match self.start_many(&names) {
+            Response::Bulk(results) => results,
+            response => return log::error!("autostart failed: {response}"),
+        };
+        let mut results: Vec<(String, Response)> = results.into_iter().collect();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, result) in results {
+            if result.failed() {
+                log::warn!(command = name.as_str(); "autostart of {name} failed: {result}");
+            } else {
+                log::info!(command = name.as_str(); "autostart of {name}: {result}");
+            }
+        }
+    }
+
+    /// Starts `names` and their dependencies in the background, like [`Daemon::start_all`].
+    fn start_many(&self, names: &[String]) -> Response {
+        let (order, commands) = {
+            let data = self.data.lock().expect("working mutex");
+            (
+                dependency_order(&data.commands, names),
+                data.commands.clone(),
+            )
+        };
+        let order = match order {
+            Ok(order) => order,
+            Err((code, reason)) => {
+                return Response::failure(code, format!("Nothing started: {reason}"))
+            }
+        };
+        let mut results = HashMap::new();
+        for name in order {
+            let failed_dependency = commands[&name]
+                .depends_on
+                .iter()
+                .find(|d| results.get(*d).is_some_and(Response::failed));
+            let (running, disabled_dependency) = {
+                let data = self.data.lock().expect("working mutex");
+                let disabled_dependency = commands[&name].depends_on.iter().find(|d| {
+                    commands.get(*d).is_some_and(|d| d.disabled) && !data.procs.contains_key(*d)
+                });
+                (
+                    data.procs.get(&name).map(|info| info.pid),
+                    disabled_dependency,
+                )
+            };
+            let result = if let Some(dependency) = failed_dependency {
+                Response::failure(
+                    ErrorCode::SpawnFailed,
+                    format!("not started, dependency {dependency} failed"),
+                )
+            } else if let Some(dependency) = disabled_dependency {
+                Response::failure(
+                    ErrorCode::Disabled,
+                    format!("not started, dependency {dependency} is disabled"),
+                )
+            } else if commands[&name].disabled {
+                Response::Ok("not started, disabled".into())
+            } else if let Some(pid) = running {
+                Response::Ok(format!("already running (pid {pid})"))
+            } else {
+                match self.start(&name) {
+                    Ok(started) => {
+                        let result = Response::Ok(format!("Started {name} (pid {})", started.pid));
+                        self.detach(name.clone(), started);
+                        result
+                    }
+                    Err(response) => response,
+                }
+            };
+            results.insert(name, result);
+        }
+        Response::Bulk(results)
+    }
+
+    /// Starts the processes that are not running yet one after another, detaching them.
+    /// Stops at the first one failing to start, its error is the last response.
+    fn start_in_order(&self, order: &[String]) -> Vec<Response> {
+        let mut responses = Vec::new();
+        for name in order {
+            {
+                let data = self.data.lock().expect("working mutex");
+                if data.procs.contains_key(name) {
+                    continue;
+                }
+                if let Some(disabled) = data.refuse_disabled(name) {
+                    responses.push(disabled);
+                    break;
+                }
+            }
+            match self.start(name) {
+                Ok(started) => {
+                    responses.push(Response::Ok(format!(
+                        "Started {name} (pid {})",
+                        started.pid
+                    )));
+                    self.detach(name.clone(), started);
+                }
+                Err(response) => {
+                    responses.push(response);
+                    break;
+                }
+            }
+        }
+        responses
+    }
+
+    fn detach(&self, name: String, started: Started) {
+        let detach = self.detach.lock().expect("working mutex");
+        if let Some(detach) = detach.as_ref() {
+            let _ = detach.send((name, started));
+        }
+    }
+
+    /// Waits for the process of `name` to pass its readiness probe, picking up one
+    /// that is still being spawned. A process without a probe is ready once it runs.
+    fn wait_ready(&self, name: String, timeout: Option<u64>) -> Response {
+        let readiness = |outcome| Response::Readiness {
+            name: name.clone(),
+            outcome,
+        };
+        let Some(command) = self
+            .data
+            .lock()
+            .expect("working mutex")
+            .commands
+            .get(&name)
+            .cloned()
+        else {
+            return Response::failure(ErrorCode::NotFound, format!("{name} is not registered yet"));
+        };
+        let timeout = timeout.map_or(command.start_timeout(), Duration::from_secs);
+        let deadline = Instant::now() + timeout;
+        let pid = loop {
+            let data = self.data.lock().expect("working mutex");
+            if let Some(info) = data.procs.get(&name) {
+                break info.pid;
+            }
+            if !data.starting.contains(&name) {
+                return readiness(ReadyOutcome::NotRunning);
+            }
+            drop(data);
+            if Instant::now() >= deadline {
+                return readiness(ReadyOutcome::Timeout {
+                    secs: timeout.as_secs(),
+                });
+            }
+            std::thread::sleep(PROBE_INTERVAL);
+        };
+        let Some(probe) = &command.readiness else {
+            return readiness(ReadyOutcome::Ready);
+        };
+        let exit = self.reaper.subscribe(pid);
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match wait_until_ready(&self.reaper, &exit, probe, &self.shell, remaining) {
+            Ok(()) => readiness(ReadyOutcome::Ready),
+            Err(NotReady::Exited(status)) => readiness(ReadyOutcome::Exited {
+                code: status.and_then(|s| s.code()),
+            }),
+            Err(NotReady::Timeout(_)) => readiness(ReadyOutcome::Timeout {
+                secs: timeout.as_secs(),
+            }),
+        }
+    }
+
+    /// Passes on the lines of the processes of `names` until the client is gone or
+    /// the daemon stops. Commands that stop and start again are followed on.
+    fn follow(&self, names: Vec<String>, output: &mut dyn FnMut(&Response) -> bool) -> Response {
+        let names = {
+            let data = self.data.lock().expect("working mutex");
+            let mut resolved = HashSet::new();
+            for name in names {
+                let name = match self.ignore_case {
+                    true => match data.resolve_name(&name) {
+                        Ok(name) => name,
+                        Err(response) => return response,
+                    },
+                    false => name,
+                };
+                match data.commands.get(&name) {
+                    None => {
+                        return Response::failure(
+                            ErrorCode::NotFound,
+                            format!("{name} is not registered yet"),
+                        )
+                    }
+                    Some(command) if !command.capture_output => {
+                        return Response::failure(
+                            ErrorCode::Invalid,
+                            format!(
+                                "{name} does not capture its output, edit it with \
+                                 --capture-output and restart it to follow it"
+                            ),
+                        )
+                    }
+                    Some(_) => resolved.insert(name),
+                };
+            }
+            resolved
+        };
+        let (lines_tx, lines) = std::sync::mpsc::channel();
+        let follower = Follower {
+            names,
+            lines: lines_tx,
+        };
+        self.followers.lock().expect("working mutex").push(follower);
+        while !self.stopping.load(Ordering::SeqCst) {
+            if let Ok((name, stderr, bytes)) = lines.recv_timeout(PROBE_INTERVAL) {
+                let (line, raw) = text_of(bytes);
+                let frame = Response::Line {
+                    name,
+                    stderr,
+                    line,
+                    raw,
+                };
+                if !output(&frame) {
+                    return Response::Ok("stopped following".into());
+                }
+            }
+        }
+        Response::Ok("the daemon is stopping".into())
+    }
+
+    /// Spawns the process of `name` and waits for it to become ready.
+    ///
+    /// The name is reserved in the same locked section that checks it is not running,
+    /// so concurrent starts of the same name bail instead of spawning duplicates.
+    fn start(&self, name: &str) -> Result<Started, Response> {
+        self.start_with_output(name, false)
+            .map(|(started, _)| started)
+    }
+
+    /// Like [`Daemon::start`], but when `capture` is set also passes on the lines
+    /// of the process, marked whether they went to stderr.
+    fn start_with_output(
+        &self,
+        name: &str,
+        capture: bool,
+    ) -> Result<(Started, Receiver<OutputLine>), Response> {
+        let command = {
+            let mut data = self.data.lock().expect("working mutex");
+            if let Some(info) = data.procs.get(name) {
+                return Err(Response::failure(
+                    ErrorCode::AlreadyRunning,
+                    format!("{name} is already running (pid {})", info.pid),
+                ));
+            }
+            if data.starting.contains(name) {
+                return Err(Response::failure(
+                    ErrorCode::AlreadyRunning,
+                    format!("{name} is already starting"),
+                ));
+            }
+            if let Some(pid) = data.killing.get(name) {
+                return Err(Response::failure(
+                    ErrorCode::AlreadyRunning,
+                    format!("{name} is still stopping (pid {pid})"),
+                ));
+            }
+            let Some(command) = data.commands.get(name).cloned() else {
+                return Err(Response::failure(
+                    ErrorCode::NotFound,
+                    format!("{name} is not registered yet"),
+                ));
+            };
+            if let Some(conflict) = data.running_conflict(name) {
+                return Err(Response::failure(
+                    ErrorCode::Conflict,
+                    format!("{name} not started, it conflicts with {conflict}"),
+                ));
+            }
+            if let Some(max) = self.max_procs {
+                let full = |data: &DaemonState| {
+                    data.procs.len() + data.starting.len() + data.killing.len() >= max
+                };
+                if self.queue && (full(&data) || !data.queued.is_empty()) {
+                    if data.queued.iter().any(|queued| queued == name) {
+                        return Err(Response::failure(
+                            ErrorCode::AlreadyRunning,
+                            format!("{name} is already queued"),
+                        ));
+                    }
+                    data.queued.push_back(name.to_string());
+                    log::info!(command = name; "queued {name}");
+                    while full(&data) || data.queued.front().map(String::as_str) != Some(name) {
+                        if !data.queued.iter().any(|queued| queued == name) {
+                            return Err(Response::Ok(format!("{name} was removed from the queue")));
+                        }
+                        if self.stopping.load(Ordering::SeqCst) {
+                            data.queued.retain(|queued| queued != name);
+                            return Err(Response::failure(
+                                ErrorCode::Stopping,
+                                format!("{name} not started, the daemon is stopping"),
+                            ));
+                        }
+                        // the timeout also catches slots freed by kills and failed starts
+                        (data, _) = self
+                            .slot_freed
+                            .wait_timeout(data, PROBE_INTERVAL)
+                            .expect("working mutex");
+                    }
+                    data.queued.pop_front();
+                    if data.procs.contains_key(name)
+                        || data.starting.contains(name)
+                        || data.killing.contains_key(name)
+                    {
+                        return Err(Response::failure(
+                            ErrorCode::AlreadyRunning,
+                            format!("{name} was started meanwhile"),
+                        ));
+                    }
+                } else if full(&data) {
+                    return Err(Response::failure(
+                        ErrorCode::LimitReached,
+                        format!("{name} not started, process limit reached ({max} running)"),
+                    ));
+                }
+            }
+            data.starting.insert(name.to_string());
+            command
+        };
+        let prepared = command.environment().and_then(|environment| {
+            command.check_rlimits()?;
+            command.check_namespaces()?;
+            let command_line = command.command_line(&environment)?;
+            Ok((environment, command_line))
+        });
+        let (environment, command_line) = match prepared {
+            Ok(prepared) => prepared,
+            Err(reason) => {
+                let mut data = self.data.lock().expect("working mutex");
+                data.starting.remove(name);
+                return Err(Response::failure(
+                    ErrorCode::SpawnFailed,
+                    format!("{name} not started: {reason}"),
+                ));
+            }
+        };
+        if let Some(hook) = &command.pre_start {
+            if let Err(reason) = run_hook(&self.shell, hook) {
+                let mut data = self.data.lock().expect("working mutex");
+                data.starting.remove(name);
+                return Err(Response::failure(
+                    ErrorCode::SpawnFailed,
+                    format!("{name} not started, pre_start hook {reason}"),
+                ));
+            }
+        }
+        let mut cmd = if command.login_shell {
+            login_shell(&self.shell, &command_line)
+        } else {
+            shell(&self.shell, &command_line)
+        };
+        if command.clean_env {
+            cmd.env_clear();
+        }
+        if let Some(cwd) = &command.cwd {
+            cmd.current_dir(cwd);
+        }
+        command.prepare_child(&mut cmd);
+        if capture || command.capture_output {
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let mut child = match cmd.envs(&environment).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                let mut data = self.data.lock().expect("working mutex");
+                data.starting.remove(name);
+                let reason = spawn_error(&cmd, &e, command.has_rlimits());
+                log::error!(command = name; "could not spawn {name}: {reason}");
+                return Err(Response::failure(
+                    ErrorCode::SpawnFailed,
+                    format!("{name} not started: {reason}"),
+                ));
+            }
+        };
+        let (lines_tx, lines) = std::sync::mpsc::channel();
+        if let Some((stdout, stderr)) = child.stdout.take().zip(child.stderr.take()) {
+            let lines_tx = Some(lines_tx).filter(|_| capture);
+            self.data
+                .lock()
+                .expect("working mutex")
+                .truncated
+                .remove(name);
+            // both streams count against the one limit
+            let limit = OutputLimit {
+                max: command.max_log_bytes.unwrap_or(self.max_log_bytes),
+                passed: Arc::new(AtomicU64::new(0)),
+                data: self.data.clone(),
+            };
+            let tee = |stderr, lines| Tee {
+                name: name.to_string(),
+                stderr,
+                lines,
+                followers: self.followers.clone(),
+                limit: limit.clone(),
+            };
+            tee(false, lines_tx.clone()).spawn(stdout);
+            tee(true, lines_tx).spawn(stderr);
+        }
+        let (pid, exit) = self.reaper.adopt(child);
+        log::info!(command = name, pid; "started {name}");
+        {
+            let mut data = self.data.lock().expect("no poisioed lock");
+            data.starting.remove(name);
+            data.counters.executes += 1;
+            data.procs
+                .insert(name.to_string(), ProcInfo::new(pid, &cmd));
+            if let Some(state) = data.restarts.get_mut(name) {
+                state.given_up = false;
+            }
+            data.save_state();
+        }
+        self.sync_watches();
+        if let Some(probe) = &command.readiness {
+            let ready = wait_until_ready(
+                &self.reaper,
+                &exit,
+                probe,
+                &self.shell,
+                command.start_timeout(),
+            );
+            if let Err(reason) = ready {
+                self.reaper.kill(pid);
+                self.reaper.wait(&exit);
+                {
+                    let mut data = self.data.lock().expect("working mutex");
+                    if data.procs.get(name).is_some_and(|p| p.pid == pid) {
+                        data.procs.remove(name);
+                        data.save_state();
+                    }
+                }
+                let message = match reason {
+                    NotReady::Timeout(_) => format!("{name} start timed out, {reason}"),
+                    NotReady::Exited(_) => format!("{name} failed to start: {reason}"),
+                };
+                let failed = Response::failure(ErrorCode::SpawnFailed, message);
+                return Err(with_post_stop(&self.shell, name, &command, failed));
+            }
+        }
+        Ok((Started { pid, exit, command }, lines))
+    }
+
+    /// Waits for a started process to exit and stops tracking it.
+    fn wait_for(&self, name: &str, started: Started) -> Exited {
+        let Started { pid, exit, command } = started;
+        let status = self.reaper.wait(&exit);
+        match status.and_then(|s| s.code()) {
+            Some(code) => log::info!(command = name, pid, exit_code = code; "{name} exited"),
+            None => log::info!(command = name, pid; "{name} exited without an exit code"),
+        }
+        if let Some(url) = &command.notify_url {
+            notify_exit(url, name, pid, status.and_then(|s| s.code()));
+        }
+        let (response, unexpected) = {
+            let mut data = self.data.lock().expect("working mutex");
+            data.liveness_failures.remove(name);
+            let tracked = data.procs.get(name).filter(|p| p.pid == pid).cloned();
+            data.record_run(name, pid, tracked.as_ref(), status);
+            if tracked.is_some() {
+                data.procs.remove(name);
+                data.save_state();
+                self.slot_freed.notify_all();
+                (Response::Ok(format!("{name} executed successfully")), true)
+            } else {
+                data.save_state();
+                let response = Response::Ok(format!(
+                    "{name} executed successfully, but was restarted with very interesting timing"
+                ));
+                (response, false)
+            }
+        };
+        self.sync_watches();
+        Exited {
+            response: with_post_stop(&self.shell, name, &command, response),
+            success: status.is_some_and(|s| s.success()),
+            unexpected,
+        }
+    }
+
+    /// Waits for the process of `name` to exit, restarting it as its restart policy demands.
+    /// Restarts back off exponentially, the delay is reset after a stable run.
+    fn supervise(&self, name: String, mut started: Started) -> Response {
+        loop {
+            let started_at = Instant::now();
+            let policy = started.command.restart;
+            let limit = started
+                .command
+                .restart_limit(self.max_restarts, self.restart_window);
+            let exited = self.wait_for(&name, started);
+            let (hung, signalled) = {
+                let mut data = self.data.lock().expect("working mutex");
+                (data.hung.remove(&name), data.signalled.remove(&name))
+            };
+            if !exited.unexpected
+                || signalled
+                || !(hung || policy.restarts(exited.success))
+                || self.stopping.load(Ordering::SeqCst)
+            {
+                return exited.response;
+            }
+            {
+                let mut data = self.data.lock().expect("working mutex");
+                let state = data.restarts.entry(name.clone()).or_default();
+                if started_at.elapsed() >= BACKOFF_RESET_AFTER {
+                    state.count = 0;
+                }
+                if let Some((max, window)) = limit.filter(|&limit| state.exceeds(limit)) {
+                    data.save_state();
+                    let reason = format!("restarted {max} times within {}s", window.as_secs());
+                    log::error!(command = name.as_str(); "giving up on {name}, {reason}");
+                    let given_up = Response::failure(
+                        ErrorCode::LimitReached,
+                        format!("{name} {reason}, gave up restarting it until it is started again"),
+                    );
+                    return Response::Sequence(vec![exited.response, given_up]);
+                }
+                state.count += 1;
+                state.pending = Some(Instant::now() + backoff_delay(state.count));
+                data.counters.restarts += 1;
+                data.save_state();
+            }
+            loop {
+                std::thread::sleep(PROBE_INTERVAL);
+                let mut data = self.data.lock().expect("working mutex");
+                let state = data.restarts.entry(name.clone()).or_default();
+                let Some(at) = state.pending else {
+                    let cancelled = Response::Ok(format!("The restart of {name} was cancelled"));
+                    return Response::Sequence(vec![exited.response, cancelled]);
+                };
+                if self.stopping.load(Ordering::SeqCst) {
+                    state.pending = None;
+                    return exited.response;
+                }
+                if Instant::now() >= at {
+                    state.pending = None;
+                    break;
+                }
+            }
+            started = match self.start(&name) {
+                Ok(started) => started,
+                Err(response) => return Response::Sequence(vec![exited.response, response]),
+            };
+        }
+    }
+
+    /// Prepares the daemon to stop, which happens once the response is sent.
+    pub fn shutdown(&self, keep_running: bool) -> Response {
+        if keep_running {
+            self.keep_running.store(true, Ordering::SeqCst);
+            let running = self.data.lock().expect("working mutex").procs.len();
+            return Response::Ok(format!("Shutting down, {running} processes keep running"));
+        }
+        self.kill_all();
+        Response::Ok("Shutting down, all processes were killed".into())
+    }
+
+    /// Kills every process still running, like the dependencies an execute started.
+    pub fn kill_all(&self) {
+        let names: Vec<String> = {
+            let data = self.data.lock().expect("working mutex");
+            data.procs.keys().cloned().collect()
+        };
+        for name in names {
+            self.kill(name);
+        }
+    }
+
+    pub fn kill(&self, name: String) -> Response {
+        {
+            // queued starts may hold the name lock while waiting, so dequeue before taking it
+            let mut data = self.data.lock().expect("working mutex");
+            if let Some(position) = data.queued.iter().position(|queued| *queued == name) {
+                data.queued.remove(position);
+                return Response::Ok(format!("Removed {name} from the queue"));
+            }
+        }
+        let lock = self.name_lock(&name);
+        let _guard = lock.lock().expect("working mutex");
+        self.kill_process(&name)
+    }
+
+    /// Kills the process of `name`. The caller must hold the name lock.
+    fn kill_process(&self, name: &str) -> Response {
+        let mut data = self.data.lock().expect("working mutex");
+
+        let Some(info) = data.procs.get(name).cloned() else {
+            if let Some(state) = data.restarts.get_mut(name) {
+                if state.pending.take().is_some() {
+                    return Response::Ok(format!("Cancelled the pending restart of {name}"));
+                }
+            }
+            return Response::failure(
+                ErrorCode::NotRunning,
+                format!("{name} was not running via uniq-proc"),
+            );
+        };
+        let pid = info.pid;
+        let mut system = System::new();
+        system.refresh_process((pid as i32).into());
+        let Some(process) = system.process((pid as i32).into()) else {
+            // SAFETY: signal 0 only checks whether the pid exists, nothing is sent
+            let exists = unsafe { libc::kill(pid as i32, 0) } == 0
+                || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+            if exists {
+                return Response::failure(
+                    ErrorCode::Inaccessible,
+                    format!("could not inspect pid {pid} of {name}, it is still tracked"),
+                );
+            }
+            // it exited before the supervisor noticed, which is what a kill wants anyway
+            data.procs.remove(name);
+            data.save_state();
+            return Response::Ok(format!("{name} (pid {pid}) had already exited"));
+        };
+        // a zombie keeps its pid until the supervisor reaped it, so it is still ours
+        if process.status() == ProcessStatus::Zombie {
+            data.procs.remove(name);
+            data.save_state();
+            return Response::Ok(format!("{name} (pid {pid}) had already exited"));
+        }
+        if !info.matches(process, data.commands.get(name)) {
+            data.procs.remove(name);
+            data.save_state();
+            return Response::failure(
+                ErrorCode::NotRunning,
+                format!("{name} was not running anymore, pid {pid} now belongs to another process"),
+            );
+        }
+        let stop_timeout = data.commands.get(name).map_or(
+            Duration::from_secs(DEFAULT_STOP_TIMEOUT),
+            CommandDef::stop_timeout,
+        );
+        data.counters.kills += 1;
+        data.procs.remove(name);
+        // until it exited, starts of the command are refused and it counts toward the limit
+        data.killing.insert(name.to_string(), pid);
+        data.save_state();
+        let exit = self.reaper.subscribe(pid);
+        drop(data);
+        let exited = !stop_timeout.is_zero() && {
+            // SAFETY: kill only sends a signal, to a pid just checked to be the tracked process
+            unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+            self.reaper.wait_timeout(&exit, stop_timeout)
+        };
+        let killed = if exited {
+            log::info!(command = name, pid; "killed {name}");
+            Response::Ok(format!("Successfully killed {name}"))
+        } else {
+            // SAFETY: as above, the pid still belongs to the process that outlived the wait
+            unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+            // SIGKILL cannot be caught, this only waits for the kernel to tear it down
+            self.reaper.wait_timeout(&exit, RESTART_TIMEOUT);
+            if stop_timeout.is_zero() {
+                log::info!(command = name, pid; "killed {name}");
+                Response::Ok(format!("Successfully killed {name}"))
+            } else {
+                let secs = stop_timeout.as_secs();
+                log::warn!(command = name, pid; "killed {name} after its stop timeout of {secs}s");
+                Response::Ok(format!(
+                    "Killed {name} after the stop timeout, it did not exit within {secs}s of SIGTERM"
+                ))
+            }
+        };
+        let mut data = self.data.lock().expect("working mutex");
+        data.killing.remove(name);
+        self.slot_freed.notify_all();
+        killed
+    }
+
+    pub fn toggle(&self, name: String) -> Response {
+        let started = {
+            let lock = self.name_lock(&name);
+            let _guard = lock.lock().expect("working mutex");
+            let is_running = {
+                let l = self.data.lock().expect("no poisoned lock");
+                l.procs.contains_key(&name)
+            };
+            if is_running {
+                return self.kill_process(&name);
+            }
+            let disabled = self
+                .data
+                .lock()
+                .expect("working mutex")
+                .refuse_disabled(&name);
+            if let Some(disabled) = disabled {
+                return disabled;
+            }
+            self.start(&name)
+        };
+        match started {
+            Ok(started) => self.supervise(name, started),
+            Err(response) => response,
+        }
+    }
+
+    pub fn add(&self, name: String, command: CommandDef) -> Response {
+        if let Err(reason) = command.validate() {
+            return Response::failure(
+                ErrorCode::Invalid,
+                format!("Invalid command {name}: {reason}"),
+            );
+        }
+        let mut data = self.data.lock().expect("no poisioed lock");
+        if data.commands.get(&name) == Some(&command) {
+            return Response::Ok(format!("Unchanged: {}", command.command));
+        }
+        data.commands.insert(name.clone(), command);
+        data.save_state();
+        data.write_commands_to_config_dir();
+        Response::Ok(format!(
+            "Added: {}",
+            data.commands.get(&name).unwrap().command
+        ))
+    }
+
+    pub fn add_template(&self, name: String, template: CommandDef) -> Response {
+        if let Err(reason) = template.validate() {
+            return Response::failure(
+                ErrorCode::Invalid,
+                format!("Invalid template {name}: {reason}"),
+            );
+        }
+        let mut data = self.data.lock().expect("working mutex");
+        if data.templates.get(&name) == Some(&template) {
+            return Response::Ok(format!("Unchanged: {}", template.command));
+        }
+        let response = Response::Ok(format!("Added template: {}", template.command));
+        data.templates.insert(name, template);
+        data.write_commands_to_config_dir();
+        response
+    }
+
+    pub fn remove_template(&self, name: String) -> Response {
+        let mut data = self.data.lock().expect("working mutex");
+        if data.templates.remove(&name).is_none() {
+            return Response::failure(ErrorCode::NotFound, format!("There is no template {name}"));
+        }
+        data.write_commands_to_config_dir();
+        Response::Ok(format!("Removed template {name}"))
+    }
+
+    fn list_templates(&self) -> Response {
+        let data = self.data.lock().expect("working mutex");
+        Response::Commands {
+            commands: data.templates.clone(),
+            runtime_only: Vec::new(),
+        }
+    }
+
+    /// Adds `name` as a copy of `template` whose command has its placeholders filled.
+    pub fn add_from_template(
+        &self,
+        template: String,
+        name: String,
+        vars: HashMap<String, String>,
+    ) -> Response {
+        let mut command = {
+            let data = self.data.lock().expect("working mutex");
+            let Some(command) = data.templates.get(&template).cloned() else {
+                return Response::failure(
+                    ErrorCode::NotFound,
+                    format!("There is no template {template}"),
+                );
+            };
+            command
+        };
+        command.command = match fill_placeholders(&command.command, &vars) {
+            Ok(filled) => filled,
+            Err(missing) => {
+                return Response::failure(
+                    ErrorCode::Invalid,
+                    format!(
+                        "{name} not added, template {template} needs a value for {}",
+                        missing.join(", ")
+                    ),
+                )
+            }
+        };
+        self.add(name, command)
+    }
+
+    pub fn add_many(&self, commands: HashMap<String, CommandDef>) -> Response {
+        let mut data = self.data.lock().expect("working mutex");
+        let mut results = HashMap::new();
+        let mut added = 0;
+        for (name, command) in commands {
+            let result = if let Err(reason) = command.validate() {
+                Response::failure(ErrorCode::Invalid, format!("Invalid command: {reason}"))
+            } else if data.commands.get(&name) == Some(&command) {
+                Response::Ok(format!("Unchanged: {}", command.command))
+            } else {
+                let result = Response::Ok(format!("Added: {}", command.command));
+                data.commands.insert(name.clone(), command);
+                added += 1;
+                result
+            };
+            results.insert(name, result);
+        }
+        if added > 0 {
+            data.save_state();
+            data.write_commands_to_config_dir();
+        }
+        Response::Bulk(results)
+    }
+
+    pub fn edit(&self, name: String, command: Option<String>, fields: DefinitionArgs) -> Response {
+        let mut data = self.data.lock().expect("working mutex");
+        let Some(current) = data.commands.get(&name) else {
+            return Response::failure(ErrorCode::NotFound, format!("{name} is not registered yet"));
+        };
+        let mut edited = current.clone();
+        if let Some(command) = command {
+            edited.command = command;
+        }
+        fields.apply(&mut edited);
+        if let Err(reason) = edited.validate() {
+            return Response::failure(
+                ErrorCode::Invalid,
+                format!("Invalid command {name}: {reason}"),
+            );
+        }
+        if *current == edited {
+            return Response::Ok(format!("Unchanged: {}", edited.command));
+        }
+        data.commands.insert(name.clone(), edited);
+        data.save_state();
+        data.write_commands_to_config_dir();
+        if data.procs.contains_key(&name) {
+            Response::Ok(format!(
+                "Edited {name}, the change takes effect on its next execute or restart"
+            ))
+        } else {
+            Response::Ok(format!("Edited {name}"))
+        }
+    }
+
+    pub fn set_enabled(&self, name: String, enabled: bool) -> Response {
+        let mut data = self.data.lock().expect("working mutex");
+        let Some(command) = data.commands.get_mut(&name) else {
+            return Response::failure(ErrorCode::NotFound, format!("{name} is not registered yet"));
+        };
+        if command.disabled != enabled {
+            let state = if enabled { "enabled" } else { "disabled" };
+            return Response::Ok(format!("{name} is already {state}"));
+        }
+        command.disabled = !enabled;
+        data.save_state();
+        data.write_commands_to_config_dir();
+        match (enabled, data.procs.get(&name)) {
+            (true, _) => Response::Ok(format!("Enabled {name}")),
+            (false, Some(info)) => Response::Ok(format!(
+                "Disabled {name}, its running process (pid {}) keeps running",
+                info.pid
+            )),
+            (false, None) => Response::Ok(format!("Disabled {name}")),
+        }
+    }
+
+    pub fn remove(&self, name: String) -> Response {
+        let mut data = self.data.lock().expect("no poisioed lock");
+        if data.commands.remove(&name).is_none() {
+            return Response::failure(ErrorCode::NotFound, format!("{name} is not registered"));
+        }
+        data.save_state();
+        data.write_commands_to_config_dir();
+        drop(data);
+        self.sync_watches();
+        Response::Ok(format!("Removed {name}"))
+    }
+
+    pub fn copy(&self, from: String, to: String, force: bool) -> Response {
+        let mut data = self.data.lock().expect("working mutex");
+        let Some(command) = data.commands.get(&from).cloned() else {
+            return Response::failure(ErrorCode::NotFound, format!("{from} is not registered yet"));
+        };
+        if data.commands.get(&to) == Some(&command) {
+            return Response::Ok(format!("Unchanged: {}", command.command));
+        }
+        if !force && data.commands.contains_key(&to) {
+            return Response::failure(
+                ErrorCode::Conflict,
+                format!("{to} already exists, use --force to overwrite it"),
+            );
+        }
+        data.commands.insert(to.clone(), command);
+        data.save_state();
+        data.write_commands_to_config_dir();
+        Response::Ok(format!("Copied {from} to {to}"))
+    }
+
+    pub fn disown(&self, name: String) -> Response {
+        let mut data = self.data.lock().expect("working mutex");
+        let Some(info) = data.procs.remove(&name) else {
+            return Response::failure(
+                ErrorCode::NotRunning,
+                format!("{name} was not running via uniq-proc"),
+            );
+        };
+        data.save_state();
+        Response::Ok(format!(
+            "Disowned {name}, pid {} keeps running untracked",
+            info.pid
+        ))
+    }
+
+    /// Sends `signal` to the process of `name`, like a client forwarding a Ctrl-C.
+    pub fn signal(&self, name: String, signal: i32) -> Response {
+        let mut data = self.data.lock().expect("working mutex");
+        let Some(info) = data.procs.get(&name).cloned() else {
+            return Response::failure(
+                ErrorCode::NotRunning,
+                format!("{name} was not running via uniq-proc"),
+            );
+        };
+        let mut system = System::new();
+        system.refresh_process((info.pid as i32).into());
+        let still_ours = system
+            .process((info.pid as i32).into())
+            .is_some_and(|process| info.matches(process, data.commands.get(&name)));
+        if !still_ours {
+            return Response::failure(
+                ErrorCode::NotRunning,
+                format!("{name} (pid {}) is not running anymore", info.pid),
+            );
+        }
+        // SAFETY: kill only sends a signal, to a pid just checked to be the tracked process
+        if unsafe { libc::kill(info.pid as i32, signal) } != 0 {
+            let e = std::io::Error::last_os_error();
+            return Response::failure(
+                ErrorCode::Inaccessible,
+                format!("could not signal {name}: {e}"),
+            );
+        }
+        log::info!(command = name.as_str(), pid = info.pid, signal; "signalled {name}");
+        data.signalled.insert(name.clone());
+        Response::Ok(format!("Sent signal {signal} to {name} (pid {})", info.pid))
+    }
+
+    pub fn reset(&self, commands: bool, procs: bool, counters: bool) -> Response {
+        let mut data = self.data.lock().expect("working mutex");
+        let mut cleared = Vec::new();
+        if commands {
+            cleared.push(format!("{} commands", data.commands.len()));
+            data.commands.clear();
+            data.write_commands_to_config_dir();
+        }
+        if procs {
+            cleared.push(format!("{} tracked processes", data.procs.len()));
+            data.procs.clear();
+            data.restarts.clear();
+        }
+        if counters {
+            cleared.push("the counters".to_string());
+            data.counters = state::Counters::default();
+        }
+        data.save_state();
+        Response::Ok(format!("Cleared {}", cleared.join(" and ")))
+    }
+
+    /// Clears the automatic restarts of `name`. A pending restart happens right away,
+    /// and a command given up on starts again when its restart policy would have
+    /// restarted it, had it not reached the restart limit.
+    pub fn reset_backoff(&self, name: String) -> Response {
+        let (reset, start) = {
+            let mut data = self.data.lock().expect("working mutex");
+            let Some(command) = data.commands.get(&name) else {
+                return Response::failure(
+                    ErrorCode::NotFound,
+                    format!("{name} is not registered yet"),
+                );
+            };
+            let restarts = command.restart.restarts(false);
+            let running = data.procs.contains_key(&name) || data.starting.contains(&name);
+            let Some(state) = data.restarts.get_mut(&name) else {
+                return Response::Ok(format!("{name} has not been restarted automatically"));
+            };
+            state.count = 0;
+            state.recent.clear();
+            let given_up = std::mem::take(&mut state.given_up);
+            let reset = match &mut state.pending {
+                Some(at) => {
+                    *at = Instant::now();
+                    (format!("Reset the backoff of {name}, restarting it now"), false)
+                }
+                None if given_up && restarts && !running => {
+                    (format!("Reset the restart limit of {name}, starting it again"), true)
+                }
+                None if given_up => (
+                    format!("Reset the restart limit of {name}, it restarts automatically again once started"),
+                    false,
+                ),
+                None => (format!("Reset the restart count of {name}"), false),
+            };
+            data.save_state();
+            reset
+        };
+        if !start {
+            return Response::Ok(reset);
+        }
+        Response::Sequence(vec![Response::Ok(reset), self.start_detached(&[name])])
+    }
+
+    /// Resets the backoff of every command restarted automatically like
+    /// [`Daemon::reset_backoff`].
+    pub fn reset_backoff_all(&self) -> Response {
+        let names: Vec<String> = {
+            let data = self.data.lock().expect("working mutex");
+            let restarted = data.restarts.keys();
+            restarted
+                .filter(|name| data.commands.contains_key(*name))
+                .cloned()
+                .collect()
+        };
+        if names.is_empty() {
+            return Response::Ok("No command has been restarted automatically".into());
+        }
+        self.reset_backoff_many(names)
+    }
+
+    /// Resets the backoff of the commands whose labels match `selector` like
+    /// [`Daemon::reset_backoff`].
+    pub fn reset_backoff_matching(&self, selector: &Selector) -> Response {
+        let (matching, names): (usize, Vec<String>) = {
+            let data = self.data.lock().expect("working mutex");
+            let matching: Vec<&String> = data
+                .commands
+                .iter()
+                .filter(|(_, command)| selector.matches(&command.labels))
+                .map(|(name, _)| name)
+                .collect();
+            let restarted = matching
+                .iter()
+                .filter(|name| data.restarts.contains_key(**name))
+                .map(|name| name.to_string())
+                .collect();
+            (matching.len(), restarted)
+        };
+        if matching == 0 {
+            return Response::failure(
+                ErrorCode::NotFound,
+                format!("No command matches {selector}"),
+            );
+        }
+        if names.is_empty() {
+            return Response::Ok(format!(
+                "No command matching {selector} has been restarted automatically"
+            ));
+        }
+        self.reset_backoff_many(names)
+    }
+
+    fn reset_backoff_many(&self, names: Vec<String>) -> Response {
+        let results = names
+            .into_iter()
+            .map(|name| {
+                let result = self.reset_backoff(name.clone());
+                (name, result)
+            })
+            .collect();
+        Response::Bulk(results)
+    }
+
+    pub fn restart(&self, name: String) -> Response {
+        {
+            let mut data = self.data.lock().expect("working mutex");
+            if let Some(state) = data.restarts.get_mut(&name) {
+                state.recent.clear();
+            }
+        }
+        let (killed, started) = self.replace(&name);
+        let executed = match started {
+            Ok(started) => self.supervise(name, started),
+            Err(response) => response,
+        };
+        Response::Sequence(vec![killed, executed])
+    }
+
+    /// Kills the process of `name` and starts a new one once the old one exited.
+    fn replace(&self, name: &str) -> (Response, Result<Started, Response>) {
+        let lock = self.name_lock(name);
+        let _guard = lock.lock().expect("working mutex");
+        let pid = self
+            .data
+            .lock()
+            .expect("working mutex")
+            .procs
+            .get(name)
+            .map(|p| p.pid);
+        let exit = pid.map(|pid| self.reaper.subscribe(pid));
+        let killed = match self.kill_process(name) {
+            // nothing to replace, restarting then just starts it
+            killed if killed.error_code() == Some(ErrorCode::NotRunning) => {
+                Response::Ok(format!("{name} was not running"))
+            }
+            killed => killed,
+        };
+        if let (Some(pid), Some(exit), Response::Ok(_)) = (pid, exit, &killed) {
+            if !self.reaper.wait_timeout(&exit, RESTART_TIMEOUT) {
+                let timeout = Response::failure(
+                    ErrorCode::Timeout,
+                    format!(
+                        "{name} (pid {pid}) did not exit within {}s, not restarting",
+                        RESTART_TIMEOUT.as_secs()
+                    ),
+                );
+                return (killed, Err(timeout));
+            }
+            self.data.lock().expect("working mutex").counters.restarts += 1;
+        }
+        (killed, self.start(name))
+    }
+}
+
+/// Runs `command` in the shell `program`.
+fn shell(program: &str, command: &str) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+/// Like `shell`, but the shell reads the login profile first.
+fn login_shell(program: &str, command: &str) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.arg("-lc").arg(command);
+    cmd
+}
+
+/// Tells why `cmd` could not be spawned, naming what is missing or not allowed.
+fn spawn_error(cmd: &Command, e: &std::io::Error, rlimits: bool) -> String {
+    let program = cmd.get_program().to_string_lossy();
+    let cwd = cmd.get_current_dir();
+    match e.kind() {
+        ErrorKind::PermissionDenied | ErrorKind::InvalidInput if rlimits => {
+            format!("could not apply the resource limits, or run '{program}': {e}")
+        }
+        ErrorKind::NotFound => match cwd.filter(|cwd| !cwd.is_dir()) {
+            Some(cwd) => format!("working directory {} does not exist", cwd.display()),
+            None => format!("shell '{program}' not found"),
+        },
+        ErrorKind::PermissionDenied => match cwd {
+            Some(cwd) => format!(
+                "permission denied running '{program}' in {}, check both are accessible",
+                cwd.display()
+            ),
+            None => format!("permission denied running '{program}'"),
+        },
+        _ if e.raw_os_error() == Some(libc::EAGAIN) => {
+            "the system is out of processes (EAGAIN), try again later or raise the limit".into()
+        }
+        _ => format!("could not run '{program}': {e}"),
+    }
+}
+
+/// The text of a line of output for a frame, with the bytes as they were when
+/// they are not valid UTF-8, to be converted only where they are shown.
+fn text_of(bytes: Vec<u8>) -> (String, Option<Vec<u8>>) {
+    match String::from_utf8(bytes) {
+        Ok(text) => (text, None),
+        Err(e) => (
+            String::from_utf8_lossy(e.as_bytes()).into_owned(),
+            Some(e.into_bytes()),
+        ),
+    }
+}
+
+/// Where the lines of one output pipe of a process go.
+struct Tee {
+    name: String,
+    stderr: bool,
+    /// The attached client, if any
+    lines: Option<Sender<OutputLine>>,
+    followers: Followers,
+    limit: OutputLimit,
+}
+
+/// The bytes of output a process may pass through the daemon.
+#[derive(Clone)]
+struct OutputLimit {
+    /// 0 for no limit
+    max: u64,
+    passed: Arc<AtomicU64>,
+    data: Arc<Mutex<DaemonState>>,
+}
+
+impl Tee {
+    /// Copies the lines of `pipe` to the same stream of the daemon and sends them
+    /// on to the attached client and the followers, for as long as the pipe is open.
+    /// Past the output limit, a notice replaces the line that would exceed it, and
+    /// the rest is read but dropped so the process does not block on a full pipe.
+    fn spawn(self, pipe: impl Read + Send + 'static) {
+        std::thread::spawn(move || {
+            let mut pipe = std::io::BufReader::new(pipe);
+            let mut line = Vec::new();
+            let max = self.limit.max;
+            // longer lines come in pieces, so a line never ending takes no more memory
+            let mut read =
+                |line: &mut Vec<u8>| (&mut pipe).take(MAX_LINE_CHUNK).read_until(b'\n', line);
+            while read(&mut line).is_ok_and(|read| read > 0) {
+                if max > 0 {
+                    let len = line.len() as u64;
+                    let passed = self.limit.passed.fetch_add(len, Ordering::SeqCst);
+                    if passed + len > max {
+                        if passed <= max {
+                            self.truncate();
+                        }
+                        line.clear();
+                        continue;
+                    }
+                }
+                let _ = if self.stderr {
+                    std::io::stderr().write_all(&line)
+                } else {
+                    std::io::stdout().write_all(&line)
+                };
+                // the receiver is gone once the client detached
+                if let Some(lines) = &self.lines {
+                    let _ = lines.send((self.stderr, line.clone()));
+                }
+                self.follow(self.stderr, &line);
+                line.clear();
+            }
+        });
+    }
+
+    fn follow(&self, stderr: bool, line: &[u8]) {
+        let mut followers = self.followers.lock().expect("working mutex");
+        followers.retain(|follower| {
+            !follower.names.contains(&self.name)
+                || follower
+                    .lines
+                    .send((self.name.clone(), stderr, line.to_vec()))
+                    .is_ok()
+        });
+    }
+
+    /// Records that the output went over the limit and tells everyone reading it.
+    fn truncate(&self) {
+        let (name, max) = (&self.name, self.limit.max);
+        log::warn!(command = name.as_str(); "output of {name} truncated at {max} bytes");
+        let mut data = self.limit.data.lock().expect("working mutex");
+        data.truncated.insert(name.clone(), max);
+        drop(data);
+        let notice = format!("uniq-proc: output of {name} truncated at {max} bytes\n");
+        if let Some(lines) = &self.lines {
+            let _ = lines.send((true, notice.clone().into_bytes()));
+        }
+        self.follow(true, notice.as_bytes());
+    }
+}
+
+/// Bytes of a line passed on at once.
+const MAX_LINE_CHUNK: u64 = 64 * 1024;
+
+/// Finished runs kept per command.
+const HISTORY_LEN: usize = 20;
+
+/// One line about `run` for people, `now` in seconds since the epoch.
+fn describe_run(run: &state::RunRecord, now: u64) -> String {
+    let how = match (run.end, run.exit_code, run.signal) {
+        (state::RunEnd::Exited, Some(code), _) => format!("exited with code {code}"),
+        (state::RunEnd::Exited, None, _) => "exited".to_string(),
+        (state::RunEnd::Killed, ..) => "killed by uniq-proc".to_string(),
+        (state::RunEnd::Timeout, ..) => "killed after failing its liveness probes".to_string(),
+        (state::RunEnd::Signalled, _, Some(signal)) => {
+            format!("ended by signal {signal} on request")
+        }
+        (state::RunEnd::Signalled, ..) => "exited after a signal on request".to_string(),
+        (state::RunEnd::Oom, ..) => "killed by the OOM killer".to_string(),
+        (state::RunEnd::Signal, _, signal) => {
+            format!("killed by signal {}", signal.unwrap_or_default())
+        }
+    };
+    let ran = match run.started {
+        Some(started) => format!(", ran {}s", run.ended.saturating_sub(started)),
+        None => String::new(),
+    };
+    let ago = now.saturating_sub(run.ended);
+    format!("pid {} {how}{ran}, ended {ago}s ago", run.pid)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Runs a hook to completion in the shell `program`, failing on a nonzero exit.
+fn run_hook(program: &str, hook: &str) -> Result<(), String> {
+    match shell(program, hook).stdin(Stdio::null()).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("failed with {status}")),
+        Err(e) => Err(format!("could not be run: {e}")),
+    }
+}
+
+/// Runs the post_stop hook of `command`, if any, in the shell `program`, reporting
+/// a failure after `response`.
+fn with_post_stop(program: &str, name: &str, command: &CommandDef, response: Response) -> Response {
+    let Some(hook) = &command.post_stop else {
+        return response;
+    };
+    match run_hook(program, hook) {
+        Ok(()) => response,
+        Err(reason) => Response::Sequence(vec![
+            response,
+            Response::failure(
+                ErrorCode::HookFailed,
+                format!("{name} post_stop hook {reason}"),
+            ),
+        ]),
+    }
+}
+
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Posts an exit notification to `url` in the background, only logging failures.
+fn notify_exit(url: &str, name: &str, pid: u32, exit_code: Option<i32>) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let body = serde_json::json!({
+        "name": name,
+        "pid": pid,
+        "exit_code": exit_code,
+        "timestamp": timestamp,
+    });
+    let url = url.to_string();
+    std::thread::spawn(move || {
+        let result = ureq::post(&url).timeout(NOTIFY_TIMEOUT).send_json(&body);
+        if let Err(e) = result {
+            log::warn!(
+                "failed to notify {url} about the exit of {}: {e}",
+                body["name"]
+            );
+        }
+    });
+}
+
+/// Polls `probe` until it passes, giving up when the process of `exit` exits or `timeout` elapses.
+fn wait_until_ready(
+    reaper: &Reaper,
+    exit: &Receiver<Option<ExitStatus>>,
+    probe: &Probe,
+    program: &str,
+    timeout: Duration,
+) -> Result<(), NotReady> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        reaper.reap();
+        if let Ok(status) = exit.try_recv() {
+            return Err(NotReady::Exited(status));
+        }
+        if probe.check(program) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(NotReady::Timeout(timeout));
+        }
+        std::thread::sleep(PROBE_INTERVAL);
+    }
+}
+
+/// Why a process did not become ready.
+enum NotReady {
+    Exited(Option<ExitStatus>),
+    Timeout(Duration),
+}
+
+impl Display for NotReady {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotReady::Exited(Some(status)) => {
+                write!(f, "exited with {status} before becoming ready")
+            }
+            NotReady::Exited(None) => write!(f, "exited before becoming ready"),
+            NotReady::Timeout(timeout) => write!(
+                f,
+                "readiness probe did not pass within {}s",
+                timeout.as_secs()
+            ),
+        }
+    }
+}
+
+const RESTART_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The names and pids of the `procs` that are gone from `system` or whose pid now
+/// belongs to another process.
+fn stale_procs(
+    system: &System,
+    procs: &HashMap<String, ProcInfo>,
+    commands: &HashMap<String, CommandDef>,
+) -> Vec<(String, u32)> {
+    procs
+        .iter()
+        .filter(|(name, info)| {
+            !system
+                .process((info.pid as i32).into())
+                .is_some_and(|p| info.matches(p, commands.get(*name)))
+        })
+        .map(|(name, info)| (name.clone(), info.pid))
+        .collect()
+}
+
+/// Maps every pid to the pids of its direct children.
+fn child_pids(system: &System) -> HashMap<u32, Vec<u32>> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (pid, process) in system.processes() {
+        if let Some(parent) = process.parent() {
+            children
+                .entry(i32::from(parent) as u32)
+                .or_default()
+                .push(i32::from(*pid) as u32);
+        }
+    }
+    children
+}
+
+/// Counts the processes below `pid`, walking all the way down the tree.
+fn count_descendants(children: &HashMap<u32, Vec<u32>>, pid: u32) -> usize {
+    let mut count = 0;
+    let mut pending = vec![pid];
+    while let Some(pid) = pending.pop() {
+        let below = children.get(&pid).map(Vec::as_slice).unwrap_or_default();
+        count += below.len();
+        pending.extend(below);
+    }
+    count
+}
+
+/// Reads the commands of a file, either a json object like the config or lines of
+/// `name=command` with `#` comments. Returns the commands and the errors of malformed lines.
+pub fn read_command_file(
+    path: &Path,
+) -> Result<(HashMap<String, CommandDef>, Vec<String>), String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("could not read {}: {e}", path.display()))?;
+    if content.trim_start().starts_with('{') {
+        let (commands, _) = parse_commands(&content)
+            .map_err(|e| format!("invalid json in {}: {e}", path.display()))?;
+        return Ok((commands, Vec::new()));
+    }
+    let mut commands = HashMap::new();
+    let mut errors = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((name, command)) if !name.trim().is_empty() && !command.trim().is_empty() => {
+                let command = CommandDef {
+                    command: command.trim().to_string(),
+                    ..Default::default()
+                };
+                commands.insert(name.trim().to_string(), command);
+            }
+            _ => errors.push(format!("line {}: expected name=command", index + 1)),
+        }
+    }
+    Ok((commands, errors))
+}
+
+/// Reads the commands of a config file along with its format version.
+fn read_config(path: &Path) -> Result<(HashMap<String, CommandDef>, u32), String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("could not read {}: {e}", path.display()))?;
+    parse_commands(&content).map_err(|e| format!("invalid config {}: {e}", path.display()))
+}
+
+/// Reads the templates of the config file, which only versioned configs can have.
+fn read_templates(path: &Path) -> Result<HashMap<String, CommandDef>, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("could not read {}: {e}", path.display()))?;
+    let invalid = |e: serde_json::Error| format!("invalid config {}: {e}", path.display());
+    let mut value: serde_json::Value = from_str(&content).map_err(invalid)?;
+    if !value.get("version").is_some_and(|v| v.is_u64()) {
+        return Ok(HashMap::new());
+    }
+    let Some(templates) = value.get_mut("templates").map(serde_json::Value::take) else {
+        return Ok(HashMap::new());
+    };
+    let entries: HashMap<String, CommandEntry> =
+        serde_json::from_value(templates).map_err(invalid)?;
+    Ok(entries.into_iter().map(|(k, v)| (k, v.into())).collect())
+}
+
+/// Replaces the `{name}` placeholders of `template` with `vars`, failing with the
+/// names that have no value. `${VAR}` is left to the shell and braces around
+/// anything but a name, like `{a,b}`, are kept as they are.
+fn fill_placeholders(
+    template: &str,
+    vars: &HashMap<String, String>,
+) -> Result<String, Vec<String>> {
+    let mut filled = String::new();
+    let mut missing = Vec::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let (before, after) = rest.split_at(open);
+        filled.push_str(before);
+        let name = after[1..].split('}').next().filter(|name| {
+            after[1..].contains('}')
+                && !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        });
+        match name {
+            Some(name) if !before.ends_with('$') => {
+                match vars.get(name) {
+                    Some(value) => filled.push_str(value),
+                    None if !missing.iter().any(|m| m == name) => missing.push(name.to_string()),
+                    None => {}
+                }
+                rest = &after[name.len() + 2..];
+            }
+            _ => {
+                filled.push('{');
+                rest = &after[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    if missing.is_empty() {
+        Ok(filled)
+    } else {
+        Err(missing)
+    }
+}
+
+/// Parses a versioned `{"version": .., "commands": {..}}` document, or the bare map
+/// of names to commands of version 0, where a command may also be just a string.
+fn parse_commands(content: &str) -> Result<(HashMap<String, CommandDef>, u32), String> {
+    #[derive(Deserialize)]
+    struct Versioned {
+        version: u32,
+        commands: HashMap<String, CommandEntry>,
+    }
+    let value: serde_json::Value = from_str(content).map_err(|e| e.to_string())?;
+    // no command definition is a number, so a numeric version marks the versioned layout
+    let (entries, version) = if value.get("version").is_some_and(|v| v.is_u64()) {
+        let versioned: Versioned = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        (versioned.commands, versioned.version)
+    } else {
+        let entries: HashMap<String, CommandEntry> =
+            serde_json::from_value(value).map_err(|e| e.to_string())?;
+        (entries, 0)
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "version {version} is newer than this uniq-proc understands"
+        ));
+    }
+    let commands = entries.into_iter().map(|(k, v)| (k, v.into())).collect();
+    Ok((commands, version))
+}
+
+/// Parses `KEY=VALUE` lines, skipping blank lines and `#` comments. Values may be
+/// quoted, double quoted ones understand `\n`, `\"` and `\\`.
+fn read_env_file(path: &Path) -> Result<HashMap<String, String>, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("could not read {}: {e}", path.display()))?;
+    let mut vars = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| format!("{}:{}: {reason}", path.display(), number + 1);
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(invalid("expected KEY=VALUE"));
+        };
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('"') {
+            let mut unescaped = String::new();
+            let mut chars = quoted.chars();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => unescaped.push('\n'),
+                        Some(c) => unescaped.push(c),
+                        None => return Err(invalid("unterminated quote")),
+                    },
+                    Some(c) => unescaped.push(c),
+                    None => return Err(invalid("unterminated quote")),
+                }
+            }
+            unescaped
+        } else if let Some(quoted) = value.strip_prefix('\'') {
+            match quoted.split_once('\'') {
+                Some((value, _)) => value.to_string(),
+                None => return Err(invalid("unterminated quote")),
+            }
+        } else {
+            // unquoted values end at an inline comment
+            value
+                .split(" #")
+                .next()
+                .unwrap_or_default()
+                .trim_end()
+                .to_string()
+        };
+        vars.insert(key.trim().to_string(), value);
+    }
+    Ok(vars)
+}
+
+/// Validates the config file, returning the problems found.
+pub fn check_config() -> Vec<String> {
+    let config_path = DaemonState::get_config_path();
+    if !config_path.exists() {
+        return Vec::new();
+    }
+    let commands = match read_config(&config_path) {
+        Ok((commands, _)) => commands,
+        Err(e) => return vec![e],
+    };
+    let mut names: Vec<String> = commands.keys().cloned().collect();
+    names.sort();
+    let mut problems = Vec::new();
+    for name in &names {
+        if let Err(reason) = commands[name].validate() {
+            problems.push(format!("{name}: {reason}"));
+        }
+    }
+    if let Err((_, reason)) = dependency_order(&commands, &names) {
+        problems.push(reason);
+    }
+    problems
+}
+
+/// One result of [`doctor`].
+pub struct Finding {
+    pub ok: bool,
+    pub finding: String,
+    /// How to fix a failed check, may be empty
+    pub suggestion: String,
+}
+
+/// Checks the health of the daemon, its socket, the state and the config.
+/// Only changes anything when `fix` is set.
+pub fn doctor(fix: bool) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut report = |ok: bool, finding: String, suggestion: &str| {
+        findings.push(Finding {
+            ok,
+            finding,
+            suggestion: suggestion.to_string(),
+        });
+    };
+    let socket_path = socket_path();
+    let running = match send_message(Message::Alive, Codec::Json, Some(ALIVE_TIMEOUT)) {
+        Ok(_) => {
+            report(true, "the daemon responds".into(), "");
+            true
+        }
+        Err(SendError::NotRunning(_)) if socket_path.exists() => {
+            let fixed = fix && fs::remove_file(&socket_path).is_ok();
+            match fixed {
+                true => report(true, "removed the stale socket".into(), ""),
+                false => report(
+                    false,
+                    format!(
+                        "{} exists, but no daemon listens on it",
+                        socket_path.display()
+                    ),
+                    "remove it, or run doctor --fix",
+                ),
+            }
+            false
+        }
+        Err(SendError::NotRunning(_)) => {
+            report(true, "no daemon is running".into(), "");
+            false
+        }
+        Err(e) => {
+            report(false, e.to_string(), "kill the daemon and start it again");
+            true
+        }
+    };
+
+    let config_path = DaemonState::get_config_path();
+    let problems = check_config();
+    report(
+        problems.is_empty(),
+        format!(
+            "{} {}",
+            config_path.display(),
+            match problems.len() {
+                0 => "is valid".to_string(),
+                _ => format!("has problems: {}", problems.join("; ")),
+            }
+        ),
+        "fix the config by hand, uniq-proc check shows the details",
+    );
+
+    // SAFETY: getuid has no preconditions
+    let uid = unsafe { libc::getuid() };
+    for path in [&config_path, &DaemonState::get_state_path(), &socket_path] {
+        use std::os::unix::fs::MetadataExt;
+        if let Ok(metadata) = fs::metadata(path) {
+            report(
+                metadata.uid() == uid,
+                format!("{} is owned by uid {}", path.display(), metadata.uid()),
+                "it belongs to another user, remove it or fix its ownership",
+            );
+        }
+    }
+    if let Some(dir) = config_path.parent() {
+        let writable = fs::metadata(dir).is_ok_and(|m| !m.permissions().readonly());
+        report(
+            writable,
+            format!("{} is writable", dir.display()),
+            "make it writable, or the daemon cannot persist commands",
+        );
+    }
+
+    let state_path = DaemonState::get_state_path();
+    if !state_path.exists() {
+        report(true, "there is no state file".into(), "");
+        return findings;
+    }
+    let mut state = match DaemonState::load(&state_path) {
+        Ok((state, _)) => state,
+        Err(e) => {
+            let corrupt = PathBuf::from(format!("{}.corrupt", state_path.display()));
+            if fix && !running && fs::rename(&state_path, &corrupt).is_ok() {
+                report(
+                    true,
+                    format!("moved the corrupt state file to {}", corrupt.display()),
+                    "",
+                );
+            } else {
+                report(
+                    false,
+                    format!("{} is corrupt: {e}", state_path.display()),
+                    "move it away while no daemon runs, or run doctor --fix",
+                );
+            }
+            return findings;
+        }
+    };
+    report(true, format!("{} is valid", state_path.display()), "");
+
+    let tracked = state.procs.len();
+    // only without a daemon the state file is ours to rewrite
+    state.ephemeral = !fix || running;
+    let stale = state.prune_stale();
+    if stale == 0 {
+        report(
+            true,
+            format!("the {tracked} tracked processes are alive"),
+            "",
+        );
+    } else if fix && running {
+        let status = Message::Status {
+            page: Page::default(),
+            tree: false,
+            verbose: false,
+        };
+        let _ = send_message(status, Codec::Json, Some(CLIENT_TIMEOUT));
+        report(
+            true,
+            format!("the daemon pruned {stale} dead processes"),
+            "",
+        );
+    } else if fix {
+        report(
+            true,
+            format!("pruned {stale} dead processes from the state"),
+            "",
+        );
+    } else {
+        report(
+            false,
+            format!("{stale} of {tracked} tracked processes are not running anymore"),
+            "uniq-proc status prunes them, or run doctor --fix",
+        );
+    }
+
+    // an unreadable config was already reported above
+    if let Ok((config, _)) = read_config(&config_path) {
+        let mut missing: Vec<&String> = state
+            .commands
+            .keys()
+            .filter(|name| !config.contains_key(*name))
+            .collect();
+        missing.sort();
+        report(
+            missing.is_empty(),
+            match missing.is_empty() {
+                true => "the state agrees with the config".into(),
+                false => format!(
+                    "only the state knows {}",
+                    missing
+                        .iter()
+                        .map(|n| n.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            },
+            "add them again to keep them, a daemon started with or without -k drops them",
+        );
+    }
+    findings
+}
+
+/// Wire encoding between client and daemon.
+///
+/// The client picks the codec, the daemon recognizes it from the first byte of
+/// the request and answers in kind, so JSON clients keep working unchanged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Codec {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl Codec {
+    /// Every JSON encoded message is an object or a string, MessagePack never
+    /// starts with `{` or `"`.
+    fn detect(bytes: &[u8]) -> Codec {
+        match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{' | b'"') | None => Codec::Json,
+            Some(_) => Codec::Msgpack,
+        }
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).expect("can convert to json"),
+            Codec::Msgpack => rmp_serde::to_vec_named(value).expect("can convert to msgpack"),
+        }
+    }
+
+    fn decode<T: serde::de::DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Codec::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Codec::Msgpack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Waits for the lock daemons hold from checking for a running daemon until they
+/// listen, next to the socket. The lock goes with the returned file.
+pub fn startup_lock() -> Result<fs::File, String> {
+    use std::os::fd::AsRawFd;
+    let mut path = socket_path().into_os_string();
+    path.push(".lock");
+    let path = PathBuf::from(path);
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|e| format!("could not open {}: {e}", path.display()))?;
+    // SAFETY: flock only takes the descriptor of the file that outlives the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(format!("could not lock {}: {e}", path.display()));
+    }
+    Ok(file)
+}
+
+/// The listening socket passed by systemd, if the daemon was socket activated.
+fn activated_listener() -> Option<std::os::unix::net::UnixListener> {
+    use std::os::fd::FromRawFd;
+    let fd = sd_notify::listen_fds().ok()?.next()?;
+    // SAFETY: systemd hands over the listening socket as this fd, nothing else owns it
+    Some(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
+}
+
+static CONFIG_PATH: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+/// Makes this process read and write the config file at `path`, bypassing the
+/// environment and XDG. Only the first call counts.
+pub fn set_config_path(path: &Path) {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let _ = CONFIG_PATH.set(path);
+}
+
+static SOCKET_OVERRIDE: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+/// Makes this process listen or connect at `path`, bypassing the environment.
+/// Only the first call counts.
+pub fn set_socket_path(path: &Path) {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let _ = SOCKET_OVERRIDE.set(path);
+}
+
+/// Where the daemon listens by default, unless systemd hands it a socket.
+pub const SOCKET_PATH: &str = "/tmp/uniq-proc.sock";
+
+/// Where the daemon listens and clients connect: the one given to [`set_socket_path`],
+/// else `UNIQ_PROC_SOCKET` if set, next to the state file when `UNIQ_PROC_STATE_DIR`
+/// is set, and [`SOCKET_PATH`] otherwise. A client spawning the daemon passes the
+/// path on, so both agree.
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = SOCKET_OVERRIDE.get() {
+        return path.clone();
+    }
+    if let Some(path) = std::env::var_os("UNIQ_PROC_SOCKET").filter(|path| !path.is_empty()) {
+        return path.into();
+    }
+    match state_dir() {
+        Some(dir) => dir.join("uniq-proc.sock"),
+        None => PathBuf::from(SOCKET_PATH),
+    }
+}
+
+/// The directory `UNIQ_PROC_STATE_DIR` names, created if missing.
+fn state_dir() -> Option<PathBuf> {
+    let dir = PathBuf::from(std::env::var_os("UNIQ_PROC_STATE_DIR").filter(|dir| !dir.is_empty())?);
+    let _ = fs::create_dir_all(&dir);
+    Some(dir)
+}
+
+/// How long the client waits for quick operations by default.
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a liveness check waits for the daemon by default. Every client checks
+/// first, so a wedged daemon must not hold it up for long.
+pub const ALIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Why no response was received from the daemon.
+pub enum SendError {
+    /// Nothing listens on the socket
+    NotRunning(std::io::Error),
+    /// The daemon took longer than the timeout to respond
+    Unresponsive(Duration),
+    /// The exchange broke down otherwise
+    Failed(String),
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::NotRunning(e) => write!(f, "the daemon is not running: {e}"),
+            SendError::Unresponsive(timeout) => {
+                let within = match timeout.subsec_millis() {
+                    0 => format!("{}s", timeout.as_secs()),
+                    _ => format!("{}ms", timeout.as_millis()),
+                };
+                write!(
+                    f,
+                    "the daemon is unresponsive, it did not respond within {within} and may need a restart"
+                )
+            }
+            SendError::Failed(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+/// Sends `msg` to the daemon and passes the [`Response::Output`] and
+/// [`Response::Line`] frames of the response to `output` as they arrive, until
+/// the final response.
+pub fn send_streaming(
+    msg: Message,
+    codec: Codec,
+    mut output: impl FnMut(&Response),
+) -> Result<Response, SendError> {
+    let mut stream =
+        std::os::unix::net::UnixStream::connect(socket_path()).map_err(SendError::NotRunning)?;
+    stream
+        .write_all(&codec.encode(&msg))
+        .and_then(|_| stream.shutdown(std::net::Shutdown::Write))
+        .map_err(|e| SendError::Failed(format!("could not send the message: {e}")))?;
+    let mut reader = std::io::BufReader::new(stream);
+    let frames: Box<dyn Iterator<Item = Result<Response, String>>> = match codec {
+        Codec::Json => Box::new(
+            serde_json::Deserializer::from_reader(reader)
+                .into_iter()
+                .map(|frame| frame.map_err(|e| e.to_string())),
+        ),
+        Codec::Msgpack => Box::new(std::iter::from_fn(move || {
+            Some(rmp_serde::from_read(&mut reader).map_err(|e| e.to_string()))
+        })),
+    };
+    for frame in frames {
+        match frame {
+            Ok(frame @ (Response::Output { .. } | Response::Line { .. })) => output(&frame),
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                return Err(SendError::Failed(format!(
+                    "Could not parse the response: {e}"
+                )))
+            }
+        }
+    }
+    Err(SendError::Failed(
+        "the daemon closed the connection before the process exited".into(),
+    ))
+}
+
+/// Sends `msg` to the daemon and waits at most `timeout` for the response.
+pub fn send_message(
+    msg: Message,
+    codec: Codec,
+    timeout: Option<Duration>,
+) -> Result<Response, SendError> {
+    let mut stream =
+        std::os::unix::net::UnixStream::connect(socket_path()).map_err(SendError::NotRunning)?;
+    stream
+        .set_read_timeout(timeout)
+        .map_err(|e| SendError::Failed(format!("could not set the timeout: {e}")))?;
+    stream
+        .write_all(&codec.encode(&msg))
+        .and_then(|_| stream.shutdown(std::net::Shutdown::Write))
+        .map_err(|e| SendError::Failed(format!("could not send the message: {e}")))?;
+
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response) {
+        Ok(_) => codec
+            .decode(&response)
+            .map_err(|e| SendError::Failed(format!("Could not parse the response: {e}"))),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Err(SendError::Unresponsive(timeout.unwrap_or_default()))
+        }
+        Err(e) => Err(SendError::Failed(format!(
+            "An error has occured while getting the response: {e}"
+        ))),
+    }
+}
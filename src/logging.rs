@@ -0,0 +1,76 @@
+//! Daemon logging through the `log` facade, to stderr, a file or the systemd journal.
+
+use log::kv::{Error, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    File,
+    /// The systemd journal, with the command name and pid as fields
+    Journal,
+}
+
+/// Installs the logger for `target`, `file` is only used by [`LogTarget::File`].
+pub fn init(target: LogTarget, file: &Path) -> Result<(), String> {
+    let installed = match target {
+        LogTarget::Stderr => log::set_boxed_logger(Box::new(TextLog::new(std::io::stderr()))),
+        LogTarget::File => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .map_err(|e| format!("could not open {}: {e}", file.display()))?;
+            log::set_boxed_logger(Box::new(TextLog::new(file)))
+        }
+        LogTarget::Journal => systemd_journal_logger::JournalLog::new()
+            .map_err(|e| format!("could not connect to the journal: {e}"))?
+            .install(),
+    };
+    installed.map_err(|e| e.to_string())?;
+    log::set_max_level(LevelFilter::Info);
+    Ok(())
+}
+
+/// Writes one line per record, the key-values appended as `key=value`.
+struct TextLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl TextLog {
+    fn new(out: impl Write + Send + 'static) -> Self {
+        TextLog {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+}
+
+impl Log for TextLog {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let mut line = format!("{} {}", record.level(), record.args());
+        let _ = record.key_values().visit(&mut AppendPairs(&mut line));
+        let mut out = self.out.lock().expect("working mutex");
+        let _ = writeln!(out, "{line}");
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().expect("working mutex").flush();
+    }
+}
+
+struct AppendPairs<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for AppendPairs<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        self.0.push_str(&format!(" {key}={value}"));
+        Ok(())
+    }
+}
@@ -1,21 +1,52 @@
 use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
-use serde_json::from_str;
 use signal_hook::consts::{SIGINT, SIGTERM};
 use signal_hook::iterator::Signals;
-use std::io::Read;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::{collections::HashMap, fs, process::Command};
-use std::{io::Write, path::PathBuf};
-use sysinfo::{ProcessExt, System, SystemExt};
-use xdg::BaseDirectories;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use uniq_proc::{
+    check_config, doctor, read_command_file, send_message, send_streaming, set_config_path,
+    set_socket_path, socket_path, startup_lock, Codec, CommandDef, Daemon, DaemonState,
+    DefinitionArgs, ErrorCode, Message, Page, PersistMode, Response, Selector, SendError,
+    ShellSyntax, ALIVE_TIMEOUT, CLIENT_TIMEOUT, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_LOG_BYTES,
+    DEFAULT_SHELL, PROBE_INTERVAL,
+};
+
+mod logging;
+mod render;
+
+/// Default log file of the daemon, where spawned daemons log to.
+const LOG_FILE: &str = "/tmp/uniq-proc.log";
+
+/// The stable exit codes of failures, see [`uniq_proc::ErrorCode`].
+const EXIT_CODES: &str = "\
+Exit codes:
+  1   failed otherwise
+  10  not-found: no such command, template or process
+  11  already-running: the command already runs, starts or is queued
+  12  not-running: the command has no process to act on
+  13  conflict: a conflicting command runs or the name is taken
+  14  spawn-failed: the process could not be started
+  15  unauthorized: missing or wrong credentials
+  16  limit-reached: the process, connection or restart limit was reached
+  17  invalid: the request, the definition or a value given for it is invalid
+  18  disabled: the command or a dependency of it is disabled
+  19  ambiguous: the name matches several commands ignoring case
+  20  inaccessible: the process cannot be inspected or signalled
+  21  timeout: the process did not exit in time
+  22  stopping: the daemon is stopping
+  23  hook-failed: the post_stop hook failed";
 
 #[derive(Parser)]
 #[command(
     name = "uniq-proc",
     version = "1.0",
-    about = "Manages unique processes"
+    about = "Manages unique processes",
+    after_help = EXIT_CODES
 )]
 struct Cli {
     #[command(subcommand)]
@@ -26,46 +57,299 @@ struct Cli {
         help = "Continue from last daemon state"
     )]
     keep: bool,
+    /// Fsync state and config writes before reporting success
+    #[arg(long, default_value_t = false)]
+    durable: bool,
+    /// Match command names case-insensitively
+    #[arg(long, default_value_t = false)]
+    ignore_case: bool,
+    /// Config file to use instead of `$UNIQ_PROC_CONFIG_DIR/config.json` or the XDG one
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Socket the daemon listens on, instead of `$UNIQ_PROC_SOCKET` or /tmp/uniq-proc.sock
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+    /// Run `execute` in this process instead of the daemon, without touching its state
+    #[arg(long, default_value_t = false)]
+    no_daemon: bool,
+    /// Encoding used to talk to the daemon
+    #[arg(long, value_enum, default_value_t = Codec::Json)]
+    codec: Codec,
+    /// Seconds to wait for the daemon to respond. Defaults to 10, except for
+    /// operations waiting on processes, which wait as long as they run
+    #[arg(long)]
+    timeout: Option<u64>,
+    /// Milliseconds to wait for the daemon to answer the liveness check every client
+    /// starts with, telling a stuck daemon from one that is not running
+    #[arg(long, value_name = "MS", default_value_t = ALIVE_TIMEOUT.as_millis() as u64)]
+    alive_timeout: u64,
+    /// How responses are printed
+    #[arg(long, value_enum, default_value_t = render::OutputFormat::Human)]
+    output: render::OutputFormat,
+    /// Print nothing on success except listings, failures still go to stderr
+    #[arg(short, long, default_value_t = false)]
+    quiet: bool,
+    /// Stream the output of `execute --until-exit` and `logs --follow` as one json
+    /// object per line, each with a `type` field, instead of passing it through as is
+    #[arg(long, default_value_t = false)]
+    json_lines: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Adds or overwrites a command
-    Add { name: String, command: String },
+    Add {
+        name: String,
+        command: String,
+        #[command(flatten)]
+        fields: DefinitionArgs,
+    },
+    /// Adds all commands of a file, either `name=command` lines or a json object
+    AddFile { path: PathBuf },
+    /// Changes only the given fields of a command
+    Edit {
+        name: String,
+        /// The new command
+        #[arg(long)]
+        command: Option<String>,
+        #[command(flatten)]
+        fields: DefinitionArgs,
+    },
     /// Removes a command
     Remove { name: String },
+    /// Adds or overwrites a template, a command with `{name}` placeholders to fill in
+    AddTemplate {
+        name: String,
+        command: String,
+        #[command(flatten)]
+        fields: DefinitionArgs,
+    },
+    /// Removes a template, leaving the commands made from it
+    RemoveTemplate { name: String },
+    /// Lists all templates
+    Templates,
+    /// Adds a command made from a template, filling its placeholders
+    AddFromTemplate {
+        template: String,
+        name: String,
+        /// Values of the placeholders
+        #[arg(value_name = "KEY=VALUE")]
+        vars: Vec<String>,
+    },
+    /// Duplicates a command definition under a new name
+    Copy {
+        from: String,
+        to: String,
+        /// Overwrite `to` if it already exists
+        #[arg(long)]
+        force: bool,
+    },
     /// Lists all commands
-    List,
+    List {
+        #[command(flatten)]
+        page: Page,
+    },
+    /// Shows which commands are running
+    Status {
+        #[command(flatten)]
+        page: Page,
+        /// Also count the descendant processes of every running command
+        #[arg(long)]
+        tree: bool,
+        /// Also show the command line every running process was spawned with
+        #[arg(long)]
+        verbose: bool,
+    },
+    /// Finds commands whose name or command line contains a term
+    Search { term: String },
+    /// Prints the full internal daemon state
+    Dump,
+    /// Shows the settings the running daemon was started with
+    Info,
+    /// Reports whether all commands are doing fine, exiting with 1 when degraded
+    Health,
+    /// Shows how the latest runs of a command ended, oldest first
+    History { name: String },
+    /// Waits for the running process of a command to pass its readiness probe. Exits
+    /// with 0 once ready, 1 when not running, 2 on the timeout and 3 when it exited first
+    WaitReady {
+        name: String,
+        /// Seconds to wait at most, by default the start timeout of the command
+        #[arg(long, value_name = "SECS")]
+        within: Option<u64>,
+    },
     /// Executes a command
-    Execute { name: String },
+    Execute {
+        name: String,
+        /// Stream the output and exit with the code of the process, which keeps
+        /// running under the daemon if this client is interrupted
+        #[arg(long)]
+        until_exit: bool,
+        /// Execute the command even though it is disabled
+        #[arg(long)]
+        force: bool,
+    },
+    /// Shows the output of commands, each line behind the name of its command
+    Logs {
+        #[arg(required = true)]
+        names: Vec<String>,
+        /// Follow the output as the processes write it, including those started later
+        #[arg(long, short)]
+        follow: bool,
+    },
+    /// Starts a command and its dependencies in the background, once they are ready
+    Start { name: String },
+    /// Starts all commands in the background, dependencies first
+    StartAll {
+        /// Only the commands whose labels match, like `env=prod,tier!=cache`
+        #[arg(long, short = 'l', value_parser = Selector::from_str)]
+        selector: Option<Selector>,
+    },
+    /// Kills all processes, those depending on others first
+    StopAll,
+    /// Validates the config file without talking to the daemon
+    Check,
+    /// Measures how long the daemon takes to answer, without starting one
+    Ping {
+        /// Number of requests to send
+        #[arg(long, short = 'c', default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+        /// Milliseconds between two requests
+        #[arg(long, short = 'i', default_value_t = 1000)]
+        interval: u64,
+    },
+    /// Diagnoses the daemon, socket, state and config, suggesting fixes
+    Doctor {
+        /// Apply the suggested fixes that are safe to automate
+        #[arg(long)]
+        fix: bool,
+    },
     /// Kills a process
     Kill { name: String },
     /// Kills and re-execute a process
     Restart { name: String },
     /// Kills or executes a process, depending on if a process for that name already exists
     Toggle { name: String },
+    /// Stops tracking a process without killing it
+    Disown { name: String },
+    /// Keeps a command from being started until it is enabled again, without removing it
+    Disable { name: String },
+    /// Lets a disabled command be started again
+    Enable { name: String },
+    /// Clears the restart backoff of a crash-looping process, restarting it immediately,
+    /// and starts it again if restarts gave up on it
+    ResetBackoff { name: String },
+    /// Clears the restart backoff of all commands restarted automatically
+    ResetBackoffAll {
+        /// Only the commands whose labels match, like `env=prod,tier!=cache`
+        #[arg(long, short = 'l', value_parser = Selector::from_str)]
+        selector: Option<Selector>,
+    },
+    /// Prints a systemd service unit running a command
+    SystemdUnit { name: String },
+    /// Registers a command running like the process with this pid already does
+    Capture {
+        pid: u32,
+        name: String,
+        /// Also track the running process as the process of the command
+        #[arg(long)]
+        adopt: bool,
+    },
+    /// Prints the environment of a command as exports for `eval`
+    Env {
+        name: String,
+        /// Syntax of the shell evaluating the exports
+        #[arg(long, value_enum, default_value_t = ShellSyntax::Sh)]
+        format: ShellSyntax,
+    },
+    /// Clears command definitions, process tracking and/or the counters, without killing anything
+    #[command(group(clap::ArgGroup::new("what").required(true).multiple(true)))]
+    Reset {
+        /// Clear all command definitions
+        #[arg(long, group = "what")]
+        commands: bool,
+        /// Stop tracking all running processes
+        #[arg(long, group = "what")]
+        procs: bool,
+        /// Clear both commands and process tracking
+        #[arg(long, group = "what")]
+        all: bool,
+        /// Zero the lifetime counters shown by `info`
+        #[arg(long, group = "what")]
+        counters: bool,
+    },
+    /// Stops the daemon, by default killing the processes it manages
+    Shutdown {
+        /// Leave the processes running, a daemon started with -k picks them up again
+        #[arg(long)]
+        keep_running: bool,
+    },
+    /// Replaces the daemon with a new one, keeping the managed processes running
+    DaemonRestart,
     /// Start a deamon
-    Daemon,
-}
-
-#[derive(Clone, Serialize, Deserialize)]
-enum Message {
-    /// Adds or overwrites a command
-    Add { name: String, command: String },
-    /// Removes a command
-    Remove { name: String },
-    /// Lists all commands
-    List,
-    /// Check if daemon is running
-    Alive,
-    /// Executes a command
-    Execute { name: String },
-    /// Kills a process
-    Kill { name: String },
-    /// Kills and re-execute a process
-    Restart { name: String },
-    /// Kills or executes a process, depending on if a process for that name already exists
-    Toggle { name: String },
+    Daemon {
+        /// Reload the config file whenever it is edited
+        #[arg(long)]
+        watch_config: bool,
+        /// Refuse to start processes while this many are running
+        #[arg(long)]
+        max_procs: Option<usize>,
+        /// Queue starts beyond the limit and run them in order as processes exit
+        #[arg(long, requires = "max_procs")]
+        queue: bool,
+        /// Automatic restarts within the restart window after which a command is
+        /// given up, unless it sets its own limit
+        #[arg(long)]
+        max_restarts: Option<u32>,
+        /// Seconds counted back for `--max-restarts`
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        restart_window: Option<u64>,
+        /// Milliseconds identical listings and status requests share one response, 0 never
+        #[arg(long, default_value_t = 25)]
+        coalesce_ms: u64,
+        /// Connections served at once, further clients are told the daemon is busy.
+        /// Each waiting on a process counts for as long as it waits
+        #[arg(
+            long,
+            default_value_t = DEFAULT_MAX_CONNECTIONS,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        max_connections: usize,
+        /// Bytes of captured output passed on per process before the rest is dropped,
+        /// unless a command sets its own limit, 0 for no limit
+        #[arg(long, default_value_t = DEFAULT_MAX_LOG_BYTES)]
+        max_log_bytes: u64,
+        /// Shell the commands, hooks and exec probes run in
+        #[arg(long, default_value = DEFAULT_SHELL)]
+        shell: String,
+        /// When changes are written to the state file
+        #[arg(long, value_enum, default_value_t = PersistMode::Sync)]
+        persist_mode: PersistMode,
+        /// Write the state file as compact json, faster than pretty json for large states
+        #[arg(long)]
+        compact_state: bool,
+        /// Shut down a running daemon, leaving its processes running, and take them over
+        #[arg(long)]
+        replace: bool,
+        /// Keep everything in memory, neither reading nor writing the config and state
+        /// files. All commands and tracked processes are lost when the daemon stops
+        #[arg(long, conflicts_with_all = ["replace", "watch_config"])]
+        ephemeral: bool,
+        /// Where the daemon logs to
+        #[arg(long, value_enum, default_value_t = logging::LogTarget::Stderr)]
+        log_target: logging::LogTarget,
+        /// Log file used with `--log-target file`
+        #[arg(long, default_value = LOG_FILE)]
+        log_file: PathBuf,
+        /// Also serve the API over HTTP on this address
+        #[cfg(feature = "http")]
+        #[arg(long, requires = "http_token")]
+        http: Option<String>,
+        /// Bearer token HTTP clients have to send
+        #[cfg(feature = "http")]
+        #[arg(long)]
+        http_token: Option<String>,
+    },
 }
 
 impl TryFrom<Commands> for Message {
@@ -74,281 +358,724 @@ impl TryFrom<Commands> for Message {
     fn try_from(value: Commands) -> Result<Self, Self::Error> {
         match value {
             Commands::Daemon { .. } => Err("Daemon is not a message".into()),
-            Commands::Add { name, command } => Ok(Message::Add { name, command }),
+            Commands::Check => Err("Check is not a message".into()),
+            Commands::Ping { .. } => Err("Ping is not a message".into()),
+            Commands::Doctor { .. } => Err("Doctor is not a message".into()),
+            Commands::DaemonRestart => Err("DaemonRestart is not a message".into()),
+            Commands::Shutdown { keep_running } => Ok(Message::Shutdown { keep_running }),
+            Commands::AddFile { path } => {
+                let (commands, errors) = read_command_file(&path)?;
+                for error in &errors {
+                    eprintln!("{}: {error}", path.display());
+                }
+                Ok(Message::AddMany { commands })
+            }
+            Commands::Add {
+                name,
+                command,
+                mut fields,
+            } => {
+                fields.resolve_paths();
+                let mut command = CommandDef {
+                    command,
+                    ..Default::default()
+                };
+                fields.apply(&mut command);
+                Ok(Message::Add { name, command })
+            }
+            Commands::Edit {
+                name,
+                command,
+                mut fields,
+            } => {
+                fields.resolve_paths();
+                Ok(Message::Edit {
+                    name,
+                    command,
+                    fields,
+                })
+            }
             Commands::Remove { name } => Ok(Message::Remove { name }),
-            Commands::Execute { name } => Ok(Message::Execute { name }),
+            Commands::AddTemplate {
+                name,
+                command,
+                mut fields,
+            } => {
+                fields.resolve_paths();
+                let mut command = CommandDef {
+                    command,
+                    ..Default::default()
+                };
+                fields.apply(&mut command);
+                Ok(Message::AddTemplate { name, command })
+            }
+            Commands::RemoveTemplate { name } => Ok(Message::RemoveTemplate { name }),
+            Commands::Templates => Ok(Message::ListTemplates),
+            Commands::AddFromTemplate {
+                template,
+                name,
+                vars,
+            } => {
+                let vars = vars
+                    .iter()
+                    .map(|var| match var.split_once('=') {
+                        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+                        None => Err(format!("{var} is not of the form KEY=VALUE")),
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Message::AddFromTemplate {
+                    template,
+                    name,
+                    vars,
+                })
+            }
+            Commands::Copy { from, to, force } => Ok(Message::Copy { from, to, force }),
+            Commands::Execute {
+                name,
+                until_exit: false,
+                force,
+            } => Ok(Message::Execute { name, force }),
+            Commands::Execute {
+                name,
+                until_exit: true,
+                force,
+            } => Ok(Message::ExecuteAttached { name, force }),
+            Commands::Logs {
+                names,
+                follow: true,
+            } => Ok(Message::Follow { names }),
+            Commands::Logs { follow: false, .. } => Err(
+                "uniq-proc keeps no past output, only the live output with --follow".to_string(),
+            ),
+            Commands::WaitReady { name, within } => Ok(Message::WaitReady {
+                name,
+                timeout: within,
+            }),
+            Commands::Start { name } => Ok(Message::Start { name }),
+            Commands::StartAll { selector: None } => Ok(Message::StartAll),
+            Commands::StopAll => Ok(Message::StopAll),
+            Commands::StartAll {
+                selector: Some(selector),
+            } => Ok(Message::StartMatching { selector }),
             Commands::Kill { name } => Ok(Message::Kill { name }),
             Commands::Restart { name } => Ok(Message::Restart { name }),
-            Commands::List => Ok(Message::List),
+            Commands::List { page } => Ok(Message::List { page }),
+            Commands::Status {
+                page,
+                tree,
+                verbose,
+            } => Ok(Message::Status {
+                page,
+                tree,
+                verbose,
+            }),
+            Commands::Search { term } => Ok(Message::Search { term }),
+            Commands::Dump => Ok(Message::Dump),
+            Commands::Info => Ok(Message::Info),
+            Commands::Health => Ok(Message::Health),
+            Commands::History { name } => Ok(Message::History { name }),
             Commands::Toggle { name } => Ok(Message::Toggle { name }),
+            Commands::Disown { name } => Ok(Message::Disown { name }),
+            Commands::Disable { name } => Ok(Message::SetEnabled {
+                name,
+                enabled: false,
+            }),
+            Commands::Enable { name } => Ok(Message::SetEnabled {
+                name,
+                enabled: true,
+            }),
+            Commands::ResetBackoff { name } => Ok(Message::ResetBackoff { name }),
+            Commands::ResetBackoffAll { selector: None } => Ok(Message::ResetBackoffAll),
+            Commands::ResetBackoffAll {
+                selector: Some(selector),
+            } => Ok(Message::ResetBackoffMatching { selector }),
+            Commands::SystemdUnit { name } => Ok(Message::SystemdUnit { name }),
+            Commands::Capture { pid, name, adopt } => Ok(Message::Capture { pid, name, adopt }),
+            Commands::Env { name, format } => Ok(Message::Env { name, format }),
+            Commands::Reset {
+                commands,
+                procs,
+                all,
+                counters,
+            } => Ok(Message::Reset {
+                commands: commands || all,
+                procs: procs || all,
+                counters,
+            }),
         }
     }
 }
 
-#[derive(Default)]
-struct Daemon {
-    data: Arc<Mutex<DaemonState>>,
-}
-
-impl Daemon {
-    pub fn new(keep: bool) -> Self {
-        Self {
-            data: Arc::from(Mutex::from(DaemonState::new(keep))),
-        }
+fn main() {
+    let cli = Cli::parse();
+    if let Some(path) = &cli.config {
+        set_config_path(path);
     }
-
-    fn list(&self) -> String {
-        let data = self.data.lock().expect("working mutex");
-        serde_json::to_string(&data.commands).expect("can convert to json")
+    if let Some(path) = &cli.socket {
+        set_socket_path(path);
     }
-}
 
-impl Daemon {
-    pub fn run(&self) {
-        if send_message(Message::Alive) == "running" {
-            return;
+    match &cli.command {
+        Commands::Daemon {
+            watch_config,
+            max_procs,
+            queue,
+            max_restarts,
+            restart_window,
+            persist_mode,
+            coalesce_ms,
+            max_connections,
+            max_log_bytes,
+            shell,
+            compact_state,
+            replace,
+            ephemeral,
+            log_target,
+            log_file,
+            ..
+        } => {
+            if let Err(e) = logging::init(*log_target, log_file) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            if *ephemeral && cli.keep {
+                eprintln!("an ephemeral daemon has no state to continue from, drop -k");
+                std::process::exit(1);
+            }
+            // the state the old daemon leaves behind is only read once it exited
+            let mut lock = None;
+            if *replace {
+                let taken = startup_lock().and_then(|taken| {
+                    evict_daemon(cli.codec)?;
+                    Ok(taken)
+                });
+                match taken {
+                    Ok(taken) => lock = Some(taken),
+                    Err(e) => {
+                        log::error!("could not replace the daemon: {e}");
+                        eprintln!("could not replace the daemon: {e}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            let daemon = match ephemeral {
+                true => Daemon::new_ephemeral(),
+                false => Daemon::new(cli.keep || *replace),
+            };
+            #[allow(unused_mut)]
+            let mut daemon = daemon
+                .with_durable(cli.durable)
+                .with_ignore_case(cli.ignore_case)
+                .with_watch_config(*watch_config)
+                .with_max_procs(*max_procs, *queue)
+                .with_restart_limit(*max_restarts, *restart_window)
+                .with_persist_mode(*persist_mode)
+                .with_compact_state(*compact_state)
+                .with_coalesce_window(Duration::from_millis(*coalesce_ms))
+                .with_max_connections(*max_connections)
+                .with_max_log_bytes(*max_log_bytes)
+                .with_shell(shell.clone());
+            #[cfg(feature = "http")]
+            if let Commands::Daemon {
+                http: Some(addr),
+                http_token: Some(token),
+                ..
+            } = &cli.command
+            {
+                daemon = daemon.with_http(addr.clone(), token.clone());
+            }
+            if let Some(lock) = lock {
+                daemon = daemon.with_startup_lock(lock);
+            }
+            daemon.run();
+        }
+        Commands::DaemonRestart => {
+            let response = restart_daemon(
+                cli.codec,
+                cli.durable,
+                cli.ignore_case,
+                cli.config.as_deref(),
+            );
+            render::print(&response, cli.output, cli.quiet);
+            if response.failed() {
+                std::process::exit(response.exit_code());
+            }
         }
-        let _ = std::fs::remove_file(SOCKET_PATH);
-        let running = Arc::from(AtomicBool::new(true));
-        let running_clone = running.clone();
-        const SOCKET_PATH: &str = "/tmp/uniq-proc.sock";
-        std::thread::spawn(move || {
-            let mut signals = Signals::new(&[SIGINT, SIGTERM]).unwrap();
-            for sig in signals.forever() {
-                match sig {
-                    _ => {
-                        running_clone.store(false, Ordering::SeqCst);
-                        break;
+        Commands::Doctor { fix } => {
+            let findings = doctor(*fix);
+            for finding in &findings {
+                if finding.ok {
+                    println!("ok    {}", finding.finding);
+                } else {
+                    println!("fail  {}", finding.finding);
+                    if !finding.suggestion.is_empty() {
+                        println!("      {}", finding.suggestion);
                     }
                 }
             }
-        });
-
-        let socket = std::os::unix::net::UnixListener::bind(SOCKET_PATH)
-            .expect("successfull creation of socket");
-        socket
-            .set_nonblocking(true)
-            .expect("can set socket to nonblocking");
-        std::thread::scope(|s| {
-            while running.load(std::sync::atomic::Ordering::SeqCst) {
-                let connection = socket.accept();
-                match connection {
-                    Ok((mut stream, _)) => {
-                        let mut msg_raw = String::new();
-                        use std::io::Read;
-                        let _ =
-                            stream.set_read_timeout(Some(std::time::Duration::from_millis(160)));
-                        let _ = stream.read_to_string(&mut msg_raw);
-                        let msg = from_str(&msg_raw);
-                        s.spawn(move || {
-                            let response = match msg {
-                                Ok(Message::Add { name, command }) => self.add(name, command),
-                                Ok(Message::Remove { name }) => self.remove(name),
-                                Ok(Message::Kill { name }) => self.kill(name),
-                                Ok(Message::Restart { name }) => self.restart(name),
-                                Ok(Message::Toggle { name }) => self.toggle(name),
-                                Ok(Message::Execute { name }) => self.execute(name),
-                                Ok(Message::List) => self.list(),
-                                Ok(Message::Alive) => String::from("running"),
-                                Err(_) => String::from("Could parse the command"),
-                            };
-                            stream.write_all(&response.bytes().collect::<Vec<_>>())
-                        });
+            if findings.iter().any(|finding| !finding.ok) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Ping { count, interval } => {
+            let timeout = cli.timeout.map_or(CLIENT_TIMEOUT, Duration::from_secs);
+            let interval = Duration::from_millis(*interval);
+            if !ping(*count, interval, cli.codec, timeout, cli.quiet) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Check => {
+            let problems = check_config();
+            for problem in &problems {
+                eprintln!("{problem}");
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+            if !cli.quiet {
+                println!("{} is valid", DaemonState::get_config_path().display());
+            }
+        }
+        _ => {
+            let message = match Message::try_from(cli.command) {
+                Ok(message) => message,
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            };
+            if cli.no_daemon {
+                if !matches!(message, Message::Execute { .. }) {
+                    eprintln!("--no-daemon only supports execute");
+                    std::process::exit(1);
+                }
+                let response = match Daemon::new_read_only() {
+                    Ok(daemon) => {
+                        let daemon = daemon.with_ignore_case(cli.ignore_case);
+                        let response = daemon.handle(message);
+                        daemon.kill_all();
+                        response
                     }
-                    Err(_) => std::thread::sleep(std::time::Duration::from_millis(160)),
+                    Err(e) => Response::failure(ErrorCode::Invalid, e),
+                };
+                render::print(&response, cli.output, cli.quiet);
+                if response.failed() {
+                    std::process::exit(response.exit_code());
+                }
+                return;
+            }
+            let quick_timeout = cli.timeout.map_or(CLIENT_TIMEOUT, Duration::from_secs);
+            let timeout = match cli.timeout {
+                None if message.waits_on_processes() => None,
+                _ => Some(quick_timeout),
+            };
+            let alive_timeout = Duration::from_millis(cli.alive_timeout);
+            match send_message(Message::Alive, cli.codec, Some(alive_timeout)) {
+                Ok(_) => warn_ignored_flags(
+                    cli.codec,
+                    cli.durable,
+                    cli.ignore_case,
+                    cli.config.is_some(),
+                ),
+                Err(SendError::NotRunning(_)) => {
+                    let spawned = spawn_daemon(
+                        cli.keep,
+                        cli.durable,
+                        cli.ignore_case,
+                        cli.config.as_deref(),
+                    );
+                    if let Err(e) = spawned {
+                        eprintln!("{e}");
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
                 }
             }
-        });
-        self.data.lock().expect("working mutex").save_state();
-        std::fs::remove_file(SOCKET_PATH).expect("can remove socket");
-    }
-}
 
-#[derive(Default, Serialize, Deserialize)]
-struct DaemonState {
-    commands: HashMap<String, String>,
-    procs: HashMap<String, u32>,
+            if let Message::ExecuteAttached { .. } | Message::Follow { .. } = message {
+                stream_output(message, cli.codec, cli.output, cli.quiet, cli.json_lines);
+            }
+            let forwarding = match &message {
+                Message::Execute { name, .. }
+                | Message::Restart { name }
+                | Message::Toggle { name } => Some(forward_signals(name.clone(), cli.codec)),
+                _ => None,
+            };
+            let response = match send_message(message, cli.codec, timeout) {
+                Ok(response) => response,
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            };
+            if let Some(forwarding) = forwarding {
+                forwarding.close();
+            }
+            render::print(&response, cli.output, cli.quiet);
+            if let Response::Readiness { outcome, .. } = response {
+                std::process::exit(outcome.exit_code());
+            }
+            if response.failed() {
+                std::process::exit(response.exit_code());
+            }
+        }
+    }
 }
 
-impl DaemonState {
-    fn get_config_path() -> PathBuf {
-        let base_dirs = BaseDirectories::with_prefix("uniq-proc").unwrap();
-        base_dirs.place_config_file("config.json").unwrap()
-    }
-    fn get_state_path() -> PathBuf {
-        PathBuf::from("/tmp/uniq-proc.state")
-    }
-    pub fn write_commands_to_config_dir(&self) {
-        let config_path = Self::get_config_path();
-        let config_content = serde_json::to_string_pretty(&self.commands).expect("can create json");
-        std::fs::write(config_path, config_content).expect("can write config file");
+/// Sends `count` alive checks `interval` apart, printing the round-trip time of each
+/// and the minimum, average and maximum like ping. Returns whether all were answered.
+fn ping(count: u32, interval: Duration, codec: Codec, timeout: Duration, quiet: bool) -> bool {
+    let millis = |time: Duration| time.as_secs_f64() * 1000.0;
+    let socket = socket_path();
+    let mut times = Vec::new();
+    for sequence in 1..=count {
+        if sequence > 1 {
+            std::thread::sleep(interval);
+        }
+        let sent = Instant::now();
+        match send_message(Message::Alive, codec, Some(timeout)) {
+            Ok(_) => {
+                let time = sent.elapsed();
+                if !quiet {
+                    println!(
+                        "reply from {}: seq={sequence} time={:.2}ms",
+                        socket.display(),
+                        millis(time)
+                    );
+                }
+                times.push(time);
+            }
+            Err(e) => eprintln!("no reply: seq={sequence}, {e}"),
+        }
     }
-
-    pub fn save_state(&self) {
-        let state_path = Self::get_state_path();
-        let state_content = serde_json::to_string_pretty(&self).expect("can create json");
-        std::fs::write(state_path, state_content).expect("can write config file");
+    let received = times.len();
+    println!("{count} sent, {received} received");
+    if let (Some(min), Some(max)) = (times.iter().min(), times.iter().max()) {
+        let avg = times.iter().sum::<Duration>() / received as u32;
+        println!(
+            "round-trip min/avg/max = {:.2}/{:.2}/{:.2}ms",
+            millis(*min),
+            millis(avg),
+            millis(*max)
+        );
     }
+    received == count as usize
+}
 
-    pub fn new(keep: bool) -> Self {
-        let config_path = Self::get_config_path();
-        let last_state_path = Self::get_state_path();
-        let mut result = Self::default();
-        if keep && last_state_path.exists() {
-            let state_content = fs::read_to_string(last_state_path).unwrap();
-            let parsed = from_str::<Self>(&state_content).expect("valid json");
-            if !config_path.exists() {
-                result.commands = parsed.commands;
+/// Streams the output of the attached or followed processes to the terminal,
+/// and exits like the attached process.
+fn stream_output(
+    message: Message,
+    codec: Codec,
+    format: render::OutputFormat,
+    quiet: bool,
+    json_lines: bool,
+) -> ! {
+    let followed = match &message {
+        Message::Follow { names } => names.clone(),
+        _ => Vec::new(),
+    };
+    let streamed = send_streaming(message, codec, |frame| match frame {
+        _ if json_lines => render::print_json_line(frame),
+        Response::Line {
+            name, stderr, line, ..
+        } => render::print_tagged(&followed, name, *stderr, line),
+        // the bytes exactly as written, for output that is not text
+        Response::Output { stderr, line, raw } => {
+            let bytes = raw.as_deref().unwrap_or(line.as_bytes());
+            let _ = match stderr {
+                true => std::io::stderr().write_all(bytes),
+                false => std::io::stdout().write_all(bytes),
+            };
+        }
+        _ => {}
+    });
+    match streamed {
+        Ok(response) if json_lines => {
+            render::print_json_line(&response);
+            match response {
+                Response::Exited { code, .. } => std::process::exit(code.unwrap_or(1)),
+                response => std::process::exit(response.exit_code()),
             }
-            result.procs = parsed.procs;
         }
-        if config_path.exists() {
-            let config_content = fs::read_to_string(config_path).unwrap();
-            result.commands = from_str(&config_content).expect("valid json");
+        Ok(Response::Exited { code, .. }) => std::process::exit(code.unwrap_or(1)),
+        Ok(response) => {
+            render::print(&response, format, quiet);
+            std::process::exit(response.exit_code())
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1)
         }
-        result
     }
 }
 
-impl Daemon {
-    pub fn execute(&self, name: String) -> String {
-        let command;
-        {
-            let data = self.data.lock().expect("working mutex");
-            let is_running = data.procs.get(&name).is_some();
-            command = if !is_running {
-                data.commands.get(&name).cloned()
-            } else {
-                None
+/// Passes SIGINT and SIGTERM on to the process of `name` while this client waits
+/// for it, instead of exiting and leaving it running. When there is no process to
+/// pass it on to yet, like while the start is queued or its dependencies start, or
+/// on a second SIGINT, the client exits with 128 plus the signal, 130 for SIGINT.
+/// Closing the handle ends the forwarding, signals arriving after it are ignored.
+fn forward_signals(name: String, codec: Codec) -> signal_hook::iterator::Handle {
+    let mut signals = Signals::new([SIGINT, SIGTERM]).expect("can install signal handlers");
+    let handle = signals.handle();
+    std::thread::spawn(move || {
+        let mut interrupted = false;
+        for signal in signals.forever() {
+            if signal == SIGINT && interrupted {
+                std::process::exit(128 + SIGINT);
+            }
+            interrupted |= signal == SIGINT;
+            let message = Message::Signal {
+                name: name.clone(),
+                signal,
             };
+            let failure = match send_message(message, codec, Some(CLIENT_TIMEOUT)) {
+                Ok(response) if response.failed() => response.to_string(),
+                Ok(_) => continue,
+                Err(e) => e.to_string(),
+            };
+            eprintln!("could not forward the signal to {name}: {failure}");
+            std::process::exit(128 + signal);
         }
-        let Some(command) = command else {
-            return format!("{name} is not registered yet");
-        };
-        let mut process = Command::new("sh").arg("-c").arg(command).spawn().unwrap();
-        let pid = process.id();
-        {
-            let mut data = self.data.lock().expect("no poisioed lock");
-            data.procs.insert(name.clone(), pid);
-            data.save_state();
-        }
-        let _ = process.wait();
-        {
-            let mut data = self.data.lock().expect("working mutex");
-            if data.procs.get(&name).filter(|&id| *id == pid).is_some() {
-                data.procs.remove(&name);
-                data.save_state();
-                format!("{name} executed successfully")
+    });
+    handle
+}
+
+/// Warns about the daemon flags given to this client that the already running
+/// daemon was started without, since they only take effect at startup.
+fn warn_ignored_flags(codec: Codec, durable: bool, ignore_case: bool, config: bool) {
+    if !durable && !ignore_case && !config {
+        return;
+    }
+    let Ok(Response::Info(info)) = send_message(Message::Info, codec, Some(CLIENT_TIMEOUT)) else {
+        return;
+    };
+    let ignored: Vec<&str> = [
+        ("--durable", durable && !info.durable),
+        ("--ignore-case", ignore_case && !info.ignore_case),
+    ]
+    .into_iter()
+    .filter_map(|(flag, ignored)| ignored.then_some(flag))
+    .collect();
+    if !ignored.is_empty() {
+        let flags = ignored.join(" ");
+        eprintln!(
+            "warning: the running daemon was started without {flags}, they only take effect when \
+             it starts. Restart it with `uniq-proc {flags} daemon-restart`"
+        );
+    }
+    let path = DaemonState::get_config_path();
+    if config && info.config != path {
+        eprintln!(
+            "warning: the running daemon uses the config {}, not {}. Restart it with \
+             `uniq-proc --config {} daemon-restart`",
+            info.config.display(),
+            path.display(),
+            path.display()
+        );
+    }
+}
+
+/// Shuts the daemon down leaving its processes running and starts a new one
+/// with -k and the old one's arguments, reporting for every process whether the
+/// new daemon took it over. Without them, like for a daemon in another pid
+/// namespace, the new one only gets the options shared with this client. An
+/// ephemeral daemon is refused before it is shut down, nothing could take it over.
+fn restart_daemon(
+    codec: Codec,
+    durable: bool,
+    ignore_case: bool,
+    config: Option<&Path>,
+) -> Response {
+    let running = |response| match response {
+        Ok(Response::Status { entries, .. }) => entries
+            .into_iter()
+            .filter_map(|entry| Some((entry.name, entry.pid?)))
+            .collect(),
+        _ => HashMap::new(),
+    };
+    let status = || Message::Status {
+        page: Page::default(),
+        tree: false,
+        verbose: false,
+    };
+    let before: HashMap<String, u32> = running(send_message(status(), codec, Some(CLIENT_TIMEOUT)));
+    let info = match send_message(Message::Info, codec, Some(CLIENT_TIMEOUT)) {
+        Ok(Response::Info(info)) => Some(info),
+        _ => None,
+    };
+    if info.as_ref().is_some_and(|info| info.ephemeral) {
+        return Response::failure(
+            ErrorCode::Invalid,
+            "The daemon is ephemeral, a new one has no state to take its processes over from. \
+             Shut it down and start a new one instead"
+                .into(),
+        );
+    }
+    let args = info.and_then(|info| daemon_args(info.pid));
+    if let Err(e) = evict_daemon(codec) {
+        return Response::failure(ErrorCode::Conflict, e);
+    }
+    let spawned = match args {
+        Some(args) => launch_daemon(restart_args(args)),
+        None => spawn_daemon(true, durable, ignore_case, config),
+    };
+    if let Err(e) = spawned {
+        return Response::failure(ErrorCode::SpawnFailed, e);
+    }
+    let after: HashMap<String, u32> = running(send_message(status(), codec, Some(CLIENT_TIMEOUT)));
+    if before.is_empty() {
+        return Response::Ok("Restarted the daemon, no processes were running".into());
+    }
+    let results = before
+        .into_iter()
+        .map(|(name, pid)| {
+            let result = if after.get(&name) == Some(&pid) {
+                Response::Ok(format!("reattached (pid {pid})"))
             } else {
-                format!(
-                    "{name} executed successfully, but was restarted with very interesting timing"
+                Response::failure(
+                    ErrorCode::NotRunning,
+                    format!("lost (pid {pid} is not running anymore)"),
                 )
-            }
-        }
-    }
+            };
+            (name, result)
+        })
+        .collect();
+    Response::Bulk(results)
+}
 
-    pub fn kill(&self, name: String) -> String {
-        let mut data = self.data.lock().expect("working mutex");
+/// The arguments the daemon `pid` was started with, read from its command line.
+fn daemon_args(pid: u32) -> Option<Vec<OsString>> {
+    let cmdline = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let args = cmdline
+        .split(|&byte| byte == 0)
+        .filter(|arg| !arg.is_empty())
+        .skip(1)
+        .map(|arg| OsString::from_vec(arg.to_vec()))
+        .collect();
+    Some(args)
+}
 
-        let Some(&pid) = data.procs.get(&name) else {
-            return format!("{name} was not running via uniq-proc");
-        };
-        let mut system = System::new();
-        system.refresh_processes();
-        let Some(process) = system.process((pid as i32).into()) else {
-            return format!("Failed to get the process");
-        };
-        process.kill();
-        data.procs.remove(&name);
-        data.save_state();
-        format!("Successfully killed name")
+/// Turns the arguments of the old daemon into those of its successor: continuing
+/// from its state on its socket, and logging to a file since it outlives this client.
+/// `--replace` is dropped, the old daemon is gone by then, and so is `--ephemeral`,
+/// which cannot continue from a state.
+fn restart_args(old: Vec<OsString>) -> Vec<OsString> {
+    let mut args = Vec::with_capacity(old.len() + 4);
+    if !old.iter().any(|arg| arg == "-k") {
+        args.push("-k".into());
+    }
+    if !old
+        .iter()
+        .any(|arg| arg == "--socket" || arg.as_bytes().starts_with(b"--socket="))
+    {
+        args.extend(["--socket".into(), socket_path().into_os_string()]);
     }
-    pub fn toggle(&self, name: String) -> String {
-        let is_running = {
-            let l = self.data.lock().expect("no poisoned lock");
-            l.procs.get(&name).is_some()
-        };
-        if is_running {
-            self.kill(name)
-        } else {
-            self.execute(name)
+    let mut old = old.into_iter();
+    while let Some(arg) = old.next() {
+        if arg == "--log-target" {
+            old.next();
+        } else if !(arg == "--replace"
+            || arg == "--ephemeral"
+            || arg.as_bytes().starts_with(b"--log-target="))
+        {
+            args.push(arg);
         }
     }
+    args.extend(["--log-target".into(), "file".into()]);
+    args
+}
 
-    pub fn add(&self, name: String, command: String) -> String {
-        let mut data = self.data.lock().expect("no poisioed lock");
-        data.commands.insert(name.clone(), command);
-        data.save_state();
-        data.write_commands_to_config_dir();
-        format!("Added: {}", data.commands.get(&name).unwrap())
+/// Shuts the running daemon down leaving its processes running, and waits for it
+/// to give up the socket, logging whether there was one.
+fn evict_daemon(codec: Codec) -> Result<(), String> {
+    let pid = match send_message(Message::Info, codec, Some(CLIENT_TIMEOUT)) {
+        Ok(Response::Info(info)) => Some(info.pid),
+        _ => None,
+    };
+    let shutdown = Message::Shutdown { keep_running: true };
+    match send_message(shutdown, codec, Some(CLIENT_TIMEOUT)) {
+        Ok(failure @ Response::Failure { .. }) => return Err(failure.to_string()),
+        Ok(_) => {}
+        Err(SendError::NotRunning(_)) => {
+            log::info!("no daemon was running, starting fresh");
+            return Ok(());
+        }
+        Err(e) => return Err(e.to_string()),
     }
-
-    pub fn remove(&self, name: String) -> String {
-        let mut data = self.data.lock().expect("no poisioed lock");
-        data.commands.remove(&name);
-        data.save_state();
-        data.write_commands_to_config_dir();
-        format!("Removed {name}")
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    while socket_path().exists() {
+        if Instant::now() >= deadline {
+            return Err("The old daemon did not exit in time".into());
+        }
+        std::thread::sleep(PROBE_INTERVAL);
     }
-
-    pub fn restart(&self, name: String) -> String {
-        format!("{}\n{}", self.kill(name.clone()), self.execute(name))
+    match pid {
+        Some(pid) => log::info!(pid; "replaced the running daemon"),
+        None => log::info!("replaced the running daemon"),
     }
+    Ok(())
 }
 
-fn send_message(msg: Message) -> String {
-    match std::os::unix::net::UnixStream::connect("/tmp/uniq-proc.sock") {
-        Ok(mut stream) => {
-            let message = serde_json::to_string(&msg).expect("can convert to json");
-            stream
-                .write_all(&message.bytes().collect::<Vec<_>>())
-                .expect("can write to stream");
-            stream
-                .set_write_timeout(std::time::Duration::from_millis(160).into())
-                .expect("can set read timeout");
-
-            let mut response = String::new();
-            match stream.read_to_string(&mut response) {
-                Ok(_) => format!("{response}"),
-                Err(e) => match msg {
-                    Message::Alive => format!("not running"),
-                    _ => format!("An error has occured while getting the response: {e}"),
-                },
-            }
-        }
-        Err(e) => format!("{e:?}"),
+/// Starts a daemon in the background, passing on the options it shares with the client.
+/// Fails when it exits or does not listen within [`CLIENT_TIMEOUT`], a panic of it
+/// already went to the inherited stderr then.
+fn spawn_daemon(
+    keep: bool,
+    durable: bool,
+    ignore_case: bool,
+    config: Option<&Path>,
+) -> Result<(), String> {
+    let mut args: Vec<OsString> = Vec::new();
+    if keep {
+        args.push("-k".into());
+    }
+    if durable {
+        args.push("--durable".into());
+    }
+    if ignore_case {
+        args.push("--ignore-case".into());
     }
+    if let Some(config) = config {
+        args.extend(["--config".into(), config.into()]);
+    }
+    args.extend(["--socket".into(), socket_path().into_os_string()]);
+    // the daemon outlives this client, so it logs to a file instead of its terminal
+    args.extend(["daemon".into(), "--log-target".into(), "file".into()]);
+    launch_daemon(args)
 }
 
-fn main() {
-    let cli = Cli::parse();
+/// Starts the daemon with `args` and waits for it to listen, see [`spawn_daemon`].
+fn launch_daemon(args: Vec<OsString>) -> Result<(), String> {
+    let mut cmd =
+        std::process::Command::new(std::env::current_exe().expect("can get own executable"));
+    cmd.args(args);
+    // and it is only waited on here if it exits right away
+    #[allow(clippy::zombie_processes)]
+    let mut daemon = cmd
+        .spawn()
+        .map_err(|e| format!("could not start the daemon: {e}"))?;
 
-    match &cli.command {
-        Commands::Daemon => {
-            let daemon = Daemon::new(cli.keep);
-            daemon.run();
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    while !socket_path().exists() {
+        if let Ok(Some(status)) = daemon.try_wait() {
+            return Err(format!(
+                "the daemon exited ({status}) before listening on {}, its log is in {LOG_FILE}",
+                socket_path().display()
+            ));
         }
-        _ => {
-            if send_message(Message::Alive) != "running" {
-                let mut cmd = std::process::Command::new(
-                    std::env::current_exe().expect("can get own executable"),
-                );
-                if cli.keep {
-                    cmd.arg("-k");
-                }
-                cmd.arg("daemon");
-                cmd.spawn().expect("can start command");
-
-                while !PathBuf::from("/tmp/uniq-proc.sock").exists() {
-                    std::thread::sleep(std::time::Duration::from_millis(5));
-                }
-            }
-
-            let response = send_message(
-                Message::try_from(cli.command)
-                    .expect("can convert all that is not Commands::Daemon"),
-            );
-            println!("{response}");
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "the daemon (pid {}) did not listen on {} within {}s, its log is in {LOG_FILE}",
+                daemon.id(),
+                socket_path().display(),
+                CLIENT_TIMEOUT.as_secs()
+            ));
         }
+        std::thread::sleep(std::time::Duration::from_millis(5));
     }
+    Ok(())
 }
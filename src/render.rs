@@ -0,0 +1,187 @@
+//! Client side rendering of [`Response`]s, as aligned tables for people or json for scripts.
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::io::{IsTerminal, Write};
+use uniq_proc::{Response, StatusEntry};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned tables, colored on a terminal unless NO_COLOR is set
+    #[default]
+    Human,
+    /// The response as one json document
+    Json,
+}
+
+const BLUE: &str = "\x1b[34m";
+const CYAN: &str = "\x1b[36m";
+const GREEN: &str = "\x1b[32m";
+const MAGENTA: &str = "\x1b[35m";
+const GREY: &str = "\x1b[90m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// Prints `response` in `format`, failures to stderr and everything else to stdout.
+/// Quiet, successes are left out unless they are the output asked for, like listings.
+pub fn print(response: &Response, format: OutputFormat, quiet: bool) {
+    if response.failed() {
+        let color = use_color(std::io::stderr().is_terminal());
+        eprintln!("{}", render(response, format, color));
+    } else if !quiet || is_output(response) {
+        let color = use_color(std::io::stdout().is_terminal());
+        println!("{}", render(response, format, color));
+    }
+}
+
+/// Prints one frame of a stream as a json object on its own line, flushed right away so
+/// tools like `jq` see it as it happens. The `type` field tells the kind of frame.
+pub fn print_json_line(response: &Response) {
+    let mut object = match response {
+        Response::Output { stderr, line, .. } => serde_json::json!({
+            "type": "output",
+            "stream": if *stderr { "stderr" } else { "stdout" },
+            "line": line.trim_end_matches('\n'),
+        }),
+        Response::Line {
+            name, stderr, line, ..
+        } => serde_json::json!({
+            "type": "output",
+            "name": name,
+            "stream": if *stderr { "stderr" } else { "stdout" },
+            "line": line.trim_end_matches('\n'),
+        }),
+        Response::Exited { name, code } => serde_json::json!({
+            "type": "exited",
+            "name": name,
+            "code": code,
+        }),
+        response => serde_json::json!({
+            "type": if response.failed() { "error" } else { "response" },
+            "response": response,
+        }),
+    };
+    // lines that are not UTF-8 also come exactly as written, newline included
+    if let Response::Output { raw: Some(raw), .. } | Response::Line { raw: Some(raw), .. } =
+        response
+    {
+        object["base64"] = BASE64_STANDARD.encode(raw).into();
+    }
+    let mut out = std::io::stdout().lock();
+    let _ = writeln!(out, "{object}").and_then(|_| out.flush());
+}
+
+/// Prints `line` of a followed process, behind the name of its command padded to
+/// the longest of `names`, which on a terminal each get a color of their own.
+pub fn print_tagged(names: &[String], name: &str, stderr: bool, line: &str) {
+    const COLORS: [&str; 5] = [CYAN, YELLOW, GREEN, MAGENTA, BLUE];
+    let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+    let mut prefix = format!("{name:width$} |");
+    let terminal = match stderr {
+        true => std::io::stderr().is_terminal(),
+        false => std::io::stdout().is_terminal(),
+    };
+    if use_color(terminal) {
+        let index = names.iter().position(|other| other == name).unwrap_or(0);
+        prefix = format!("{}{prefix}{RESET}", COLORS[index % COLORS.len()]);
+    }
+    let line = format!("{prefix} {}", line.trim_end_matches('\n'));
+    let _ = if stderr {
+        writeln!(std::io::stderr(), "{line}")
+    } else {
+        let mut out = std::io::stdout().lock();
+        writeln!(out, "{line}").and_then(|_| out.flush())
+    };
+}
+
+fn render(response: &Response, format: OutputFormat, color: bool) -> String {
+    match format {
+        OutputFormat::Json => serde_json::to_string(response).expect("can convert to json"),
+        OutputFormat::Human => human(response, color),
+    }
+}
+
+fn is_output(response: &Response) -> bool {
+    matches!(
+        response,
+        Response::Commands { .. }
+            | Response::Status { .. }
+            | Response::Matches(_)
+            | Response::Dump(_)
+            | Response::Info(_)
+            | Response::History { .. }
+    )
+}
+
+/// Colors only go to a terminal, and not at all when NO_COLOR is set to anything.
+fn use_color(terminal: bool) -> bool {
+    terminal && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
+fn human(response: &Response, color: bool) -> String {
+    let paint = |code: &str, text: String| {
+        if color {
+            format!("{code}{text}{RESET}")
+        } else {
+            text
+        }
+    };
+    match response {
+        Response::Commands {
+            commands,
+            runtime_only,
+        } => {
+            let mut names: Vec<&String> = commands.keys().collect();
+            names.sort();
+            let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+            let mut lines: Vec<String> = names
+                .into_iter()
+                .map(|name| {
+                    let line = format!("{name:width$}  {}", commands[name].command);
+                    if commands[name].disabled {
+                        paint(GREY, format!("{line}  (disabled)"))
+                    } else if runtime_only.contains(name) {
+                        paint(YELLOW, line)
+                    } else {
+                        line
+                    }
+                })
+                .collect();
+            if !runtime_only.is_empty() {
+                lines.push(format!(
+                    "not in the config, lost when the daemon restarts without -k: {}",
+                    runtime_only.join(", ")
+                ));
+            }
+            lines.join("\n")
+        }
+        Response::Status { entries, stale } => {
+            let width = entries.iter().map(|e| e.name.len()).max().unwrap_or(0);
+            let mut lines: Vec<String> = entries
+                .iter()
+                .map(|entry| {
+                    let state = paint(state_color(entry), entry.to_string());
+                    format!("{:width$}  {state}", entry.name)
+                })
+                .collect();
+            if *stale > 0 {
+                lines.push(format!("pruned {stale} stale process entries"));
+            }
+            lines.join("\n")
+        }
+        Response::Failure { .. } => paint(RED, response.to_string()),
+        _ => response.to_string(),
+    }
+}
+
+/// Running is green, and turns red like a crashed command once it fails probes.
+fn state_color(entry: &StatusEntry) -> &'static str {
+    match (entry.pid, entry.restarting_in, entry.queued) {
+        (Some(_), _, _) if entry.liveness_failures > 0 => RED,
+        (Some(_), _, _) => GREEN,
+        (None, _, Some(_)) => YELLOW,
+        (None, Some(_), None) => RED,
+        (None, None, None) if entry.given_up => RED,
+        (None, None, None) => GREY,
+    }
+}
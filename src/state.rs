@@ -0,0 +1,212 @@
+//! The format of the state file, for programs like status bars or editors that want
+//! to know what uniq-proc tracks without talking to the daemon.
+//!
+//! The daemon replaces the whole file atomically on every change, so readers never
+//! see it half written. Within a [`STATE_VERSION`] fields are only ever added, readers
+//! should ignore those they do not know. Any other change bumps the version.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Version of the format written by this uniq-proc.
+pub const STATE_VERSION: u32 = 1;
+
+/// Content of the state file, `/tmp/uniq-proc.state` unless `UNIQ_PROC_STATE_DIR` says otherwise.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StateFile {
+    /// Format version, files without one are version 0
+    #[serde(default)]
+    pub version: u32,
+    /// The registered commands by name, objects of the same fields as in the
+    /// config file. Version 0 files may also hold the bare command string.
+    #[serde(default)]
+    pub commands: HashMap<String, serde_json::Value>,
+    /// Names of the `commands` missing from the config file when this was written,
+    /// which a restart keeps even though the config file takes precedence
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsaved: Vec<String>,
+    /// The tracked processes by command name
+    #[serde(default, deserialize_with = "deserialize_procs")]
+    pub procs: HashMap<String, ProcRecord>,
+    /// Automatic restarts by command name, of the commands that were restarted
+    #[serde(default)]
+    pub restarts: HashMap<String, RestartRecord>,
+    /// The latest finished runs by command name, oldest first
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub history: HashMap<String, Vec<RunRecord>>,
+    /// Totals since the counters were last reset, kept across daemon restarts
+    #[serde(default)]
+    pub counters: Counters,
+}
+
+/// What happened to the processes of all commands over time, a measure of how
+/// flaky a setup is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Counters {
+    /// Processes started
+    #[serde(default)]
+    pub executes: u64,
+    /// Processes killed on request, including those replaced by a restart
+    #[serde(default)]
+    pub kills: u64,
+    /// Restarts, automatic or requested
+    #[serde(default)]
+    pub restarts: u64,
+    /// Processes that exited with a failure or by a signal nobody asked for
+    #[serde(default)]
+    pub crashes: u64,
+    /// Processes killed for failing their liveness probes
+    #[serde(default)]
+    pub timeouts: u64,
+}
+
+/// A process uniq-proc tracks for a command.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProcRecord {
+    pub pid: u32,
+    /// Start time in seconds since the epoch, telling the process apart from
+    /// a later one that got the recycled pid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<u64>,
+    /// Program and arguments the process was spawned with, empty if unknown
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub argv: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RestartRecord {
+    /// Automatic restarts since the last stable run
+    pub count: u32,
+    /// Whether uniq-proc stopped restarting the command after too many restarts
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub given_up: bool,
+}
+
+/// A finished process of a command.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub pid: u32,
+    /// Start time in seconds since the epoch, if known
+    pub started: Option<u64>,
+    /// Exit time in seconds since the epoch
+    pub ended: u64,
+    pub exit_code: Option<i32>,
+    /// The signal that ended the process, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    pub end: RunEnd,
+}
+
+/// How a run ended.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunEnd {
+    /// The process exited by itself
+    Exited,
+    /// uniq-proc killed it, on a kill, restart or toggle
+    Killed,
+    /// uniq-proc killed it after it failed its liveness probes in a row
+    Timeout,
+    /// It got a signal forwarded on request
+    Signalled,
+    /// The OOM killer killed it, as the `oom_kill` count of its cgroup rose meanwhile
+    Oom,
+    /// Something else sent it a signal, SIGKILL too unless the OOM killer was seen
+    Signal,
+}
+
+impl StateFile {
+    /// Parses the content of a state file of this or an older version.
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let file: StateFile = serde_json::from_str(content).map_err(|e| e.to_string())?;
+        if file.version > STATE_VERSION {
+            return Err(format!(
+                "version {} is newer than this uniq-proc understands",
+                file.version
+            ));
+        }
+        Ok(file)
+    }
+}
+
+/// Version 0 files map names directly to the pid.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProcEntry {
+    Pid(u32),
+    Record(ProcRecord),
+}
+
+fn deserialize_procs<'de, D>(deserializer: D) -> Result<HashMap<String, ProcRecord>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let entries = HashMap::<String, ProcEntry>::deserialize(deserializer)?;
+    let procs = entries.into_iter().map(|(name, entry)| match entry {
+        ProcEntry::Pid(pid) => (
+            name,
+            ProcRecord {
+                pid,
+                start_time: None,
+                argv: Vec::new(),
+            },
+        ),
+        ProcEntry::Record(record) => (name, record),
+    });
+    Ok(procs.collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DaemonState;
+
+    const VERSION_0: &str = r#"{
+        "commands": {"web": "python -m http.server", "db": {"command": "postgres"}},
+        "procs": {"web": 4242},
+        "restarts": {"web": {"count": 2}}
+    }"#;
+
+    #[test]
+    fn parses_version_0() {
+        let file = StateFile::parse(VERSION_0).expect("version 0 parses");
+        assert_eq!(file.version, 0);
+        let web = ProcRecord {
+            pid: 4242,
+            start_time: None,
+            argv: Vec::new(),
+        };
+        assert_eq!(file.procs, HashMap::from([("web".to_string(), web)]));
+        assert_eq!(file.commands["web"], "python -m http.server");
+        assert_eq!(file.restarts["web"].count, 2);
+    }
+
+    #[test]
+    fn upgrades_version_0() {
+        let file = StateFile::parse(VERSION_0).expect("version 0 parses");
+        let state = DaemonState::from_file(file).expect("version 0 loads");
+        assert_eq!(state.commands["web"].command, "python -m http.server");
+        assert_eq!(state.commands["db"].command, "postgres");
+        assert_eq!(state.procs["web"].pid, 4242);
+
+        let upgraded = state.to_file();
+        assert_eq!(upgraded.version, STATE_VERSION);
+        assert!(
+            upgraded.commands["web"].is_object(),
+            "{:?}",
+            upgraded.commands
+        );
+        let written = serde_json::to_string(&upgraded).expect("can serialize");
+        let reread = StateFile::parse(&written).expect("the upgrade parses");
+        assert_eq!(reread.version, STATE_VERSION);
+        assert_eq!(reread.procs, upgraded.procs);
+        assert_eq!(reread.commands, upgraded.commands);
+        assert_eq!(reread.restarts["web"].count, 2);
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let newer = format!(r#"{{"version": {}}}"#, STATE_VERSION + 1);
+        assert!(StateFile::parse(&newer).is_err());
+    }
+}
@@ -0,0 +1,1796 @@
+//! Tests of the daemon through [`Daemon::handle`], on daemons that keep everything
+//! in memory so they neither read nor write the user's files.
+
+use super::*;
+use std::ops::Deref;
+use std::sync::{mpsc, Barrier};
+use std::thread;
+
+/// An ephemeral daemon that kills the processes it started when dropped, also
+/// when the test panics.
+struct TestDaemon(Daemon);
+
+impl Deref for TestDaemon {
+    type Target = Daemon;
+
+    fn deref(&self) -> &Daemon {
+        &self.0
+    }
+}
+
+impl Drop for TestDaemon {
+    fn drop(&mut self) {
+        self.0.kill_all();
+    }
+}
+
+fn daemon() -> TestDaemon {
+    // coalescing would hand a read its predecessor's response
+    TestDaemon(Daemon::new_ephemeral().with_coalesce_window(Duration::ZERO))
+}
+
+fn command(line: &str) -> CommandDef {
+    CommandDef {
+        command: line.into(),
+        ..Default::default()
+    }
+}
+
+fn add(daemon: &Daemon, name: &str, command: CommandDef) {
+    ok(daemon.handle(Message::Add {
+        name: name.into(),
+        command,
+    }));
+}
+
+fn pid_of(daemon: &Daemon, name: &str) -> Option<u32> {
+    let data = daemon.data.lock().expect("working mutex");
+    data.procs.get(name).map(|info| info.pid)
+}
+
+/// A command whose processes [`live`] can tell apart from those of other tests,
+/// `tag` has to be unique among the tests.
+fn marked_sleep(tag: u32) -> String {
+    format!("exec sleep 30.{tag}{}", std::process::id())
+}
+
+/// How many processes run the command line of [`marked_sleep`], reaped or not.
+fn live(command: &str) -> usize {
+    let argv: Vec<u8> = command
+        .split_whitespace()
+        .skip(1)
+        .flat_map(|arg| arg.bytes().chain([0]))
+        .collect();
+    fs::read_dir("/proc")
+        .expect("can list processes")
+        .filter_map(|entry| fs::read(entry.ok()?.path().join("cmdline")).ok())
+        .filter(|cmdline| *cmdline == argv)
+        .count()
+}
+
+/// Like [`live`], but first gives a freshly spawned shell time to exec the command.
+fn settled_live(command: &str) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while live(command) == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    live(command)
+}
+
+#[track_caller]
+fn ok(response: Response) -> Response {
+    assert!(!response.failed(), "unexpected failure: {response}");
+    response
+}
+
+#[track_caller]
+fn fails(response: Response, code: ErrorCode) -> Response {
+    assert!(response.failed(), "unexpected success: {response}");
+    assert_eq!(response.error_code(), Some(code), "{response}");
+    response
+}
+
+/// The message of an [`Response::Ok`].
+#[track_caller]
+fn message(response: Response) -> String {
+    match response {
+        Response::Ok(message) => message,
+        response => panic!("expected a plain success, got {response}"),
+    }
+}
+
+fn names(response: &Response) -> Vec<String> {
+    let mut names: Vec<String> = match response {
+        Response::Commands { commands, .. } => commands.keys().cloned().collect(),
+        Response::Status { entries, .. } => entries.iter().map(|e| e.name.clone()).collect(),
+        Response::Matches(matches) => matches.iter().map(|(name, _)| name.clone()).collect(),
+        Response::Bulk(results) => results.keys().cloned().collect(),
+        response => panic!("expected a listing, got {response}"),
+    };
+    names.sort();
+    names
+}
+
+#[test]
+fn add_registers_a_command() {
+    let daemon = daemon();
+    let added = message(daemon.handle(Message::Add {
+        name: "web".into(),
+        command: command("sleep 30"),
+    }));
+    assert_eq!(added, "Added: sleep 30");
+    let unchanged = message(daemon.handle(Message::Add {
+        name: "web".into(),
+        command: command("sleep 30"),
+    }));
+    assert_eq!(unchanged, "Unchanged: sleep 30");
+    fails(
+        daemon.handle(Message::Add {
+            name: "empty".into(),
+            command: command(" "),
+        }),
+        ErrorCode::Invalid,
+    );
+    assert_eq!(
+        names(&daemon.handle(Message::List {
+            page: Page::default()
+        })),
+        ["web"]
+    );
+}
+
+#[test]
+fn add_many_reports_per_command() {
+    let daemon = daemon();
+    let commands = HashMap::from([
+        ("a".to_string(), command("true")),
+        ("b".to_string(), command("")),
+    ]);
+    let Response::Bulk(results) = daemon.handle(Message::AddMany { commands }) else {
+        panic!("expected per command results");
+    };
+    assert!(!results["a"].failed());
+    fails(results["b"].clone(), ErrorCode::Invalid);
+    assert_eq!(
+        names(&daemon.handle(Message::List {
+            page: Page::default()
+        })),
+        ["a"]
+    );
+}
+
+#[test]
+fn edit_changes_only_the_given_fields() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    let fields = DefinitionArgs {
+        cwd: Some("/tmp".into()),
+        ..Default::default()
+    };
+    ok(daemon.handle(Message::Edit {
+        name: "web".into(),
+        command: None,
+        fields,
+    }));
+    let data = daemon.data.lock().expect("working mutex");
+    assert_eq!(data.commands["web"].command, "sleep 30");
+    assert_eq!(data.commands["web"].cwd, Some("/tmp".into()));
+    drop(data);
+    let missing = daemon.handle(Message::Edit {
+        name: "nope".into(),
+        command: Some("true".into()),
+        fields: DefinitionArgs::default(),
+    });
+    fails(missing, ErrorCode::NotFound);
+}
+
+#[test]
+fn remove_forgets_a_command() {
+    let daemon = daemon();
+    add(&daemon, "web", command("true"));
+    ok(daemon.handle(Message::Remove { name: "web".into() }));
+    fails(
+        daemon.handle(Message::Remove { name: "web".into() }),
+        ErrorCode::NotFound,
+    );
+}
+
+#[test]
+fn templates_fill_their_placeholders() {
+    let daemon = daemon();
+    ok(daemon.handle(Message::AddTemplate {
+        name: "serve".into(),
+        command: command("python -m http.server {port}"),
+    }));
+    assert_eq!(names(&daemon.handle(Message::ListTemplates)), ["serve"]);
+    let incomplete = daemon.handle(Message::AddFromTemplate {
+        template: "serve".into(),
+        name: "docs".into(),
+        vars: HashMap::new(),
+    });
+    assert!(incomplete.failed());
+    ok(daemon.handle(Message::AddFromTemplate {
+        template: "serve".into(),
+        name: "docs".into(),
+        vars: HashMap::from([("port".to_string(), "8000".to_string())]),
+    }));
+    let data = daemon.data.lock().expect("working mutex");
+    assert_eq!(data.commands["docs"].command, "python -m http.server 8000");
+    drop(data);
+    ok(daemon.handle(Message::RemoveTemplate {
+        name: "serve".into(),
+    }));
+    fails(
+        daemon.handle(Message::RemoveTemplate {
+            name: "serve".into(),
+        }),
+        ErrorCode::NotFound,
+    );
+}
+
+#[test]
+fn copy_refuses_to_overwrite_unless_forced() {
+    let daemon = daemon();
+    add(&daemon, "a", command("true"));
+    add(&daemon, "b", command("false"));
+    let copy = |force| Message::Copy {
+        from: "a".into(),
+        to: "b".into(),
+        force,
+    };
+    fails(daemon.handle(copy(false)), ErrorCode::Conflict);
+    ok(daemon.handle(copy(true)));
+    assert_eq!(message(daemon.handle(copy(true))), "Unchanged: true");
+    let data = daemon.data.lock().expect("working mutex");
+    assert_eq!(data.commands["b"].command, "true");
+}
+
+#[test]
+fn list_and_status_page_through_the_sorted_names() {
+    let daemon = daemon();
+    for name in ["c", "a", "b"] {
+        add(&daemon, name, command("true"));
+    }
+    let page = Page {
+        offset: 1,
+        limit: Some(1),
+        ..Default::default()
+    };
+    let list = daemon.handle(Message::List { page: page.clone() });
+    assert_eq!(names(&list), ["b"]);
+    let status = daemon.handle(Message::Status {
+        page,
+        tree: true,
+        verbose: true,
+    });
+    assert_eq!(names(&status), ["b"]);
+}
+
+#[test]
+fn list_and_status_page_through_many_commands() {
+    let daemon = daemon();
+    let commands: HashMap<String, CommandDef> = (0..2000)
+        .map(|i| (format!("cmd-{i:04}"), command(&format!("sleep {i}"))))
+        .collect();
+    ok(daemon.handle(Message::AddMany { commands }));
+    let mut listed = Vec::new();
+    let mut statused = Vec::new();
+    for offset in (0..2000).step_by(150) {
+        let page = Page {
+            offset,
+            limit: Some(150),
+            ..Default::default()
+        };
+        let list = daemon.handle(Message::List { page: page.clone() });
+        let status = daemon.handle(Message::Status {
+            page,
+            tree: true,
+            verbose: false,
+        });
+        listed.extend(names(&list));
+        statused.extend(names(&status));
+    }
+    let expected: Vec<String> = (0..2000).map(|i| format!("cmd-{i:04}")).collect();
+    assert_eq!(listed, expected);
+    assert_eq!(statused, expected);
+}
+
+#[test]
+fn status_tree_counts_the_descendants() {
+    let daemon = daemon();
+    add(&daemon, "family", command("sleep 30 & sleep 30 & wait"));
+    ok(daemon.handle(Message::Start {
+        name: "family".into(),
+    }));
+    let children = || {
+        let status = daemon.handle(Message::Status {
+            page: Page::default(),
+            tree: true,
+            verbose: false,
+        });
+        let Response::Status { entries, .. } = status else {
+            panic!("expected the status");
+        };
+        entries[0].children
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while children() != Some(2) {
+        assert!(Instant::now() < deadline, "found {:?} children", children());
+        thread::sleep(Duration::from_millis(10));
+    }
+    // killing the shell would leave the children behind
+    let pid = pid_of(&daemon, "family").expect("running");
+    let children = fs::read_to_string(format!("/proc/{pid}/task/{pid}/children"));
+    for child in children.unwrap_or_default().split_whitespace() {
+        let child: i32 = child.parse().expect("a pid");
+        // SAFETY: a child of the process this test started
+        unsafe { libc::kill(child, libc::SIGKILL) };
+    }
+}
+
+#[test]
+fn search_matches_names_and_commands() {
+    let daemon = daemon();
+    add(&daemon, "web", command("python -m http.server"));
+    add(&daemon, "db", command("postgres"));
+    let found = daemon.handle(Message::Search {
+        term: "HTTP".into(),
+    });
+    assert_eq!(names(&found), ["web"]);
+}
+
+#[test]
+fn dump_and_info_describe_the_daemon() {
+    // the socket path in the info creates the state directory
+    let _files = on_disk();
+    let daemon = daemon();
+    add(&daemon, "web", command("true"));
+    let Response::Dump(state) = daemon.handle(Message::Dump) else {
+        panic!("expected the state");
+    };
+    assert!(state["commands"]["web"].is_object(), "{state}");
+    let Response::Info(info) = daemon.handle(Message::Info) else {
+        panic!("expected the settings");
+    };
+    assert!(info.ephemeral);
+    assert_eq!(info.pid, std::process::id());
+}
+
+#[test]
+fn a_missing_shell_is_a_spawn_error_naming_it() {
+    let _files = on_disk();
+    let daemon = TestDaemon(
+        Daemon::new_ephemeral()
+            .with_coalesce_window(Duration::ZERO)
+            .with_shell("/nonexistent/sh".into()),
+    );
+    add(&daemon, "once", command("true"));
+    let failed = fails(
+        daemon.handle(Message::Execute {
+            name: "once".into(),
+            force: false,
+        }),
+        ErrorCode::SpawnFailed,
+    );
+    assert!(
+        failed
+            .to_string()
+            .contains("shell '/nonexistent/sh' not found"),
+        "{failed}"
+    );
+    let Response::Info(info) = daemon.handle(Message::Info) else {
+        panic!("expected the settings");
+    };
+    assert_eq!(info.shell, "/nonexistent/sh");
+}
+
+#[test]
+fn identical_reads_share_a_response_until_something_changes() {
+    let daemon = TestDaemon(Daemon::new_ephemeral().with_coalesce_window(Duration::from_secs(60)));
+    add(&daemon, "a", command("true"));
+    let list = || {
+        names(&daemon.handle(Message::List {
+            page: Page::default(),
+        }))
+    };
+    assert_eq!(list(), ["a"]);
+    // added behind the daemon's back, only a request changing something shows it
+    let mut data = daemon.data.lock().expect("working mutex");
+    data.commands.insert("b".into(), command("true"));
+    drop(data);
+    ok(daemon.handle(Message::Alive));
+    assert_eq!(list(), ["a"]);
+    add(&daemon, "c", command("true"));
+    assert_eq!(list(), ["a", "b", "c"]);
+}
+
+#[test]
+fn health_reports_a_healthy_daemon() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    let Response::Health { status, .. } = daemon.handle(Message::Health) else {
+        panic!("expected the health");
+    };
+    assert_eq!(status, HealthStatus::Healthy);
+}
+
+#[test]
+fn history_records_finished_runs() {
+    let daemon = daemon();
+    add(&daemon, "once", command("exit 3"));
+    let Response::History { runs, .. } = daemon.handle(Message::History {
+        name: "once".into(),
+    }) else {
+        panic!("expected the history");
+    };
+    assert!(runs.is_empty());
+    ok(daemon.handle(Message::Execute {
+        name: "once".into(),
+        force: false,
+    }));
+    let Response::History { runs, .. } = daemon.handle(Message::History {
+        name: "once".into(),
+    }) else {
+        panic!("expected the history");
+    };
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].exit_code, Some(3));
+    fails(
+        daemon.handle(Message::History {
+            name: "nope".into(),
+        }),
+        ErrorCode::NotFound,
+    );
+}
+
+#[test]
+fn execute_waits_for_the_process() {
+    let daemon = daemon();
+    add(&daemon, "once", command("true"));
+    let executed = message(daemon.handle(Message::Execute {
+        name: "once".into(),
+        force: false,
+    }));
+    assert_eq!(executed, "once executed successfully");
+    assert_eq!(pid_of(&daemon, "once"), None);
+    fails(
+        daemon.handle(Message::Execute {
+            name: "nope".into(),
+            force: false,
+        }),
+        ErrorCode::NotFound,
+    );
+}
+
+#[test]
+fn a_failing_post_stop_hook_follows_the_exit() {
+    let daemon = daemon();
+    let once = CommandDef {
+        post_stop: Some("exit 3".into()),
+        ..command("true")
+    };
+    add(&daemon, "once", once);
+    let failed = fails(
+        daemon.handle(Message::Execute {
+            name: "once".into(),
+            force: false,
+        }),
+        ErrorCode::HookFailed,
+    )
+    .to_string();
+    assert!(failed.contains("once executed successfully"), "{failed}");
+}
+
+#[test]
+fn execute_attached_streams_the_output() {
+    let daemon = daemon();
+    add(&daemon, "hello", command("echo hello; echo oops >&2"));
+    let mut lines = Vec::new();
+    let exited = daemon.handle_streaming(
+        Message::ExecuteAttached {
+            name: "hello".into(),
+            force: false,
+        },
+        &mut |frame| {
+            if let Response::Output { stderr, line, .. } = frame {
+                lines.push((*stderr, line.clone()));
+            }
+            true
+        },
+    );
+    let Response::Exited { code, .. } = exited else {
+        panic!("expected the exit, got {exited}");
+    };
+    assert_eq!(code, Some(0));
+    lines.sort();
+    assert_eq!(
+        lines,
+        [(false, "hello\n".to_string()), (true, "oops\n".to_string())]
+    );
+}
+
+/// The `line` and `raw` of an [`Response::Output`] or [`Response::Line`] after
+/// going over the wire with `codec`.
+fn over_the_wire(codec: Codec, frame: &Response) -> (String, Option<Vec<u8>>) {
+    let decoded: Response = codec.decode(&codec.encode(frame)).expect("decodes");
+    match decoded {
+        Response::Output { line, raw, .. } | Response::Line { line, raw, .. } => (line, raw),
+        response => panic!("expected a line, got {response}"),
+    }
+}
+
+#[test]
+fn execute_attached_passes_on_invalid_utf8_unchanged() {
+    let daemon = daemon();
+    add(&daemon, "binary", command(r"printf 'caf\351\nfine\n'"));
+    let mut frames = Vec::new();
+    daemon.handle_streaming(
+        Message::ExecuteAttached {
+            name: "binary".into(),
+            force: false,
+        },
+        &mut |frame| {
+            if matches!(frame, Response::Output { .. }) {
+                frames.push(frame.clone());
+            }
+            true
+        },
+    );
+    assert_eq!(frames.len(), 2);
+    for codec in [Codec::Json, Codec::Msgpack] {
+        let (line, raw) = over_the_wire(codec, &frames[0]);
+        assert_eq!(line, "caf\u{fffd}\n");
+        assert_eq!(raw.as_deref(), Some(&b"caf\xe9\n"[..]));
+        assert_eq!(over_the_wire(codec, &frames[1]), ("fine\n".into(), None));
+    }
+}
+
+#[test]
+fn follow_passes_on_invalid_utf8_unchanged() {
+    let daemon = daemon();
+    let binary = CommandDef {
+        capture_output: true,
+        ..command(r"while true; do printf '\377\n'; sleep 0.05; done")
+    };
+    add(&daemon, "binary", binary);
+    ok(daemon.handle(Message::Start {
+        name: "binary".into(),
+    }));
+    let mut followed = None;
+    daemon.handle_streaming(
+        Message::Follow {
+            names: vec!["binary".into()],
+        },
+        &mut |frame| {
+            followed = Some(frame.clone());
+            !matches!(frame, Response::Line { .. })
+        },
+    );
+    let followed = followed.expect("a line was followed");
+    for codec in [Codec::Json, Codec::Msgpack] {
+        let (line, raw) = over_the_wire(codec, &followed);
+        assert_eq!(line, "\u{fffd}\n");
+        assert_eq!(raw.as_deref(), Some(&b"\xff\n"[..]));
+    }
+}
+
+#[test]
+fn nofile_limit_is_enforced_on_the_child() {
+    let daemon = daemon();
+    let limited = CommandDef {
+        rlimit_nofile: Some(8),
+        ..command(
+            "ulimit -n; python3 -c \"import os\nfor _ in range(8): os.open('/dev/null', 0)\" \
+             || echo refused",
+        )
+    };
+    add(&daemon, "limited", limited);
+    let mut lines = Vec::new();
+    let exited = daemon.handle_streaming(
+        Message::ExecuteAttached {
+            name: "limited".into(),
+            force: false,
+        },
+        &mut |frame| {
+            if let Response::Output {
+                stderr: false,
+                line,
+                ..
+            } = frame
+            {
+                lines.push(line.clone());
+            }
+            true
+        },
+    );
+    assert_eq!(lines, ["8\n", "refused\n"]);
+    assert!(matches!(exited, Response::Exited { code: Some(0), .. }));
+}
+
+#[test]
+fn rlimits_beyond_what_the_kernel_allows_are_refused() {
+    let daemon = daemon();
+    let unlimited = CommandDef {
+        rlimit_nofile: Some(u64::MAX),
+        ..command("true")
+    };
+    add(&daemon, "unlimited", unlimited);
+    let refused = daemon.handle(Message::Execute {
+        name: "unlimited".into(),
+        force: false,
+    });
+    assert!(refused.failed());
+    assert!(refused.to_string().contains("nofile limit"), "{refused}");
+    assert_eq!(pid_of(&daemon, "unlimited"), None);
+}
+
+#[test]
+fn follow_passes_on_lines_of_capturing_commands() {
+    let daemon = daemon();
+    let chatty = CommandDef {
+        capture_output: true,
+        ..command("while true; do echo tick; sleep 0.05; done")
+    };
+    add(&daemon, "chatty", chatty);
+    add(&daemon, "quiet", command("sleep 30"));
+    fails(
+        daemon.handle(Message::Follow {
+            names: vec!["quiet".into()],
+        }),
+        ErrorCode::Invalid,
+    );
+    ok(daemon.handle(Message::Start {
+        name: "chatty".into(),
+    }));
+    let mut followed = None;
+    let stopped = daemon.handle_streaming(
+        Message::Follow {
+            names: vec!["chatty".into()],
+        },
+        &mut |frame| {
+            if let Response::Line { name, line, .. } = frame {
+                followed = Some((name.clone(), line.clone()));
+            }
+            false
+        },
+    );
+    assert_eq!(message(stopped), "stopped following");
+    assert_eq!(followed, Some(("chatty".into(), "tick\n".into())));
+}
+
+#[test]
+fn concurrent_toggles_run_one_process() {
+    let daemon = daemon();
+    let line = marked_sleep(105);
+    add(&daemon, "web", command(&line));
+    // serialised, the toggles alternate between starting and killing, an odd
+    // number of them leaves it running
+    let toggles = 7;
+    let barrier = Barrier::new(toggles);
+    let (done, finished) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..toggles {
+            let (done, daemon, barrier) = (done.clone(), &daemon, &barrier);
+            scope.spawn(move || {
+                barrier.wait();
+                let response = daemon.handle(Message::Toggle { name: "web".into() });
+                let _ = done.send(response);
+            });
+        }
+        for _ in 1..toggles {
+            let response = finished
+                .recv_timeout(Duration::from_secs(10))
+                .expect("all toggles but the last start return");
+            assert!(!response.failed(), "{response}");
+            assert!(live(&line) <= 1);
+        }
+        // the last toggle may only now get to start it
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while pid_of(&daemon, "web").is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(pid_of(&daemon, "web").is_some());
+        assert_eq!(settled_live(&line), 1);
+        daemon.kill_all();
+    });
+}
+
+#[test]
+fn concurrent_executes_run_one_process() {
+    let daemon = daemon();
+    let line = marked_sleep(1051);
+    add(&daemon, "web", command(&line));
+    let executes = 8;
+    let barrier = Barrier::new(executes);
+    let (done, finished) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..executes {
+            let (done, daemon, barrier) = (done.clone(), &daemon, &barrier);
+            scope.spawn(move || {
+                barrier.wait();
+                let response = daemon.handle(Message::Execute {
+                    name: "web".into(),
+                    force: false,
+                });
+                let _ = done.send(response);
+            });
+        }
+        for _ in 1..executes {
+            let response = finished
+                .recv_timeout(Duration::from_secs(10))
+                .expect("all executes but one are refused");
+            assert_eq!(response.error_code(), Some(ErrorCode::AlreadyRunning));
+        }
+        assert_eq!(settled_live(&line), 1);
+        daemon.kill_all();
+    });
+}
+
+fn depending(line: &str, depends_on: &[&str]) -> CommandDef {
+    CommandDef {
+        depends_on: depends_on.iter().map(|name| name.to_string()).collect(),
+        ..command(line)
+    }
+}
+
+fn strings(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+#[test]
+fn dependency_order_puts_dependencies_first() {
+    let commands = HashMap::from([
+        ("web".to_string(), depending("true", &["api"])),
+        ("api".to_string(), depending("true", &["db"])),
+        ("db".to_string(), command("true")),
+    ]);
+    let order = dependency_order(&commands, &strings(&["web"])).expect("no cycle");
+    assert_eq!(order, ["db", "api", "web"]);
+    let order = dependency_order(&commands, &strings(&["web", "db", "api"])).expect("no cycle");
+    assert_eq!(order, ["db", "api", "web"]);
+}
+
+#[test]
+fn dependency_order_names_the_cycle() {
+    let commands = HashMap::from([
+        ("a".to_string(), depending("true", &["b"])),
+        ("b".to_string(), depending("true", &["c"])),
+        ("c".to_string(), depending("true", &["a"])),
+    ]);
+    let (code, reason) = dependency_order(&commands, &strings(&["a"])).expect_err("a cycle");
+    assert_eq!(code, ErrorCode::Conflict);
+    assert_eq!(reason, "dependency cycle a -> b -> c -> a");
+    let (_, reason) = dependency_order(&commands, &strings(&["b"])).expect_err("a cycle");
+    assert_eq!(reason, "dependency cycle b -> c -> a -> b");
+}
+
+#[test]
+fn dependency_order_reports_unknown_dependencies() {
+    let commands = HashMap::from([("web".to_string(), depending("true", &["db"]))]);
+    let (code, reason) = dependency_order(&commands, &strings(&["web"])).expect_err("unknown");
+    assert_eq!(code, ErrorCode::NotFound);
+    assert_eq!(reason, "web depends on unknown command db");
+}
+
+/// Whether `pid` set up a handler for `signal` yet.
+fn catches(pid: u32, signal: i32) -> bool {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("SigCgt:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .is_some_and(|mask| mask & (1 << (signal - 1)) != 0)
+}
+
+#[test]
+fn stop_all_stops_dependents_first() {
+    let daemon = daemon();
+    let stopped = std::env::temp_dir().join(format!("uniq-proc-stopped-{}", std::process::id()));
+    let _ = fs::remove_file(&stopped);
+    // each notes when it got SIGTERM, and takes a moment to exit so a kill that
+    // did not wait for it would let the next one overtake it
+    let noting = |name: &str| {
+        format!(
+            "trap 'echo {name} >> {}; sleep 0.1; kill $!; exit' TERM; sleep 30 & wait",
+            stopped.display()
+        )
+    };
+    add(&daemon, "db", command(&noting("db")));
+    add(&daemon, "api", depending(&noting("api"), &["db"]));
+    add(&daemon, "web", depending(&noting("web"), &["api"]));
+    for name in ["db", "api", "web"] {
+        ok(daemon.handle(Message::Start { name: name.into() }));
+        let pid = pid_of(&daemon, name).expect("running");
+        while !catches(pid, libc::SIGTERM) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    let Response::Bulk(results) = ok(daemon.handle(Message::StopAll)) else {
+        panic!("expected per command results");
+    };
+    let stopped_order = fs::read_to_string(&stopped).expect("the commands noted their stop");
+    let _ = fs::remove_file(&stopped);
+    for name in ["db", "api", "web"] {
+        ok(results[name].clone());
+        assert_eq!(pid_of(&daemon, name), None);
+    }
+    assert_eq!(stopped_order, "web\napi\ndb\n");
+}
+
+#[test]
+fn start_all_skips_dependents_of_a_failed_start() {
+    let daemon = daemon();
+    let db = CommandDef {
+        pre_start: Some("exit 1".into()),
+        ..command("sleep 30")
+    };
+    add(&daemon, "db", db);
+    add(&daemon, "api", depending("sleep 30", &["db"]));
+    let Response::Bulk(results) = fails(daemon.handle(Message::StartAll), ErrorCode::SpawnFailed)
+    else {
+        panic!("expected per command results");
+    };
+    assert_eq!(
+        results["api"].to_string(),
+        "not started, dependency db failed"
+    );
+    assert_eq!(pid_of(&daemon, "api"), None);
+}
+
+#[test]
+fn stop_all_refuses_cycles() {
+    let daemon = daemon();
+    add(&daemon, "a", depending("sleep 30", &["b"]));
+    add(&daemon, "b", depending("sleep 30", &["a"]));
+    let refused = fails(daemon.handle(Message::StopAll), ErrorCode::Conflict);
+    assert_eq!(
+        refused.to_string(),
+        "Nothing stopped: dependency cycle a -> b -> a"
+    );
+}
+
+#[test]
+fn start_runs_in_the_background() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    assert!(pid_of(&daemon, "web").is_some());
+    fails(
+        daemon.handle(Message::Start {
+            name: "nope".into(),
+        }),
+        ErrorCode::NotFound,
+    );
+}
+
+#[test]
+fn start_all_and_start_matching() {
+    let daemon = daemon();
+    let labelled = |env: &str| CommandDef {
+        labels: HashMap::from([("env".to_string(), env.to_string())]),
+        ..command("sleep 30")
+    };
+    add(&daemon, "prod", labelled("prod"));
+    add(&daemon, "dev", labelled("dev"));
+    let selector: Selector = "env=prod".parse().expect("valid selector");
+    let started = daemon.handle(Message::StartMatching { selector });
+    assert_eq!(names(&ok(started)), ["prod"]);
+    assert!(pid_of(&daemon, "dev").is_none());
+    let none: Selector = "env=staging".parse().expect("valid selector");
+    fails(
+        daemon.handle(Message::StartMatching { selector: none }),
+        ErrorCode::NotFound,
+    );
+    let started = daemon.handle(Message::StartAll);
+    assert_eq!(names(&ok(started)), ["dev", "prod"]);
+    assert!(pid_of(&daemon, "dev").is_some());
+}
+
+#[test]
+fn stop_all_kills_every_process() {
+    let daemon = daemon();
+    add(&daemon, "a", command("sleep 30"));
+    add(&daemon, "b", command("sleep 30"));
+    ok(daemon.handle(Message::Start { name: "a".into() }));
+    let Response::Bulk(results) = ok(daemon.handle(Message::StopAll)) else {
+        panic!("expected per command results");
+    };
+    assert_eq!(message(results["a"].clone()), "Successfully killed a");
+    assert_eq!(message(results["b"].clone()), "already stopped");
+    assert_eq!(pid_of(&daemon, "a"), None);
+}
+
+#[test]
+fn kill_stops_the_process() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    let killed = message(daemon.handle(Message::Kill { name: "web".into() }));
+    assert_eq!(killed, "Successfully killed web");
+    fails(
+        daemon.handle(Message::Kill { name: "web".into() }),
+        ErrorCode::NotRunning,
+    );
+}
+
+#[test]
+fn kill_prunes_a_process_that_already_exited() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    let mut gone = Command::new("true").spawn().expect("can spawn true");
+    let pid = gone.id();
+    gone.wait().expect("true exits");
+    // tracked, but it exited and was reaped before the kill came
+    let record = state::ProcRecord {
+        pid,
+        start_time: None,
+        argv: Vec::new(),
+    };
+    daemon
+        .data
+        .lock()
+        .expect("working mutex")
+        .procs
+        .insert("web".into(), record.into());
+    let killed = message(daemon.handle(Message::Kill { name: "web".into() }));
+    assert_eq!(killed, format!("web (pid {pid}) had already exited"));
+    assert_eq!(pid_of(&daemon, "web"), None);
+    fails(
+        daemon.handle(Message::Kill { name: "web".into() }),
+        ErrorCode::NotRunning,
+    );
+}
+
+#[test]
+fn kill_succeeds_on_a_process_killed_meanwhile() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    let pid = pid_of(&daemon, "web").expect("running");
+    // SAFETY: the child this test just started
+    unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+    ok(daemon.handle(Message::Kill { name: "web".into() }));
+    assert_eq!(pid_of(&daemon, "web"), None);
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+}
+
+#[test]
+fn execute_is_refused_while_a_kill_waits_for_the_exit() {
+    let daemon = daemon();
+    let slow = command("trap 'sleep 1; exit 0' TERM; while :; do sleep 0.1; done");
+    add(&daemon, "slow", slow);
+    ok(daemon.handle(Message::Start {
+        name: "slow".into(),
+    }));
+    // the shell has to set its trap before the SIGTERM comes
+    thread::sleep(Duration::from_millis(300));
+    thread::scope(|scope| {
+        let killed = scope.spawn(|| {
+            daemon.handle(Message::Kill {
+                name: "slow".into(),
+            })
+        });
+        let killing = || {
+            let data = daemon.data.lock().expect("working mutex");
+            data.killing.contains_key("slow")
+        };
+        while !killing() {
+            assert!(!killed.is_finished(), "the kill did not wait for the exit");
+            thread::sleep(Duration::from_millis(10));
+        }
+        fails(
+            daemon.handle(Message::Execute {
+                name: "slow".into(),
+                force: false,
+            }),
+            ErrorCode::AlreadyRunning,
+        );
+        assert_eq!(pid_of(&daemon, "slow"), None);
+        ok(killed.join().expect("kill returns"));
+    });
+    ok(daemon.handle(Message::Start {
+        name: "slow".into(),
+    }));
+}
+
+#[test]
+fn restart_replaces_the_process() {
+    let daemon = daemon();
+    add(&daemon, "once", command("true"));
+    let Response::Sequence(steps) = daemon.handle(Message::Restart {
+        name: "once".into(),
+    }) else {
+        panic!("expected the kill and the execute");
+    };
+    assert_eq!(message(steps[0].clone()), "once was not running");
+    assert_eq!(message(steps[1].clone()), "once executed successfully");
+}
+
+#[test]
+fn restart_starts_a_stopped_command() {
+    let daemon = daemon();
+    add(&daemon, "web", command("exit 0"));
+    let restarted = daemon.handle(Message::Restart { name: "web".into() });
+    assert_eq!(restarted.exit_code(), 0, "{restarted}");
+    let restarts = daemon.data.lock().expect("working mutex").counters.restarts;
+    assert_eq!(restarts, 0, "nothing was replaced");
+}
+
+/// A command listening on a free port of localhost that on SIGTERM keeps the
+/// port for a moment before it exits, the port is returned along.
+fn lingering_listener() -> (String, u16) {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("can find a free port")
+        .port();
+    let script = format!(
+        "import signal, socket, sys, time\n\
+         s = socket.socket()\n\
+         s.bind(('127.0.0.1', {port}))\n\
+         s.listen()\n\
+         signal.signal(signal.SIGTERM, lambda *_: (time.sleep(0.3), sys.exit(0)))\n\
+         time.sleep(30)\n"
+    );
+    (format!("exec python3 -c \"{script}\""), port)
+}
+
+/// Waits until `name` runs a process other than `old` and `old` is gone, then
+/// checks that the new process is the one listening on `port`: had they
+/// overlapped, it could not have bound the port and exited.
+#[track_caller]
+fn wait_for_listener(daemon: &Daemon, name: &str, old: u32, port: u16) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let pid = loop {
+        assert!(Instant::now() < deadline, "{name} never started again");
+        match pid_of(daemon, name) {
+            Some(pid) if pid != old => break pid,
+            _ => thread::sleep(Duration::from_millis(10)),
+        }
+        let Response::History { runs, .. } = daemon.handle(Message::History { name: name.into() })
+        else {
+            panic!("expected the history");
+        };
+        if let Some(run) = runs.iter().find(|run| run.pid != old) {
+            panic!("the new process exited: {:?} {:?}", run.end, run.exit_code);
+        }
+    };
+    // reaped or a zombie, the old process no longer holds the port
+    while fs::read(format!("/proc/{old}/cmdline")).is_ok_and(|cmdline| !cmdline.is_empty()) {
+        assert!(Instant::now() < deadline, "pid {old} never exited");
+        thread::sleep(Duration::from_millis(10));
+    }
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "{name} never listened");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(pid_of(daemon, name), Some(pid), "the new process exited");
+}
+
+#[test]
+fn restart_waits_for_the_old_process_to_free_its_port() {
+    let daemon = daemon();
+    let (line, port) = lingering_listener();
+    add(&daemon, "web", command(&line));
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    let old = pid_of(&daemon, "web").expect("running");
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::scope(|scope| {
+        let restarted = scope.spawn(|| daemon.handle(Message::Restart { name: "web".into() }));
+        wait_for_listener(&daemon, "web", old, port);
+        daemon.kill_all();
+        let Response::Sequence(steps) = restarted.join().expect("restart returns") else {
+            panic!("expected the kill and the execute");
+        };
+        ok(steps[0].clone());
+    });
+}
+
+#[test]
+fn execute_after_kill_finds_the_port_free() {
+    let daemon = daemon();
+    let (line, port) = lingering_listener();
+    add(&daemon, "web", command(&line));
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    let old = pid_of(&daemon, "web").expect("running");
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    ok(daemon.handle(Message::Kill { name: "web".into() }));
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            daemon.handle(Message::Execute {
+                name: "web".into(),
+                force: false,
+            })
+        });
+        wait_for_listener(&daemon, "web", old, port);
+        daemon.kill_all();
+    });
+}
+
+#[test]
+fn execute_beyond_the_process_limit_is_refused() {
+    let daemon = TestDaemon(
+        Daemon::new_ephemeral()
+            .with_coalesce_window(Duration::ZERO)
+            .with_max_procs(Some(2), false),
+    );
+    for name in ["a", "b", "c"] {
+        add(&daemon, name, command("sleep 30"));
+    }
+    ok(daemon.handle(Message::Start { name: "a".into() }));
+    ok(daemon.handle(Message::Start { name: "b".into() }));
+    let refused = daemon.handle(Message::Execute {
+        name: "c".into(),
+        force: false,
+    });
+    fails(refused, ErrorCode::LimitReached);
+    assert_eq!(pid_of(&daemon, "c"), None);
+    ok(daemon.handle(Message::Kill { name: "a".into() }));
+    ok(daemon.handle(Message::Start { name: "c".into() }));
+}
+
+#[test]
+fn execute_beyond_the_process_limit_queues_with_queue() {
+    let daemon = TestDaemon(
+        Daemon::new_ephemeral()
+            .with_coalesce_window(Duration::ZERO)
+            .with_max_procs(Some(1), true),
+    );
+    add(&daemon, "a", command("sleep 30"));
+    add(&daemon, "b", command("sleep 30"));
+    ok(daemon.handle(Message::Start { name: "a".into() }));
+    thread::scope(|scope| {
+        let queued = scope.spawn(|| {
+            daemon.handle(Message::Execute {
+                name: "b".into(),
+                force: false,
+            })
+        });
+        let place = || {
+            let data = daemon.data.lock().expect("working mutex");
+            data.queued.iter().position(|queued| queued == "b")
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while place().is_none() {
+            assert!(Instant::now() < deadline, "b never queued");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pid_of(&daemon, "b"), None);
+        ok(daemon.handle(Message::Kill { name: "a".into() }));
+        while pid_of(&daemon, "b").is_none() {
+            assert!(Instant::now() < deadline, "b never started");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(place(), None);
+        daemon.kill_all();
+        queued.join().expect("execute returns");
+    });
+}
+
+#[test]
+fn toggle_kills_a_running_process() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    let toggled = message(daemon.handle(Message::Toggle { name: "web".into() }));
+    assert_eq!(toggled, "Successfully killed web");
+    assert_eq!(pid_of(&daemon, "web"), None);
+}
+
+#[test]
+fn disown_stops_tracking_without_killing() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    let pid = pid_of(&daemon, "web").expect("running");
+    ok(daemon.handle(Message::Disown { name: "web".into() }));
+    assert_eq!(pid_of(&daemon, "web"), None);
+    // SAFETY: signal 0 only checks whether the pid exists
+    assert_eq!(unsafe { libc::kill(pid as i32, 0) }, 0);
+    // SAFETY: the disowned child of this test
+    unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+}
+
+#[test]
+fn signal_reaches_the_process() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    fails(
+        daemon.handle(Message::Signal {
+            name: "web".into(),
+            signal: libc::SIGTERM,
+        }),
+        ErrorCode::NotRunning,
+    );
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    ok(daemon.handle(Message::Signal {
+        name: "web".into(),
+        signal: libc::SIGTERM,
+    }));
+}
+
+#[test]
+fn reset_backoff_of_a_command_never_restarted() {
+    let daemon = daemon();
+    add(&daemon, "web", command("true"));
+    let reset = message(daemon.handle(Message::ResetBackoff { name: "web".into() }));
+    assert_eq!(reset, "web has not been restarted automatically");
+    fails(
+        daemon.handle(Message::ResetBackoff {
+            name: "nope".into(),
+        }),
+        ErrorCode::NotFound,
+    );
+}
+
+/// Adds `name` restarting as `restart` says, labelled `env=<env>`, which automatic
+/// restarts gave up on.
+fn given_up(daemon: &Daemon, name: &str, restart: RestartPolicy, env: &str) {
+    let command = CommandDef {
+        restart,
+        labels: HashMap::from([("env".to_string(), env.to_string())]),
+        ..command("sleep 30")
+    };
+    add(daemon, name, command);
+    let state = RestartState {
+        count: 5,
+        given_up: true,
+        ..Default::default()
+    };
+    let mut data = daemon.data.lock().expect("working mutex");
+    data.restarts.insert(name.into(), state);
+}
+
+#[test]
+fn reset_backoff_starts_a_command_given_up_on() {
+    let daemon = daemon();
+    given_up(&daemon, "web", RestartPolicy::OnFailure, "prod");
+    let Response::Sequence(steps) = daemon.handle(Message::ResetBackoff { name: "web".into() })
+    else {
+        panic!("expected the reset and the start");
+    };
+    assert_eq!(
+        message(steps[0].clone()),
+        "Reset the restart limit of web, starting it again"
+    );
+    ok(steps[1].clone());
+    assert!(pid_of(&daemon, "web").is_some());
+    let data = daemon.data.lock().expect("working mutex");
+    assert!(!data.restarts["web"].given_up);
+    assert_eq!(data.restarts["web"].count, 0);
+}
+
+#[test]
+fn reset_backoff_leaves_commands_that_never_restart_stopped() {
+    let daemon = daemon();
+    given_up(&daemon, "web", RestartPolicy::Never, "prod");
+    let reset = message(daemon.handle(Message::ResetBackoff { name: "web".into() }));
+    assert!(reset.contains("once started"), "{reset}");
+    assert_eq!(pid_of(&daemon, "web"), None);
+}
+
+#[test]
+fn reset_backoff_ignores_case_when_asked_to() {
+    let daemon = TestDaemon(
+        Daemon::new_ephemeral()
+            .with_coalesce_window(Duration::ZERO)
+            .with_ignore_case(true),
+    );
+    given_up(&daemon, "Web", RestartPolicy::Always, "prod");
+    let reset = daemon.handle(Message::ResetBackoff { name: "web".into() });
+    assert!(matches!(reset, Response::Sequence(_)), "{reset}");
+    assert!(pid_of(&daemon, "Web").is_some());
+    add(&daemon, "WEB", command("true"));
+    fails(
+        daemon.handle(Message::ResetBackoff { name: "web".into() }),
+        ErrorCode::Ambiguous,
+    );
+}
+
+#[test]
+fn reset_backoff_all_and_matching() {
+    let daemon = daemon();
+    given_up(&daemon, "prod", RestartPolicy::OnFailure, "prod");
+    given_up(&daemon, "dev", RestartPolicy::OnFailure, "dev");
+    add(&daemon, "calm", command("true"));
+    let selector: Selector = "env=prod".parse().expect("valid selector");
+    let reset = daemon.handle(Message::ResetBackoffMatching { selector });
+    assert_eq!(names(&ok(reset)), ["prod"]);
+    assert!(pid_of(&daemon, "prod").is_some());
+    assert_eq!(pid_of(&daemon, "dev"), None);
+    let none: Selector = "env=staging".parse().expect("valid selector");
+    fails(
+        daemon.handle(Message::ResetBackoffMatching { selector: none }),
+        ErrorCode::NotFound,
+    );
+    let reset = daemon.handle(Message::ResetBackoffAll);
+    assert_eq!(names(&ok(reset)), ["dev", "prod"]);
+    assert!(pid_of(&daemon, "dev").is_some());
+}
+
+#[test]
+fn disabled_commands_only_start_when_forced() {
+    let daemon = daemon();
+    add(&daemon, "once", command("true"));
+    ok(daemon.handle(Message::SetEnabled {
+        name: "once".into(),
+        enabled: false,
+    }));
+    fails(
+        daemon.handle(Message::Execute {
+            name: "once".into(),
+            force: false,
+        }),
+        ErrorCode::Disabled,
+    );
+    ok(daemon.handle(Message::Execute {
+        name: "once".into(),
+        force: true,
+    }));
+}
+
+#[test]
+fn systemd_unit_renders_the_command() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    let unit = message(daemon.handle(Message::SystemdUnit { name: "web".into() }));
+    assert!(unit.contains("ExecStart="), "{unit}");
+    assert!(unit.contains("Restart=no"), "{unit}");
+    assert!(unit.contains("ExecStart=/bin/sh -c"), "{unit}");
+}
+
+#[test]
+fn systemd_unit_sets_the_environment_and_shell() {
+    let daemon = TestDaemon(
+        Daemon::new_ephemeral()
+            .with_coalesce_window(Duration::ZERO)
+            .with_shell("bash".into()),
+    );
+    let env_file = std::env::temp_dir().join(format!("uniq-proc-unit-{}", std::process::id()));
+    std::fs::write(&env_file, "PORT=8080\n").expect("can write the env file");
+    let mut web = command("serve $PORT");
+    web.env = HashMap::from([("GREETING".into(), "50% \"hi\" $HOME".into())]);
+    web.env_file = Some(env_file.clone());
+    add(&daemon, "web", web);
+    let unit = message(daemon.handle(Message::SystemdUnit { name: "web".into() }));
+    assert!(
+        unit.contains(&format!("EnvironmentFile={}", env_file.display())),
+        "{unit}"
+    );
+    assert!(
+        unit.contains(r#"Environment="GREETING=50%% \"hi\" $HOME""#),
+        "{unit}"
+    );
+    assert!(unit.contains("ExecStart=bash -c"), "{unit}");
+
+    let mut clashing = command("serve");
+    clashing.env = HashMap::from([("PORT".into(), "9090".into())]);
+    clashing.env_file = Some(env_file.clone());
+    add(&daemon, "clashing", clashing);
+    fails(
+        daemon.handle(Message::SystemdUnit {
+            name: "clashing".into(),
+        }),
+        ErrorCode::Invalid,
+    );
+    let mut expanding = command("serve");
+    expanding.expand_env = ExpandEnv::Strict;
+    add(&daemon, "expanding", expanding);
+    fails(
+        daemon.handle(Message::SystemdUnit {
+            name: "expanding".into(),
+        }),
+        ErrorCode::Invalid,
+    );
+    std::fs::remove_file(env_file).ok();
+}
+
+#[test]
+fn capture_registers_a_running_process() {
+    let daemon = daemon();
+    let mut child = Command::new("sleep")
+        .arg("30")
+        .spawn()
+        .expect("can spawn sleep");
+    // the command line only shows once the child has exec'd
+    thread::sleep(Duration::from_millis(50));
+    let captured = daemon.handle(Message::Capture {
+        pid: child.id(),
+        name: "napping".into(),
+        adopt: false,
+    });
+    let _ = child.kill();
+    let _ = child.wait();
+    ok(captured);
+    let data = daemon.data.lock().expect("working mutex");
+    assert_eq!(data.commands["napping"].command, "sleep 30");
+    assert!(!data.procs.contains_key("napping"));
+}
+
+#[test]
+fn env_renders_shell_exports() {
+    let daemon = daemon();
+    let with_env = CommandDef {
+        env: HashMap::from([("GREETING".to_string(), "hi there".to_string())]),
+        ..command("true")
+    };
+    add(&daemon, "web", with_env);
+    let exports = message(daemon.handle(Message::Env {
+        name: "web".into(),
+        format: ShellSyntax::Sh,
+    }));
+    assert!(exports.contains("export GREETING='hi there'"), "{exports}");
+}
+
+#[test]
+fn reset_clears_what_it_is_asked_to() {
+    let daemon = daemon();
+    add(&daemon, "web", command("true"));
+    ok(daemon.handle(Message::Reset {
+        commands: false,
+        procs: true,
+        counters: true,
+    }));
+    assert_eq!(
+        names(&daemon.handle(Message::List {
+            page: Page::default()
+        })),
+        ["web"]
+    );
+    ok(daemon.handle(Message::Reset {
+        commands: true,
+        procs: false,
+        counters: false,
+    }));
+    assert!(names(&daemon.handle(Message::List {
+        page: Page::default()
+    }))
+    .is_empty());
+}
+
+#[test]
+fn shutdown_kills_the_processes_unless_kept() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    ok(daemon.handle(Message::Shutdown {
+        keep_running: false,
+    }));
+    assert_eq!(pid_of(&daemon, "web"), None);
+}
+
+#[test]
+fn wait_ready_reports_the_outcome() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    let outcome = |daemon: &Daemon| match daemon.handle(Message::WaitReady {
+        name: "web".into(),
+        timeout: Some(1),
+    }) {
+        Response::Readiness { outcome, .. } => outcome,
+        response => panic!("expected the readiness, got {response}"),
+    };
+    assert_eq!(outcome(&daemon), ReadyOutcome::NotRunning);
+    ok(daemon.handle(Message::Start { name: "web".into() }));
+    assert_eq!(outcome(&daemon), ReadyOutcome::Ready);
+}
+
+#[test]
+fn alive_answers() {
+    assert!(matches!(daemon().handle(Message::Alive), Response::Alive));
+}
+
+static ON_DISK: Mutex<()> = Mutex::new(());
+
+/// Keeps the temporary directory of [`on_disk`] to one test, and removes it after.
+struct OnDisk {
+    dir: &'static Path,
+    _guard: std::sync::MutexGuard<'static, ()>,
+}
+
+impl Drop for OnDisk {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.dir);
+    }
+}
+
+/// Points the config and state files of this test process into an empty
+/// temporary directory.
+fn on_disk() -> OnDisk {
+    static DIR: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+    let dir = DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("uniq-proc-test-{}", std::process::id()));
+        set_config_path(&dir.join("config.json"));
+        std::env::set_var("UNIQ_PROC_STATE_DIR", &dir);
+        dir
+    });
+    let guard = ON_DISK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).expect("can create the test directory");
+    OnDisk { dir, _guard: guard }
+}
+
+#[test]
+fn added_commands_survive_a_restart() {
+    let _files = on_disk();
+    let daemon = Daemon::new(false).with_coalesce_window(Duration::ZERO);
+    add(&daemon, "web", command("sleep 30"));
+    drop(daemon);
+
+    let restarted = Daemon::new(true).with_coalesce_window(Duration::ZERO);
+    assert_eq!(
+        names(&restarted.handle(Message::List {
+            page: Page::default()
+        })),
+        ["web"]
+    );
+    assert!(DaemonState::new(false).commands.contains_key("web"));
+
+    // without a config the state file stands in for it
+    fs::remove_file(DaemonState::get_config_path()).expect("the config was written");
+    assert!(DaemonState::new(true).commands.contains_key("web"));
+}
+
+#[test]
+fn config_watcher_reloads_only_external_edits() {
+    let _files = on_disk();
+    let daemon = Daemon::new(false).with_coalesce_window(Duration::ZERO);
+    add(&daemon, "web", command("sleep 30"));
+    let (watcher, events) = watch_config_file().expect("can watch the config");
+    let reloads = || daemon.config_reloads.load(Ordering::SeqCst);
+    let own_write = thread::scope(|scope| {
+        scope.spawn(|| daemon.reload_on_changes(watcher, events));
+        add(&daemon, "db", command("sleep 30"));
+        thread::sleep(WATCH_DEBOUNCE * 3);
+        let own_write = reloads();
+
+        let config = serde_json::json!({
+            "version": CONFIG_VERSION,
+            "commands": {"edited": {"command": "from an editor"}},
+        });
+        fs::write(DaemonState::get_config_path(), config.to_string())
+            .expect("can write the config");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while reloads() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        // before asserting, a failing test would wait for the watching thread for good
+        daemon.stopping.store(true, Ordering::SeqCst);
+        own_write
+    });
+    assert_eq!(own_write, 0, "the daemon's own write was reloaded");
+    assert_eq!(reloads(), 1, "the external edit was not reloaded");
+    assert_eq!(
+        names(&daemon.handle(Message::List {
+            page: Page::default()
+        })),
+        ["edited"]
+    );
+}
+
+#[test]
+fn config_overrides_state_for_commands() {
+    let _files = on_disk();
+    let config = serde_json::json!({
+        "version": CONFIG_VERSION,
+        "commands": {"web": {"command": "from config"}},
+    });
+    fs::write(DaemonState::get_config_path(), config.to_string()).expect("can write the config");
+    let state = serde_json::json!({
+        "version": state::STATE_VERSION,
+        "commands": {
+            "web": {"command": "from state"},
+            "removed": {"command": "removed from the config"},
+            "unsaved": {"command": "never made it to the config"},
+        },
+        "unsaved": ["unsaved", "web"],
+    });
+    fs::write(DaemonState::get_state_path(), state.to_string()).expect("can write the state");
+
+    let kept = DaemonState::new(true);
+    let mut names: Vec<&str> = kept.commands.keys().map(String::as_str).collect();
+    names.sort();
+    assert_eq!(names, ["unsaved", "web"]);
+    assert_eq!(kept.commands["web"].command, "from config");
+
+    let fresh = DaemonState::new(false);
+    let names: Vec<&str> = fresh.commands.keys().map(String::as_str).collect();
+    assert_eq!(names, ["web"]);
+}
+
+#[test]
+fn probes_parse_from_their_spec() {
+    let parse = |spec: &str| spec.parse::<Probe>();
+    assert!(parse("exec:true") == Ok(Probe::Exec("true".into())));
+    assert!(parse("tcp://localhost:80") == Ok(Probe::Tcp("localhost:80".into())));
+    let url = "https://localhost/health";
+    assert!(parse(url) == Ok(Probe::Http(url.into())));
+    assert!("localhost:80".parse::<Probe>().is_err());
+    assert_eq!(parse_probe(""), Ok(String::new()));
+    assert!(parse_probe("ftp://localhost").is_err());
+}
+
+#[test]
+fn exec_probe_checks_the_exit_status() {
+    assert!(Probe::Exec("true".into()).check(DEFAULT_SHELL));
+    assert!(!Probe::Exec("exit 1".into()).check(DEFAULT_SHELL));
+}
+
+#[test]
+fn tcp_probe_connects_to_a_listener() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("can listen");
+    let addr = listener.local_addr().expect("bound").to_string();
+    assert!(Probe::Tcp(addr.clone()).check(DEFAULT_SHELL));
+    drop(listener);
+    assert!(!Probe::Tcp(addr).check(DEFAULT_SHELL));
+}
+
+/// Answers one request on a free port of localhost with `status`, returns the URL.
+fn answer_once(status: &'static str) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("can listen");
+    let url = format!("http://{}/health", listener.local_addr().expect("bound"));
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("the probe connects");
+        let mut request = std::io::BufReader::new(stream.try_clone().expect("can clone"));
+        let mut line = String::new();
+        while request.read_line(&mut line).is_ok_and(|read| read > 2) {
+            line.clear();
+        }
+        let response =
+            format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        let _ = stream.write_all(response.as_bytes());
+    });
+    url
+}
+
+#[test]
+fn http_probe_wants_a_success_status() {
+    assert!(Probe::Http(answer_once("204 No Content")).check(DEFAULT_SHELL));
+    assert!(!Probe::Http(answer_once("503 Service Unavailable")).check(DEFAULT_SHELL));
+}
+
+fn selector(selector: &str) -> Selector {
+    selector.parse().expect("valid selector")
+}
+
+fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+    labels
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn selectors_parse_every_requirement() {
+    let parsed = selector("env=prod, tier==web,app!=db,zone in (a, b),team notin (x),gpu,!spot");
+    assert_eq!(
+        parsed.requirements,
+        [
+            Requirement::Equals("env".into(), "prod".into()),
+            Requirement::Equals("tier".into(), "web".into()),
+            Requirement::NotEquals("app".into(), "db".into()),
+            Requirement::In("zone".into(), vec!["a".into(), "b".into()]),
+            Requirement::NotIn("team".into(), vec!["x".into()]),
+            Requirement::Exists("gpu".into()),
+            Requirement::NotExists("spot".into()),
+        ]
+    );
+    assert_eq!(
+        parsed.to_string(),
+        "env=prod,tier=web,app!=db,zone in (a,b),team notin (x),gpu,!spot"
+    );
+    assert_eq!(selector(&parsed.to_string()), parsed);
+}
+
+#[test]
+fn selectors_refuse_malformed_requirements() {
+    for invalid in [
+        "",
+        "env=",
+        "=prod",
+        "a b=c",
+        "zone in a",
+        "zone in (a,)",
+        "zone at (a)",
+        "env=a=b",
+    ] {
+        assert!(invalid.parse::<Selector>().is_err(), "{invalid} parsed");
+    }
+}
+
+#[test]
+fn equality_selectors_match() {
+    let web = labels(&[("env", "prod"), ("tier", "web")]);
+    assert!(selector("env=prod").matches(&web));
+    assert!(selector("env==prod,tier=web").matches(&web));
+    assert!(!selector("env=prod,tier=db").matches(&web));
+    assert!(!selector("env=prod").matches(&labels(&[])));
+}
+
+#[test]
+fn inequality_selectors_match_missing_labels() {
+    let web = labels(&[("env", "prod")]);
+    assert!(!selector("env!=prod").matches(&web));
+    assert!(selector("env!=dev").matches(&web));
+    assert!(selector("env!=prod").matches(&labels(&[])));
+}
+
+#[test]
+fn set_selectors_match() {
+    let web = labels(&[("zone", "b"), ("gpu", "yes")]);
+    assert!(selector("zone in (a,b)").matches(&web));
+    assert!(!selector("zone in (c)").matches(&web));
+    assert!(!selector("zone notin (a,b)").matches(&web));
+    assert!(selector("zone notin (c)").matches(&web));
+    assert!(selector("region notin (c)").matches(&web));
+    assert!(!selector("region in (c)").matches(&web));
+    assert!(selector("gpu,!spot").matches(&web));
+    assert!(!selector("!gpu").matches(&web));
+}
+
+/// The way the run of a tracked process killed by SIGKILL is recorded, when the
+/// `oom_kill` count of its cgroup went from 3 to `count` meanwhile.
+fn sigkill_end(count: u64) -> state::RunEnd {
+    use std::os::unix::process::ExitStatusExt;
+    let events = std::env::temp_dir().join(format!("uniq-proc-oom-{count}-{}", std::process::id()));
+    fs::write(&events, format!("oom 0\noom_kill {count}\n")).expect("can write the events");
+    let tracked = ProcInfo {
+        pid: 4242,
+        start_time: None,
+        argv: Vec::new(),
+        oom_kills: Some(OomKills {
+            events: events.clone(),
+            count: 3,
+        }),
+    };
+    let mut state = DaemonState::default();
+    let killed = ExitStatus::from_raw(libc::SIGKILL);
+    state.record_run("web", 4242, Some(&tracked), Some(killed));
+    let _ = fs::remove_file(events);
+    let run = &state.history["web"][0];
+    assert_eq!(run.signal, Some(libc::SIGKILL));
+    assert_eq!(state.counters.crashes, 1);
+    run.end
+}
+
+#[test]
+fn sigkill_is_only_blamed_on_the_oom_killer_it_counted() {
+    assert_eq!(sigkill_end(4), state::RunEnd::Oom);
+    assert_eq!(sigkill_end(3), state::RunEnd::Signal);
+}
+
+#[test]
+fn sigkill_from_someone_else_is_recorded_as_a_signal() {
+    let daemon = daemon();
+    add(&daemon, "web", command("sleep 30"));
+    thread::scope(|scope| {
+        let executed = scope.spawn(|| {
+            daemon.handle(Message::Execute {
+                name: "web".into(),
+                force: false,
+            })
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let pid = loop {
+            if let Some(pid) = pid_of(&daemon, "web") {
+                break pid;
+            }
+            assert!(Instant::now() < deadline, "web never started");
+            thread::sleep(Duration::from_millis(10));
+        };
+        // SAFETY: the child this test just started
+        unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+        executed.join().expect("execute returns");
+    });
+    let Response::History { runs, .. } = daemon.handle(Message::History { name: "web".into() })
+    else {
+        panic!("expected the history");
+    };
+    assert_eq!(runs[0].end, state::RunEnd::Signal);
+    assert_eq!(runs[0].signal, Some(libc::SIGKILL));
+}
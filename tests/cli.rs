@@ -0,0 +1,229 @@
+//! Runs the binary the way scripts do: data on stdout, failures on stderr and in
+//! the exit code.
+
+use std::path::PathBuf;
+use std::process::{Child, Command, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Config, state and socket of one test in a directory of its own, and the
+/// daemon serving it once started.
+struct Sandbox {
+    dir: PathBuf,
+    daemon: Option<Child>,
+}
+
+impl Sandbox {
+    fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("uniq-proc-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("can create the sandbox");
+        Sandbox { dir, daemon: None }
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_uniq-proc"));
+        command
+            .env("UNIQ_PROC_CONFIG_DIR", &self.dir)
+            .env("UNIQ_PROC_STATE_DIR", &self.dir)
+            .env_remove("UNIQ_PROC_SOCKET");
+        command
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.command()
+            .args(args)
+            .output()
+            .expect("can run uniq-proc")
+    }
+
+    fn start_daemon(&mut self) {
+        self.start_daemon_with(&[]);
+    }
+
+    fn start_daemon_with(&mut self, options: &[&str]) {
+        let daemon = self
+            .command()
+            .arg("daemon")
+            .args(options)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .expect("can start the daemon");
+        self.daemon = Some(daemon);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        // a client finding no daemon would spawn one of its own
+        while !self.dir.join("uniq-proc.sock").exists() {
+            assert!(Instant::now() < deadline, "the daemon never listened");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        if let Some(mut daemon) = self.daemon.take() {
+            let _ = daemon.kill();
+            let _ = daemon.wait();
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn text(bytes: &[u8]) -> &str {
+    std::str::from_utf8(bytes).expect("UTF-8 output")
+}
+
+#[test]
+fn errors_go_to_stderr_with_their_exit_code() {
+    let mut sandbox = Sandbox::new("errors");
+    sandbox.start_daemon();
+    let missing = sandbox.run(&["kill", "nope"]);
+    assert_eq!(missing.status.code(), Some(12), "{missing:?}");
+    assert!(missing.stdout.is_empty(), "{missing:?}");
+    assert!(text(&missing.stderr).contains("nope"), "{missing:?}");
+
+    let unknown = sandbox.run(&["execute", "nope"]);
+    assert_eq!(unknown.status.code(), Some(10), "{unknown:?}");
+    assert!(unknown.stdout.is_empty(), "{unknown:?}");
+    assert!(
+        text(&unknown.stderr).contains("not registered"),
+        "{unknown:?}"
+    );
+}
+
+#[test]
+fn data_goes_to_stdout() {
+    let mut sandbox = Sandbox::new("data");
+    sandbox.start_daemon();
+    let added = sandbox.run(&["add", "web", "sleep 30"]);
+    assert!(added.status.success(), "{added:?}");
+    let listed = sandbox.run(&["list"]);
+    assert!(listed.status.success(), "{listed:?}");
+    assert!(text(&listed.stdout).contains("sleep 30"), "{listed:?}");
+    assert!(listed.stderr.is_empty(), "{listed:?}");
+}
+
+#[test]
+fn daemon_restart_keeps_the_options_and_processes() {
+    let mut sandbox = Sandbox::new("restart");
+    sandbox.start_daemon_with(&["--max-procs", "3", "--max-log-bytes", "1234"]);
+    assert!(sandbox
+        .run(&["add", "web", "exec sleep 30"])
+        .status
+        .success());
+    assert!(sandbox.run(&["start", "web"]).status.success());
+    // the new daemon inherits the output, which a pipe would wait on for good
+    let report = sandbox.dir.join("restarted");
+    let restarted = sandbox
+        .command()
+        .arg("daemon-restart")
+        .stdout(std::fs::File::create(&report).expect("can create the report"))
+        .stderr(std::process::Stdio::null())
+        .status()
+        .expect("can run uniq-proc");
+    // the new daemon is no child of the sandbox, only a shutdown stops it
+    let info = sandbox.run(&["--output", "json", "info"]);
+    let shutdown = sandbox.run(&["shutdown"]);
+    assert!(restarted.success(), "{restarted:?}");
+    let report = std::fs::read_to_string(&report).expect("can read the report");
+    assert!(report.contains("reattached"), "{report}");
+    let info = text(&info.stdout);
+    assert!(info.contains(r#""max_procs":3"#), "{info}");
+    assert!(info.contains(r#""max_log_bytes":1234"#), "{info}");
+    assert!(shutdown.status.success(), "{shutdown:?}");
+}
+
+#[test]
+fn daemon_restart_refuses_an_ephemeral_daemon() {
+    let mut sandbox = Sandbox::new("restart-ephemeral");
+    sandbox.start_daemon_with(&["--ephemeral"]);
+    let restarted = sandbox.run(&["daemon-restart"]);
+    assert_eq!(restarted.status.code(), Some(17), "{restarted:?}");
+    assert!(
+        text(&restarted.stderr).contains("ephemeral"),
+        "{restarted:?}"
+    );
+    // still the same daemon, it was not evicted
+    let daemon = sandbox.daemon.as_mut().expect("started");
+    assert!(daemon.try_wait().expect("can check the daemon").is_none());
+    let alive = sandbox.run(&["info"]);
+    assert!(alive.status.success(), "{alive:?}");
+}
+
+#[test]
+fn the_socket_flag_wins_over_the_environment() {
+    let sandbox = Sandbox::new("socket");
+    let flag = sandbox.dir.join("flag.sock");
+    let env = sandbox.dir.join("env.sock");
+    let client = |args: &[&str]| {
+        let mut command = sandbox.command();
+        command
+            .env("UNIQ_PROC_SOCKET", &env)
+            .arg("--socket")
+            .arg(&flag)
+            .args(args);
+        command
+    };
+    // the auto-spawned daemon inherits the output, which a pipe would wait on for good
+    let added = client(&["add", "web", "true"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .expect("can run uniq-proc");
+    let listed = client(&["list"]).output().expect("can run uniq-proc");
+    let listening = flag.exists();
+    let shutdown = client(&["shutdown"]).output().expect("can run uniq-proc");
+    assert!(added.success(), "{added:?}");
+    assert!(listening, "the daemon does not listen on the flag's path");
+    assert!(!env.exists(), "the daemon listens on UNIQ_PROC_SOCKET");
+    let in_state_dir = sandbox.dir.join("uniq-proc.sock");
+    assert!(
+        !in_state_dir.exists(),
+        "the daemon listens in the state dir"
+    );
+    assert!(listed.status.success(), "{listed:?}");
+    assert!(text(&listed.stdout).contains("web"), "{listed:?}");
+    assert!(shutdown.status.success(), "{shutdown:?}");
+}
+
+#[test]
+fn errors_without_a_daemon_go_to_stderr() {
+    let sandbox = Sandbox::new("no-daemon");
+    let unknown = sandbox.run(&["--no-daemon", "execute", "nope"]);
+    assert_eq!(unknown.status.code(), Some(10), "{unknown:?}");
+    assert!(unknown.stdout.is_empty(), "{unknown:?}");
+    assert!(!unknown.stderr.is_empty(), "{unknown:?}");
+}
+
+#[test]
+fn usage_errors_go_to_stderr() {
+    let sandbox = Sandbox::new("usage");
+    let usage = sandbox.run(&["frobnicate"]);
+    assert_eq!(usage.status.code(), Some(2), "{usage:?}");
+    assert!(usage.stdout.is_empty(), "{usage:?}");
+    assert!(text(&usage.stderr).contains("frobnicate"), "{usage:?}");
+}
+
+#[test]
+fn no_daemon_leaves_an_old_config_as_it_is() {
+    let sandbox = Sandbox::new("no-daemon-old");
+    let config = sandbox.dir.join("config.json");
+    let old = r#"{"web": "true"}"#;
+    std::fs::write(&config, old).expect("can write the config");
+    let executed = sandbox.run(&["--no-daemon", "execute", "web"]);
+    assert!(executed.status.success(), "{executed:?}");
+    assert_eq!(std::fs::read_to_string(&config).expect("config"), old);
+    assert!(!sandbox.dir.join("uniq-proc.state").exists());
+}
+
+#[test]
+fn no_daemon_reports_an_invalid_config() {
+    let sandbox = Sandbox::new("no-daemon-invalid");
+    std::fs::write(sandbox.dir.join("config.json"), "{").expect("can write the config");
+    let executed = sandbox.run(&["--no-daemon", "execute", "web"]);
+    assert_eq!(executed.status.code(), Some(17), "{executed:?}");
+    let stderr = text(&executed.stderr);
+    assert!(stderr.contains("invalid config"), "{executed:?}");
+    assert!(!stderr.contains("panicked"), "{executed:?}");
+}